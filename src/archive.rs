@@ -0,0 +1,210 @@
+use crate::PortraitDir;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A portrait directory inside a zip archive.
+#[derive(Debug)]
+pub struct ArchivedPortrait {
+    pub archive: PathBuf,
+    /// The directory inside the archive, empty for its top level.
+    pub dir: PathBuf,
+    /// The names of the entries holding the required files, in order.
+    entries: Vec<String>,
+}
+
+impl ArchivedPortrait {
+    /// The archive and the directory inside it, as if it were extracted in place.
+    pub fn path(&self) -> PathBuf {
+        self.archive.join(&self.dir)
+    }
+
+    /// Extracts the required files into the new directory `dst`, which is removed on failure.
+    pub(crate) fn extract(&self, dst: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::open(&self.archive)?;
+        let mut archive = zip::ZipArchive::new(file).map_err(std::io::Error::other)?;
+        std::fs::create_dir_all(dst)?;
+        let extracted = self.entries.iter().try_for_each(|entry| {
+            let mut entry = archive.by_name(entry).map_err(std::io::Error::other)?;
+            let file_name = entry
+                .enclosed_name()
+                .and_then(|name| name.file_name().map(PathBuf::from))
+                .ok_or(std::io::ErrorKind::InvalidData)?;
+            let mut file = std::fs::File::create(dst.join(file_name))?;
+            std::io::copy(&mut entry, &mut file).map(|_| ())
+        });
+        if extracted.is_err() && std::fs::remove_dir_all(dst).is_err() {
+            log::error!("Failed to clean up partial extraction {}", dst.display());
+        }
+        extracted
+    }
+}
+
+/// The directories of the zip archive at `path` that hold the files required by
+/// `portrait_dir`.
+pub(crate) fn find_portraits(
+    path: &Path,
+    portrait_dir: &PortraitDir,
+) -> zip::result::ZipResult<Vec<ArchivedPortrait>> {
+    let archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut dirs: BTreeMap<PathBuf, Vec<(String, String)>> = BTreeMap::new();
+    for name in archive.file_names() {
+        let name = name?;
+        // Entries escaping the archive, e.g. "../x", are ignored.
+        let Some(enclosed) = enclosed_name(&name) else {
+            continue;
+        };
+        let (Some(dir), Some(file_name)) = (enclosed.parent(), enclosed.file_name()) else {
+            continue;
+        };
+        dirs.entry(dir.to_path_buf())
+            .or_default()
+            .push((file_name.to_string_lossy().into_owned(), name.into_owned()));
+    }
+    let portraits = dirs
+        .into_iter()
+        .filter_map(|(dir, files)| {
+            let entries = portrait_dir
+                .required_files
+                .iter()
+                .map(|required| {
+                    portrait_dir
+                        .alternatives(required)
+                        .iter()
+                        .find_map(|required| {
+                            let exact = files.iter().find(|(file_name, _)| file_name == required);
+                            match exact {
+                                None if !portrait_dir.case_sensitive => {
+                                    files.iter().find(|(file_name, _)| {
+                                        file_name.eq_ignore_ascii_case(required)
+                                    })
+                                }
+                                found => found,
+                            }
+                        })
+                        .map(|(_, entry)| entry.clone())
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(ArchivedPortrait {
+                archive: path.to_path_buf(),
+                dir,
+                entries,
+            })
+        })
+        .collect();
+    Ok(portraits)
+}
+
+fn enclosed_name(name: &str) -> Option<PathBuf> {
+    if name.ends_with('/') || name.contains('\0') {
+        return None;
+    }
+    let mut enclosed = PathBuf::new();
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            component if component.contains(':') => return None,
+            component => enclosed.push(component),
+        }
+    }
+    Some(enclosed).filter(|enclosed| enclosed.file_name().is_some())
+}
+
+/// Whether `path` looks like a zip archive.
+pub(crate) fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A new directory for the test `name`, holding a zip archive of empty `files`.
+    fn archive(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("portraits-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(dir.join("pack.zip")).unwrap());
+        for file in files {
+            zip.start_file(*file, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(file.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        dir
+    }
+
+    fn portrait_dir(case_sensitive: bool) -> PortraitDir {
+        PortraitDir {
+            required_files: vec!["Small.png".to_owned(), "Medium.png".to_owned()],
+            case_sensitive,
+            extensions: vec!["png".to_owned()],
+            min_image_size: None,
+        }
+    }
+
+    #[test]
+    fn enclosed_names() {
+        assert_eq!(
+            enclosed_name("./a\\b/Small.png"),
+            Some(PathBuf::from("a/b/Small.png"))
+        );
+        assert_eq!(enclosed_name("Small.png"), Some(PathBuf::from("Small.png")));
+        for escaping in [
+            "../Small.png",
+            "a/../../Small.png",
+            "C:/Small.png",
+            "C:Small.png",
+        ] {
+            assert_eq!(enclosed_name(escaping), None, "{}", escaping);
+        }
+        assert_eq!(enclosed_name("a/b/"), None);
+    }
+
+    #[test]
+    fn find_enclosed_portraits() {
+        let dir = archive(
+            "archive-find",
+            &[
+                "gimli/small.PNG",
+                "gimli/Medium.png",
+                "../escaped/Small.png",
+                "../escaped/Medium.png",
+                "D:/drive/Small.png",
+                "D:/drive/Medium.png",
+                "legolas/Small.png",
+            ],
+        );
+        let archive = dir.join("pack.zip");
+        let found = find_portraits(&archive, &portrait_dir(false)).unwrap();
+        let dirs: Vec<&Path> = found
+            .iter()
+            .map(|portrait| portrait.dir.as_path())
+            .collect();
+        assert_eq!(dirs, [Path::new("gimli")]);
+        assert_eq!(found[0].entries, ["gimli/small.PNG", "gimli/Medium.png"]);
+        assert!(find_portraits(&archive, &portrait_dir(true))
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extract_cleans_up() {
+        let dir = archive("archive-extract", &["gimli/Small.png", "gimli/Medium.png"]);
+        let mut portrait = find_portraits(&dir.join("pack.zip"), &portrait_dir(false))
+            .unwrap()
+            .pop()
+            .unwrap();
+        portrait.extract(&dir.join("extracted")).unwrap();
+        let small = std::fs::read_to_string(dir.join("extracted/Small.png")).unwrap();
+        assert_eq!(small, "gimli/Small.png");
+
+        portrait.entries.push("gimli/Fulllength.png".to_owned());
+        assert!(portrait.extract(&dir.join("partial")).is_err());
+        assert!(!dir.join("partial").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,12 @@
-use std::path::PathBuf;
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use wrathoftherighteousportraits::{
+    Bucket, CleanMove, Config, DedupOn, HashAlgorithm, Keep, NameTemplate, ProgressFormat,
+    SortOrder,
+};
 
 const DOWNLOADS_ARG: &str = "downloads";
 const PORTRAITS_ARG: &str = "portraits";
@@ -6,43 +14,581 @@ const PREFIX_ARG: &str = "prefix";
 const KEEP_ORIGINAL_PATH_ARG: &str = "keep-original-path";
 const REMOVE_USELESS_DIRS_ARG: &str = "remove-useless-dirs";
 const REMOVE_DUPLICATE_DIRS_ARG: &str = "remove-duplicate-dirs";
+const DRY_RUN_ARG: &str = "dry-run";
+const COPY_ARG: &str = "copy";
+const HASH_ALGORITHM_ARG: &str = "hash-algorithm";
+const PERCEPTUAL_DEDUP_ARG: &str = "perceptual-dedup";
+const PERCEPTUAL_THRESHOLD_ARG: &str = "perceptual-threshold";
+const VALIDATE_PNG_ARG: &str = "validate-png";
+const CHECK_DIMENSIONS_ARG: &str = "check-dimensions";
+const STRICT_DIMENSIONS_ARG: &str = "strict-dimensions";
+const GENERATE_MISSING_ARG: &str = "generate-missing";
+const NO_PROGRESS_ARG: &str = "no-progress";
+const REPORT_ARG: &str = "report";
+const UNDO_LOG_ARG: &str = "undo-log";
+const UNDO_ARG: &str = "undo";
+const MAX_DEPTH_ARG: &str = "max-depth";
+const JOBS_ARG: &str = "jobs";
+const FOLLOW_SYMLINKS_ARG: &str = "follow-symlinks";
+const INCLUDE_HIDDEN_ARG: &str = "include-hidden";
+const VERBOSE_ARG: &str = "verbose";
+const QUIET_ARG: &str = "quiet";
+const EXCLUDE_ARG: &str = "exclude";
+const CASE_SENSITIVE_ARG: &str = "case-sensitive";
+const REQUIRED_FILES_ARG: &str = "required-files";
+const DEDUPE_PORTRAITS_ARG: &str = "dedupe-portraits";
+const CONFIRM_ARG: &str = "confirm";
+const TRASH_DIR_ARG: &str = "trash-dir";
+const LOG_FILE_ARG: &str = "log-file";
+const NAME_TEMPLATE_ARG: &str = "name-template";
+const SEPARATOR_ARG: &str = "separator";
+const ATTEMPT_PADDING_ARG: &str = "attempt-padding";
+const PRESERVE_TREE_ARG: &str = "preserve-tree";
+const STRIP_PREFIX_REPEAT_ARG: &str = "strip-prefix-repeat";
+const SORT_ARG: &str = "sort";
+const SKIP_INSTALLED_ARG: &str = "skip-installed";
+const MAX_PORTRAITS_ARG: &str = "max-portraits";
+const GALLERY_ARG: &str = "gallery";
+const EMBED_ARG: &str = "embed";
+const MANIFEST_ARG: &str = "manifest";
+const VERIFY_ARG: &str = "verify";
+const CREATE_TARGET_ARG: &str = "create-target";
+const STRICT_ARG: &str = "strict";
+const SCAN_ARCHIVES_ARG: &str = "scan-archives";
+const WATCH_ARG: &str = "watch";
+const CACHE_FILE_ARG: &str = "cache-file";
+const THREADS_ARG: &str = "threads";
+const PRESERVE_TIMES_ARG: &str = "preserve-times";
+const NO_PRESERVE_TIMES_ARG: &str = "no-preserve-times";
+const REPORT_EXTRAS_ARG: &str = "report-extras";
+const STRIP_EXTRAS_ARG: &str = "strip-extras";
+const STATS_ARG: &str = "stats";
+const IMAGE_EXTENSIONS_ARG: &str = "image-extensions";
+const CONVERT_TO_PNG_ARG: &str = "convert-to-png";
+const CONFIG_ARG: &str = "config";
+const COMPLETIONS_ARG: &str = "completions";
+const BUCKET_ARG: &str = "bucket";
+const MIN_IMAGE_SIZE_ARG: &str = "min-image-size";
+const DEDUP_ON_ARG: &str = "dedup-on";
+const COLLAPSE_REDUNDANT_ARG: &str = "collapse-redundant";
+const MAX_NAME_LENGTH_ARG: &str = "max-name-length";
+const SANITIZE_REPLACEMENT_ARG: &str = "sanitize-replacement";
+const WINDOWS_NAMES_ARG: &str = "windows-names";
+const NO_MOVE_ARG: &str = "no-move";
+const CHECK_CONSISTENCY_ARG: &str = "check-consistency";
+const PROGRESS_FORMAT_ARG: &str = "progress-format";
+const PROGRESS_FD_ARG: &str = "progress-fd";
+const RETRIES_ARG: &str = "retries";
+const RETRY_DELAY_ARG: &str = "retry-delay";
+const BLOCKLIST_ARG: &str = "blocklist";
+const KEEP_ARG: &str = "keep";
+const INCLUDE_LIST_ARG: &str = "include-list";
+const WRITE_CHECKSUMS_ARG: &str = "write-checksums";
+const CHECK_CHECKSUMS_ARG: &str = "check-checksums";
+const REPORT_INCOMPLETE_ARG: &str = "report-incomplete";
+const HASH_BUFFER_SIZE_ARG: &str = "hash-buffer-size";
+const FORCE_COLLISIONS_ARG: &str = "force-collisions";
+const PRUNE_EMPTY_ARG: &str = "prune-empty";
+const USE_DESCRIPTOR_ARG: &str = "use-descriptor";
+const MIN_AGE_ARG: &str = "min-age";
+const SELF_TEST_ARG: &str = "self-test";
+const MAX_ATTEMPTS_ARG: &str = "max-attempts";
+const ASPECT_MIN_ARG: &str = "aspect-min";
+const ASPECT_MAX_ARG: &str = "aspect-max";
+const REVIEW_ARG: &str = "review";
+const NAME_FROM_METADATA_ARG: &str = "name-from-metadata";
+const COMPARE_ARG: &str = "compare";
+const PREFIX_FROM_SOURCE_ARG: &str = "prefix-from-source";
+const FIX_CASING_ARG: &str = "fix-casing";
+const LIMIT_BYTES_ARG: &str = "limit-bytes";
+const PLAN_ARG: &str = "plan";
+const APPLY_ARG: &str = "apply";
+const PREFER_RICHER_ARG: &str = "prefer-richer";
+const IGNORE_PERMISSION_ERRORS_ARG: &str = "ignore-permission-errors";
+const CLEAN_MOVE_ARG: &str = "clean-move";
+const PREFIX_SEPARATOR_ARG: &str = "prefix-separator";
+const LIST_DUPLICATES_ARG: &str = "list-duplicates";
+const SLOT_MAP_ARG: &str = "slot-map";
+const DEDUPE_ACROSS_FORMATS_ARG: &str = "dedupe-across-formats";
+const UNDO_LOG_FILE_NAME: &str = "portraits_undo.jsonl";
+const CACHE_FILE_NAME: &str = "portraits_checksums.json";
+const CONFIG_FILE_NAME: &str = "portraits.toml";
 
-#[derive(Debug)]
-pub struct Args {
-    pub downloads_dir: PathBuf,
-    pub portraits_dir: PathBuf,
-    pub prefix: String,
-    pub keep_original_path: bool,
-    pub remove_useless_dirs: bool,
-    pub remove_duplicate_dirs: bool,
-}
-
-impl Args {
-    pub fn fetch() -> Self {
-        let matches = get_matches();
-        let downloads_dir = matches.get_one::<PathBuf>(DOWNLOADS_ARG).unwrap().clone();
-        let portraits_dir = matches.get_one::<PathBuf>(PORTRAITS_ARG).unwrap().clone();
-        let prefix = matches.get_one::<String>(PREFIX_ARG).unwrap().clone();
-        let keep_original_path = matches.get_flag(KEEP_ORIGINAL_PATH_ARG);
-        let remove_useless_dirs = matches.get_flag(REMOVE_USELESS_DIRS_ARG);
-        let remove_duplicate_dirs = matches.get_flag(REMOVE_DUPLICATE_DIRS_ARG);
-        assert_is_dir(&downloads_dir);
-        assert_is_dir(&portraits_dir);
-        Self {
-            downloads_dir,
-            portraits_dir,
-            prefix,
-            keep_original_path,
-            remove_useless_dirs,
-            remove_duplicate_dirs,
+pub fn fetch() -> anyhow::Result<Config> {
+    from_matches(get_matches()?)
+}
+
+/// The configuration of `--self-test`: the defaults, along with the removal of the duplicate
+/// and useless dirs, for the given directories.
+pub fn self_test_config(downloads_dir: &Path, portraits_dir: &Path) -> anyhow::Result<Config> {
+    let args: [&OsStr; 8] = [
+        "portraits".as_ref(),
+        "--downloads".as_ref(),
+        downloads_dir.as_os_str(),
+        "--portraits".as_ref(),
+        portraits_dir.as_os_str(),
+        "--remove-duplicate-dirs".as_ref(),
+        "--remove-useless-dirs".as_ref(),
+        "--no-progress".as_ref(),
+    ];
+    from_matches(command().try_get_matches_from(args)?)
+}
+
+fn from_matches(matches: clap::ArgMatches) -> anyhow::Result<Config> {
+    let undo = matches.get_one::<PathBuf>(UNDO_ARG).cloned();
+    // So that e.g. "./downloads" and "downloads/../downloads" name the moved dirs, as well as
+    // the files kept next to them, alike.
+    let downloads_dir = canonical(
+        matches
+            .get_one::<PathBuf>(DOWNLOADS_ARG)
+            .cloned()
+            .unwrap_or_default(),
+    );
+    let portraits_dir = canonical(
+        matches
+            .get_one::<PathBuf>(PORTRAITS_ARG)
+            .cloned()
+            .unwrap_or_default(),
+    );
+    let prefix = value::<String>(&matches, PREFIX_ARG)?;
+    // Without a prefix, the names do not start with a separator either.
+    let prefix = match value::<String>(&matches, PREFIX_SEPARATOR_ARG)? {
+        separator if !prefix.is_empty() => prefix + &separator,
+        _ => prefix,
+    };
+    let keep_original_path = matches.get_flag(KEEP_ORIGINAL_PATH_ARG);
+    let remove_useless_dirs = matches.get_flag(REMOVE_USELESS_DIRS_ARG);
+    let remove_duplicate_dirs = matches.get_flag(REMOVE_DUPLICATE_DIRS_ARG);
+    let plan = matches.get_one::<PathBuf>(PLAN_ARG).cloned();
+    // Planning only looks, like a dry run.
+    let dry_run = matches.get_flag(DRY_RUN_ARG) || plan.is_some();
+    let copy = matches.get_flag(COPY_ARG);
+    let hash_algorithm: HashAlgorithm = value::<String>(&matches, HASH_ALGORITHM_ARG)?.parse()?;
+    let perceptual_dedup = matches.get_flag(PERCEPTUAL_DEDUP_ARG);
+    let perceptual_threshold = value::<u32>(&matches, PERCEPTUAL_THRESHOLD_ARG)?;
+    let validate_png = matches.get_flag(VALIDATE_PNG_ARG);
+    let check_dimensions = matches.get_flag(CHECK_DIMENSIONS_ARG);
+    let strict_dimensions = matches.get_flag(STRICT_DIMENSIONS_ARG);
+    let generate_missing = matches.get_flag(GENERATE_MISSING_ARG);
+    let progress = !matches.get_flag(NO_PROGRESS_ARG);
+    let report = matches.get_one::<PathBuf>(REPORT_ARG).cloned();
+    let undo_log = matches
+        .get_one::<PathBuf>(UNDO_LOG_ARG)
+        .cloned()
+        .unwrap_or_else(|| portraits_dir.join(UNDO_LOG_FILE_NAME));
+    let max_depth = matches.get_one::<usize>(MAX_DEPTH_ARG).copied();
+    let jobs = matches.get_one::<usize>(JOBS_ARG).copied();
+    let follow_symlinks = matches.get_flag(FOLLOW_SYMLINKS_ARG);
+    let include_hidden = matches.get_flag(INCLUDE_HIDDEN_ARG);
+    let log_level = if matches.get_flag(QUIET_ARG) {
+        log::LevelFilter::Error
+    } else {
+        match matches.get_count(VERBOSE_ARG) {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    };
+    let case_sensitive = matches.get_flag(CASE_SENSITIVE_ARG);
+    let required_files = matches
+        .get_many::<String>(REQUIRED_FILES_ARG)
+        .ok_or_else(|| anyhow!("Missing --{}", REQUIRED_FILES_ARG))?
+        .cloned()
+        .collect();
+    let dedupe_portraits = matches.get_flag(DEDUPE_PORTRAITS_ARG);
+    let confirm = matches.get_flag(CONFIRM_ARG);
+    let trash_dir = matches.get_one::<PathBuf>(TRASH_DIR_ARG).cloned();
+    let log_file = matches.get_one::<PathBuf>(LOG_FILE_ARG).cloned();
+    let name_template = matches.get_one::<NameTemplate>(NAME_TEMPLATE_ARG).cloned();
+    let separator = value::<String>(&matches, SEPARATOR_ARG)?;
+    let attempt_padding = value::<u32>(&matches, ATTEMPT_PADDING_ARG)?;
+    let preserve_tree = matches.get_flag(PRESERVE_TREE_ARG);
+    let strip_prefix_repeat = matches.get_flag(STRIP_PREFIX_REPEAT_ARG);
+    let sort = match value::<String>(&matches, SORT_ARG)?.as_str() {
+        "name" => SortOrder::Name,
+        "mtime" => SortOrder::Mtime,
+        "size" => SortOrder::Size,
+        _ => SortOrder::None,
+    };
+    let skip_installed = matches.get_flag(SKIP_INSTALLED_ARG);
+    let max_portraits = matches.get_one::<usize>(MAX_PORTRAITS_ARG).copied();
+    let gallery = matches.get_one::<PathBuf>(GALLERY_ARG).cloned();
+    let embed = matches.get_flag(EMBED_ARG);
+    let manifest = matches.get_one::<PathBuf>(MANIFEST_ARG).cloned();
+    let verify = matches.get_one::<PathBuf>(VERIFY_ARG).cloned();
+    let create_target = matches.get_flag(CREATE_TARGET_ARG);
+    let strict = matches.get_flag(STRICT_ARG);
+    let scan_archives = matches.get_flag(SCAN_ARCHIVES_ARG);
+    let watch = matches.get_flag(WATCH_ARG);
+    let cache_file = matches
+        .get_one::<PathBuf>(CACHE_FILE_ARG)
+        .cloned()
+        .unwrap_or_else(|| downloads_dir.join(CACHE_FILE_NAME));
+    let threads = matches.get_one::<usize>(THREADS_ARG).copied();
+    let preserve_times = !matches.get_flag(NO_PRESERVE_TIMES_ARG);
+    let report_extras = matches.get_flag(REPORT_EXTRAS_ARG);
+    let strip_extras = matches.get_flag(STRIP_EXTRAS_ARG);
+    let stats = matches.get_flag(STATS_ARG);
+    let image_extensions = matches
+        .get_many::<String>(IMAGE_EXTENSIONS_ARG)
+        .ok_or_else(|| anyhow!("Missing --{}", IMAGE_EXTENSIONS_ARG))?
+        .map(|extension| extension.trim_start_matches('.').to_owned())
+        .collect();
+    let convert_to_png = matches.get_flag(CONVERT_TO_PNG_ARG);
+    let bucket = match value::<String>(&matches, BUCKET_ARG)?.as_str() {
+        "by-letter" => Bucket::ByLetter,
+        "by-source-top" => Bucket::BySourceTop,
+        _ => Bucket::None,
+    };
+    let min_image_size = matches.get_one::<u64>(MIN_IMAGE_SIZE_ARG).copied();
+    let dedup_on = match value::<String>(&matches, DEDUP_ON_ARG)?.as_str() {
+        "full" => DedupOn::Full,
+        "small" => DedupOn::Small,
+        _ => DedupOn::All,
+    };
+    let collapse_redundant = matches.get_flag(COLLAPSE_REDUNDANT_ARG);
+    let max_name_length = value::<usize>(&matches, MAX_NAME_LENGTH_ARG)?;
+    let sanitize_replacement = value::<String>(&matches, SANITIZE_REPLACEMENT_ARG)?;
+    let windows_names = matches.get_flag(WINDOWS_NAMES_ARG);
+    let no_move = matches.get_flag(NO_MOVE_ARG);
+    let check_consistency = matches.get_flag(CHECK_CONSISTENCY_ARG);
+    let progress_format = match value::<String>(&matches, PROGRESS_FORMAT_ARG)?.as_str() {
+        "ndjson" => ProgressFormat::Ndjson,
+        _ => ProgressFormat::Human,
+    };
+    let progress_fd = matches.get_one::<i32>(PROGRESS_FD_ARG).copied();
+    let retries = value::<u32>(&matches, RETRIES_ARG)?;
+    let retry_delay = Duration::from_millis(value::<u64>(&matches, RETRY_DELAY_ARG)?);
+    let blocklist = match matches.get_one::<PathBuf>(BLOCKLIST_ARG) {
+        Some(path) => read_blocklist(path)?,
+        None => HashSet::new(),
+    };
+    let keep = match value::<String>(&matches, KEEP_ARG)?.as_str() {
+        "newest" => Keep::Newest,
+        "shortest-path" => Keep::ShortestPath,
+        "longest-name" => Keep::LongestName,
+        _ => Keep::First,
+    };
+    let include_list = matches
+        .get_one::<PathBuf>(INCLUDE_LIST_ARG)
+        .map(|path| read_include_list(path))
+        .transpose()?;
+    let write_checksums = matches.get_one::<PathBuf>(WRITE_CHECKSUMS_ARG).cloned();
+    let check_checksums = matches.get_one::<PathBuf>(CHECK_CHECKSUMS_ARG).cloned();
+    let report_incomplete = matches.get_flag(REPORT_INCOMPLETE_ARG);
+    let hash_buffer_size = value::<usize>(&matches, HASH_BUFFER_SIZE_ARG)?;
+    let force_collisions = value::<u32>(&matches, FORCE_COLLISIONS_ARG)?;
+    let prune_empty = matches.get_flag(PRUNE_EMPTY_ARG);
+    let use_descriptor = matches.get_flag(USE_DESCRIPTOR_ARG);
+    let min_age = matches
+        .get_one::<u64>(MIN_AGE_ARG)
+        .map(|seconds| Duration::from_secs(*seconds));
+    let self_test = matches.get_flag(SELF_TEST_ARG);
+    let max_attempts = value::<u32>(&matches, MAX_ATTEMPTS_ARG)?;
+    let aspect_min = matches.get_one::<f64>(ASPECT_MIN_ARG).copied();
+    let aspect_max = matches.get_one::<f64>(ASPECT_MAX_ARG).copied();
+    if let (Some(aspect_min), Some(aspect_max)) = (aspect_min, aspect_max) {
+        if aspect_min > aspect_max {
+            return Err(anyhow!(
+                "--{} {} is above --{} {}",
+                ASPECT_MIN_ARG,
+                aspect_min,
+                ASPECT_MAX_ARG,
+                aspect_max
+            ));
+        }
+    }
+    let review = matches.get_flag(REVIEW_ARG);
+    let name_from_metadata = matches.get_flag(NAME_FROM_METADATA_ARG);
+    let compare = matches.get_one::<PathBuf>(COMPARE_ARG).cloned();
+    if let Some(compare) = &compare {
+        check_is_dir(compare, COMPARE_ARG)?;
+    }
+    let compare = compare.map(canonical);
+    let prefix_from_source = matches.get_flag(PREFIX_FROM_SOURCE_ARG);
+    let fix_casing = matches.get_flag(FIX_CASING_ARG);
+    let limit_bytes = matches.get_one::<u64>(LIMIT_BYTES_ARG).copied();
+    let apply = matches.get_one::<PathBuf>(APPLY_ARG).cloned();
+    let prefer_richer = matches.get_one::<String>(PREFER_RICHER_ARG).cloned();
+    let ignore_permission_errors = matches.get_flag(IGNORE_PERMISSION_ERRORS_ARG);
+    let clean_move =
+        matches
+            .get_one::<String>(CLEAN_MOVE_ARG)
+            .map(|extras| match extras.as_str() {
+                "leave" => CleanMove::Leave,
+                _ => CleanMove::Subfolder,
+            });
+    let list_duplicates = matches.get_flag(LIST_DUPLICATES_ARG);
+    let slot_map = match matches.get_one::<PathBuf>(SLOT_MAP_ARG) {
+        Some(path) => read_slot_map(path)?,
+        None => HashMap::new(),
+    };
+    let dedupe_across_formats = matches.get_flag(DEDUPE_ACROSS_FORMATS_ARG);
+    let mut exclude = globset::GlobSetBuilder::new();
+    for glob in matches
+        .get_many::<globset::Glob>(EXCLUDE_ARG)
+        .unwrap_or_default()
+    {
+        exclude.add(glob.clone());
+    }
+    let exclude = exclude
+        .build()
+        .map_err(|err| anyhow!("Invalid exclude patterns: {}", err))?;
+    if undo.is_none() && !self_test {
+        if gallery.is_none()
+            && verify.is_none()
+            && write_checksums.is_none()
+            && compare.is_none()
+            && apply.is_none()
+            && check_checksums.is_none()
+        {
+            check_is_dir(&downloads_dir, DOWNLOADS_ARG)?;
+        }
+        if !create_target {
+            check_is_dir(&portraits_dir, PORTRAITS_ARG)?;
+        }
+    }
+    Ok(Config {
+        downloads_dir,
+        portraits_dir,
+        prefix,
+        keep_original_path,
+        remove_useless_dirs,
+        remove_duplicate_dirs,
+        dry_run,
+        copy,
+        hash_algorithm,
+        perceptual_dedup,
+        perceptual_threshold,
+        validate_png,
+        check_dimensions,
+        strict_dimensions,
+        generate_missing,
+        progress,
+        report,
+        undo_log,
+        undo,
+        max_depth,
+        jobs,
+        follow_symlinks,
+        include_hidden,
+        log_level,
+        exclude,
+        case_sensitive,
+        required_files,
+        dedupe_portraits,
+        confirm,
+        trash_dir,
+        log_file,
+        name_template,
+        separator,
+        attempt_padding,
+        preserve_tree,
+        strip_prefix_repeat,
+        sort,
+        skip_installed,
+        max_portraits,
+        gallery,
+        embed,
+        manifest,
+        verify,
+        create_target,
+        strict,
+        scan_archives,
+        watch,
+        cache_file,
+        threads,
+        preserve_times,
+        report_extras,
+        strip_extras,
+        stats,
+        image_extensions,
+        convert_to_png,
+        bucket,
+        min_image_size,
+        dedup_on,
+        collapse_redundant,
+        max_name_length,
+        sanitize_replacement,
+        windows_names,
+        no_move,
+        check_consistency,
+        progress_format,
+        progress_fd,
+        retries,
+        retry_delay,
+        blocklist,
+        keep,
+        include_list,
+        write_checksums,
+        check_checksums,
+        report_incomplete,
+        hash_buffer_size,
+        force_collisions,
+        prune_empty,
+        use_descriptor,
+        min_age,
+        self_test,
+        max_attempts,
+        aspect_min,
+        aspect_max,
+        review,
+        name_from_metadata,
+        compare,
+        prefix_from_source,
+        fix_casing,
+        limit_bytes,
+        plan,
+        apply,
+        prefer_richer,
+        ignore_permission_errors,
+        clean_move,
+        list_duplicates,
+        slot_map,
+        dedupe_across_formats,
+    })
+}
+
+/// Parses the command line, on top of the options of the config file given with --config.
+fn get_matches() -> anyhow::Result<clap::ArgMatches> {
+    let args = with_config_file(std::env::args_os().collect())?;
+    Ok(completed(command().get_matches_from(args)))
+}
+
+/// `args` preceded by the options of the config file given with --config, or else of the
+/// default one if there is such a file.
+fn with_config_file(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    // Only --config is needed from this first pass, the others are checked later.
+    let config = command()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .ok()
+        .and_then(|matches| matches.get_one::<PathBuf>(CONFIG_ARG).cloned());
+    let config_args = match config {
+        Some(config) => config_file_args(&command(), &config, true)?,
+        None => config_file_args(&command(), Path::new(CONFIG_FILE_NAME), false)?,
+    };
+    // The command line comes last, so it overrides the config file.
+    Ok(args
+        .iter()
+        .take(1)
+        .cloned()
+        .chain(config_args)
+        .chain(args.iter().skip(1).cloned())
+        .collect())
+}
+
+/// Prints the completion script for the shell given with --completions and exits, if any.
+fn completed(matches: clap::ArgMatches) -> clap::ArgMatches {
+    if let Some(shell) = matches.get_one::<clap_complete::Shell>(COMPLETIONS_ARG) {
+        clap_complete::generate(
+            *shell,
+            &mut command(),
+            env!("CARGO_BIN_NAME"),
+            &mut std::io::stdout(),
+        );
+        std::process::exit(0);
+    }
+    matches
+}
+
+/// The options of the TOML config file at `path` as command line arguments. Its keys are the
+/// long names of the options, e.g. `remove-duplicate-dirs = true` or `prefix = "pf_"`. Unless
+/// `required`, a missing file has no options.
+fn config_file_args(
+    command: &clap::Command,
+    path: &Path,
+    required: bool,
+) -> anyhow::Result<Vec<OsString>> {
+    let text = match std::fs::read_to_string(path) {
+        Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        text => {
+            text.map_err(|err| anyhow!("Failed to read config file {}: {}", path.display(), err))?
+        }
+    };
+    let table: toml::Table = toml::from_str(&text)
+        .map_err(|err| anyhow!("Invalid config file {}: {}", path.display(), err))?;
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_long() == Some(name.as_str())
+                    || arg
+                        .get_all_aliases()
+                        .is_some_and(|aliases| aliases.contains(&name.as_str()))
+            })
+            .filter(|arg| arg.get_id() != CONFIG_ARG)
+            .ok_or_else(|| anyhow!("Unknown option {} in config file {}", key, path.display()))?;
+        let option = format!("--{}", arg.get_long().unwrap_or_default());
+        let invalid = || {
+            anyhow!(
+                "Invalid value for {} in config file {}",
+                key,
+                path.display()
+            )
+        };
+        let scalar = |value: &toml::Value| match value {
+            toml::Value::String(value) => Ok(value.clone()),
+            toml::Value::Integer(value) => Ok(value.to_string()),
+            toml::Value::Float(value) => Ok(value.to_string()),
+            _ => Err(invalid()),
+        };
+        match (arg.get_action(), &value) {
+            (clap::ArgAction::SetTrue, toml::Value::Boolean(value)) => {
+                if *value {
+                    args.push(option.into());
+                }
+            }
+            (clap::ArgAction::Count, toml::Value::Integer(count)) => {
+                args.extend((0..*count).map(|_| OsString::from(&option)));
+            }
+            (clap::ArgAction::Append, toml::Value::Array(values)) => {
+                for value in values {
+                    args.push(OsString::from(&option));
+                    args.push(scalar(value)?.into());
+                }
+            }
+            (clap::ArgAction::Set, toml::Value::Array(values)) => {
+                let values = values
+                    .iter()
+                    .map(scalar)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                args.push(option.into());
+                match arg.get_value_delimiter() {
+                    Some(delimiter) => args.push(values.join(&delimiter.to_string()).into()),
+                    None => args.extend(values.into_iter().map(OsString::from)),
+                }
+            }
+            (clap::ArgAction::Set | clap::ArgAction::Append, value) => {
+                args.push(option.into());
+                args.push(scalar(value)?.into());
+            }
+            _ => return Err(invalid()),
         }
     }
+    Ok(args)
 }
 
-fn get_matches() -> clap::ArgMatches {
+fn command() -> clap::Command {
     let downloads_dir_arg = clap::Arg::new(DOWNLOADS_ARG)
-        .required(true)
+        .required_unless_present_any([
+            UNDO_ARG,
+            GALLERY_ARG,
+            VERIFY_ARG,
+            WRITE_CHECKSUMS_ARG,
+            COMPARE_ARG,
+            APPLY_ARG,
+            CHECK_CHECKSUMS_ARG,
+            COMPLETIONS_ARG,
+            SELF_TEST_ARG,
+        ])
         .long(DOWNLOADS_ARG)
+        .alias("downloads-dir")
+        .env("PORTRAITS_DOWNLOADS_DIR")
         .action(clap::ArgAction::Set)
         .value_name("PATH")
         .value_parser(clap::builder::PathBufValueParser::new())
@@ -52,8 +598,10 @@ The contents will be moved to the Portraits directory.
 This path may equal that of the Portraits directory."#,
         );
     let portraits_dir_arg = clap::Arg::new(PORTRAITS_ARG)
-        .required(true)
+        .required_unless_present_any([UNDO_ARG, COMPLETIONS_ARG, SELF_TEST_ARG])
         .long(PORTRAITS_ARG)
+        .alias("portraits-dir")
+        .env("PORTRAITS_TARGET_DIR")
         .action(clap::ArgAction::Set)
         .value_name("PATH")
         .value_parser(clap::builder::PathBufValueParser::new())
@@ -63,9 +611,9 @@ This path may equal that of the Portraits directory."#,
         .long(PREFIX_ARG)
         .action(clap::ArgAction::Set)
         .value_name("PREFIX")
-        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+        .value_parser(name_part)
         .default_value("pf_portrait_")
-        .help(r#"Every directory in the Portraits directory will have this prefix"#);
+        .help(r#"Every directory in the Portraits directory will have this prefix, which may be empty"#);
     let keep_original_path_arg = clap::Arg::new(KEEP_ORIGINAL_PATH_ARG)
         .required(false)
         .long(KEEP_ORIGINAL_PATH_ARG)
@@ -81,24 +629,978 @@ This path may equal that of the Portraits directory."#,
         .long(REMOVE_DUPLICATE_DIRS_ARG)
         .action(clap::ArgAction::SetTrue)
         .help(r#"Remove all directories in the downloads directory whose "Small.png", "Medium.png" and "Fulllength.png" match that of another."#);
+    let dry_run_arg = clap::Arg::new(DRY_RUN_ARG)
+        .required(false)
+        .long(DRY_RUN_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Only report the renames and deletions that would be performed, without touching the filesystem."#);
+    let copy_arg = clap::Arg::new(COPY_ARG)
+        .required(false)
+        .long(COPY_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Copy "Small.png", "Medium.png" and "Fulllength.png" into the Portraits directory instead of moving the directories, so the downloads dir is left untouched."#);
+    let hash_algorithm_arg = clap::Arg::new(HASH_ALGORITHM_ARG)
+        .required(false)
+        .long(HASH_ALGORITHM_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("ALGORITHM")
+        .value_parser(["md5", "sha256", "blake3"])
+        .default_value("md5")
+        .help(r#"The hash algorithm used to compare "Small.png", "Medium.png" and "Fulllength.png" when removing duplicate dirs"#);
+    let perceptual_dedup_arg = clap::Arg::new(PERCEPTUAL_DEDUP_ARG)
+        .required(false)
+        .long(PERCEPTUAL_DEDUP_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .requires(REMOVE_DUPLICATE_DIRS_ARG)
+        .help(r#"Also remove directories whose "Medium.png" looks like that of another, even if the files differ (e.g. re-exported at a different compression)."#);
+    let perceptual_threshold_arg = clap::Arg::new(PERCEPTUAL_THRESHOLD_ARG)
+        .required(false)
+        .long(PERCEPTUAL_THRESHOLD_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("DISTANCE")
+        .value_parser(clap::value_parser!(u32).range(0..=64))
+        .default_value("5")
+        .help(r#"The maximum number of differing bits (out of 64) between two perceptual hashes for them to be considered duplicates"#);
+    let validate_png_arg = clap::Arg::new(VALIDATE_PNG_ARG)
+        .required(false)
+        .long(VALIDATE_PNG_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Skip directories whose "Small.png", "Medium.png" or "Fulllength.png" do not start with a valid PNG signature and header."#);
+    let check_dimensions_arg = clap::Arg::new(CHECK_DIMENSIONS_ARG)
+        .required(false)
+        .long(CHECK_DIMENSIONS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Warn about directories whose "Small.png", "Medium.png" or "Fulllength.png" are not roughly 64x76, 152x175 and 692x1024."#);
+    let strict_dimensions_arg = clap::Arg::new(STRICT_DIMENSIONS_ARG)
+        .required(false)
+        .long(STRICT_DIMENSIONS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .requires(CHECK_DIMENSIONS_ARG)
+        .help(r#"Skip the directories with wrong dimensions instead of only warning about them."#);
+    let generate_missing_arg = clap::Arg::new(GENERATE_MISSING_ARG)
+        .required(false)
+        .long(GENERATE_MISSING_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"For directories in the downloads directory whose only missing required files are "Small.png" or "Medium.png", generate them as PNGs by downscaling their "Fulllength.png", in any of the --image-extensions. A dry run plans to move these directories as if they had been generated."#);
+    let no_progress_arg = clap::Arg::new(NO_PROGRESS_ARG)
+        .required(false)
+        .long(NO_PROGRESS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Do not show progress bars while scanning, checking for duplicates and moving."#);
+    let report_arg = clap::Arg::new(REPORT_ARG)
+        .required(false)
+        .long(REPORT_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Write a JSON report of every move and deletion to this path. Use "-" to write it to stdout instead of the summary."#);
+    let undo_log_arg = clap::Arg::new(UNDO_LOG_ARG)
+        .required(false)
+        .long(UNDO_LOG_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"The append-only log to which every rename and deletion is recorded before it happens [default: "portraits_undo.jsonl" in the Portraits directory]"#);
+    let undo_arg = clap::Arg::new(UNDO_ARG)
+        .required(false)
+        .long(UNDO_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("LOGFILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Instead of scanning and moving, move the directories recorded in this undo log back to where they came from. Deleted directories cannot be restored and are only reported."#);
+    let max_depth_arg = clap::Arg::new(MAX_DEPTH_ARG)
+        .required(false)
+        .long(MAX_DEPTH_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(usize))
+        .help(r#"Do not scan the downloads directory deeper than N levels, 0 meaning only its immediate subdirectories"#);
+    let jobs_arg = clap::Arg::new(JOBS_ARG)
+        .required(false)
+        .long(JOBS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(usize))
+        .help(r#"Scan the downloads directory on N threads, which helps on slow (e.g. network) drives"#);
+    let follow_symlinks_arg = clap::Arg::new(FOLLOW_SYMLINKS_ARG)
+        .required(false)
+        .long(FOLLOW_SYMLINKS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Descend into symbolically linked directories while scanning the downloads directory. Directories that were already scanned (e.g. because of a symlink loop) are skipped."#);
+    let include_hidden_arg = clap::Arg::new(INCLUDE_HIDDEN_ARG)
+        .required(false)
+        .long(INCLUDE_HIDDEN_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Also scan directories whose name starts with a ".", such as ".git" or ".thumbnails", which are skipped by default."#);
+    let verbose_arg = clap::Arg::new(VERBOSE_ARG)
+        .required(false)
+        .short('v')
+        .long(VERBOSE_ARG)
+        .action(clap::ArgAction::Count)
+        .help(r#"Print additional details about the run, such as every rename and the number of skipped hidden directories. Given twice (-vv), also print every directory visited while scanning, whether it was included and which required files were found in it."#);
+    let quiet_arg = clap::Arg::new(QUIET_ARG)
+        .required(false)
+        .short('q')
+        .long(QUIET_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with(VERBOSE_ARG)
+        .help(r#"Only print errors and the final summary."#);
+    let exclude_arg = clap::Arg::new(EXCLUDE_ARG)
+        .required(false)
+        .long(EXCLUDE_ARG)
+        .action(clap::ArgAction::Append)
+        .value_name("GLOB")
+        .value_parser(globset::Glob::new)
+        .help(r#"Neither scan nor move the directories whose path relative to the downloads directory matches this glob pattern, e.g. "experimental/*". May be given multiple times."#);
+    let case_sensitive_arg = clap::Arg::new(CASE_SENSITIVE_ARG)
+        .required(false)
+        .long(CASE_SENSITIVE_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Only accept the exact file names "Small.png", "Medium.png" and "Fulllength.png". By default, e.g. "small.png" or "FULLLENGTH.PNG" are accepted too."#);
+    let required_files_arg = clap::Arg::new(REQUIRED_FILES_ARG)
+        .required(false)
+        .long(REQUIRED_FILES_ARG)
+        .action(clap::ArgAction::Set)
+        .num_args(1..)
+        .value_name("NAME")
+        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+        .default_values(["Small.png", "Medium.png", "Fulllength.png"])
+        .help(r#"The files which a directory must contain to be considered a portrait directory, e.g. to also require a "Background.png""#);
+    let dedupe_portraits_arg = clap::Arg::new(DEDUPE_PORTRAITS_ARG)
+        .required(false)
+        .long(DEDUPE_PORTRAITS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Remove all directories in the Portraits directory whose files match that of another, e.g. duplicates accumulated by earlier runs."#);
+    let confirm_arg = clap::Arg::new(CONFIRM_ARG)
+        .required(false)
+        .long(CONFIRM_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Before erasing any directories, list them and wait for "yes" to be typed. Nothing is erased if stdin is not a terminal."#);
+    let trash_dir_arg = clap::Arg::new(TRASH_DIR_ARG)
+        .required(false)
+        .long(TRASH_DIR_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Move useless and duplicate directories into this directory instead of deleting them, so they can be reviewed (and recovered with --undo)."#);
+    let log_file_arg = clap::Arg::new(LOG_FILE_ARG)
+        .required(false)
+        .long(LOG_FILE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Append a timestamped line for every rename, skip and deletion to this file, which is created if needed."#);
+    let name_template_arg = clap::Arg::new(NAME_TEMPLATE_ARG)
+        .required(false)
+        .long(NAME_TEMPLATE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("TEMPLATE")
+        .value_parser(NameTemplate::parse)
+        .help(r#"How to name the directories in the Portraits directory, using the placeholders {prefix}, {index}, {path} (the original path joined with the separator) and {attempt}, e.g. "{prefix}{index:04}". Unless {attempt} is used, it is appended on collisions."#);
+    let separator_arg = clap::Arg::new(SEPARATOR_ARG)
+        .required(false)
+        .long(SEPARATOR_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("STR")
+        .value_parser(name_part)
+        .default_value("_")
+        .help(r#"Joins the components of the original path when keeping it, and precedes the attempt number on collisions"#);
+    let attempt_padding_arg = clap::Arg::new(ATTEMPT_PADDING_ARG)
+        .required(false)
+        .long(ATTEMPT_PADDING_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(u32).range(1..=9))
+        .default_value("3")
+        .help(r#"The number of digits of the attempt number appended on collisions, e.g. 3 for "_001", which allows for 10^N - 1 collisions"#);
+    let preserve_tree_arg = clap::Arg::new(PRESERVE_TREE_ARG)
+        .required(false)
+        .long(PRESERVE_TREE_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Recreate the directory structure of the downloads dir in the Portraits directory, e.g. "packA/gimli" becomes "packA/pf_portrait_gimli", instead of numbering the directories or joining their original path into one name."#);
+    let strip_prefix_repeat_arg = clap::Arg::new(STRIP_PREFIX_REPEAT_ARG)
+        .required(false)
+        .long(STRIP_PREFIX_REPEAT_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Strip the prefix from the original names as often as it occurs at their start, e.g. "pf_portrait_pf_portrait_gimli" becomes "gimli" instead of "pf_portrait_gimli"."#);
+    let sort_arg = clap::Arg::new(SORT_ARG)
+        .required(false)
+        .long(SORT_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("ORDER")
+        .value_parser(["name", "mtime", "size", "none"])
+        .default_value("none")
+        .help(r#"The order in which the portrait dirs are moved, and thus numbered: by path, oldest first, smallest first or as found. Sorting makes the names reproducible across runs."#);
+    let skip_installed_arg = clap::Arg::new(SKIP_INSTALLED_ARG)
+        .required(false)
+        .long(SKIP_INSTALLED_ARG)
+        .visible_alias("skip-existing")
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Leave alone the portrait dirs in the downloads dir whose "Small.png", "Medium.png" and "Fulllength.png" match those of a directory already in the Portraits directory, so that re-running the program does not install the same portraits again. With --remove-duplicate-dirs, they are erased instead."#);
+    let max_portraits_arg = clap::Arg::new(MAX_PORTRAITS_ARG)
+        .required(false)
+        .long(MAX_PORTRAITS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(usize))
+        .help(r#"Stop moving portrait dirs once the Portraits directory, including the portraits already in it, would hold N of them, as too many portraits make the game's selection unusable."#);
+    let gallery_arg = clap::Arg::new(GALLERY_ARG)
+        .required(false)
+        .long(GALLERY_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("OUTPUT.html")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Instead of moving anything, write an HTML page showing the "Medium.png" of every portrait dir in the Portraits directory."#);
+    let embed_arg = clap::Arg::new(EMBED_ARG)
+        .required(false)
+        .long(EMBED_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .requires(GALLERY_ARG)
+        .help(r#"Embed the images into the gallery, so that it can be viewed without the Portraits directory."#);
+    let manifest_arg = clap::Arg::new(MANIFEST_ARG)
+        .required(false)
+        .long(MANIFEST_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Write the name of every directory moved into the Portraits directory along with its original path in the downloads dir to PATH, as JSON if it ends with ".json" and as CSV otherwise, which cannot hold paths that are not valid UTF-8."#);
+    let verify_arg = clap::Arg::new(VERIFY_ARG)
+        .required(false)
+        .long(VERIFY_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("MANIFEST")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Instead of moving anything, check that the directories listed in a manifest written by --manifest still hold the files they were moved with."#);
+    let create_target_arg = clap::Arg::new(CREATE_TARGET_ARG)
+        .required(false)
+        .long(CREATE_TARGET_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Create the Portraits directory, along with its missing parents, if it does not exist yet."#);
+    let strict_arg = clap::Arg::new(STRICT_ARG)
+        .required(false)
+        .long(STRICT_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Exit with a failure status if any directory was skipped or could not be erased, rather than only if a portrait dir could not be moved."#);
+    let scan_archives_arg = clap::Arg::new(SCAN_ARCHIVES_ARG)
+        .required(false)
+        .long(SCAN_ARCHIVES_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Also look inside the ".zip" files of the downloads dir, extracting the "Small.png", "Medium.png" and "Fulllength.png" of the portrait dirs they contain into the Portraits directory. The archives themselves are left untouched, and their portraits are neither validated nor checked for duplicates."#);
+    let watch_arg = clap::Arg::new(WATCH_ARG)
+        .required(false)
+        .long(WATCH_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with(REPORT_ARG)
+        .help(r#"After the regular run, keep watching the downloads dir and install the portrait dirs that appear in it, until interrupted."#);
+    let cache_file_arg = clap::Arg::new(CACHE_FILE_ARG)
+        .required(false)
+        .long(CACHE_FILE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("PATH")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(format!(r#"Where to keep the checksums computed when looking for duplicates, so that the files that did not change need not be hashed again. Defaults to "{}" in the downloads dir."#, CACHE_FILE_NAME));
+    let threads_arg = clap::Arg::new(THREADS_ARG)
+        .required(false)
+        .long(THREADS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(usize))
+        .help(r#"Move (or copy) the directories into the Portraits directory on N threads, which helps with many portraits on fast drives"#);
+    let preserve_times_arg = clap::Arg::new(PRESERVE_TIMES_ARG)
+        .required(false)
+        .long(PRESERVE_TIMES_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .overrides_with(NO_PRESERVE_TIMES_ARG)
+        .help(r#"Keep the modification times of the files when copying them, with --copy or when moving across filesystems. This is the default."#);
+    let no_preserve_times_arg = clap::Arg::new(NO_PRESERVE_TIMES_ARG)
+        .required(false)
+        .long(NO_PRESERVE_TIMES_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .overrides_with(PRESERVE_TIMES_ARG)
+        .help(r#"Give the copied files a fresh modification time instead."#);
+    let report_extras_arg = clap::Arg::new(REPORT_EXTRAS_ARG)
+        .required(false)
+        .long(REPORT_EXTRAS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Instead of moving, list the files of every portrait directory in the downloads directory besides the required ones, such as a "readme.txt" or "Thumbs.db" that would be moved along."#);
+    let strip_extras_arg = clap::Arg::new(STRIP_EXTRAS_ARG)
+        .required(false)
+        .long(STRIP_EXTRAS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with(COPY_ARG)
+        .help(r#"Delete the files besides the required ones from the portrait directories before moving them."#);
+    let stats_arg = clap::Arg::new(STATS_ARG)
+        .required(false)
+        .long(STATS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Instead of moving, only count the portrait directories in the downloads directory, their duplicates and the useless directories in the Portraits directory, along with their sizes."#);
+    let image_extensions_arg = clap::Arg::new(IMAGE_EXTENSIONS_ARG)
+        .required(false)
+        .long(IMAGE_EXTENSIONS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("EXTENSIONS")
+        .value_delimiter(',')
+        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+        .default_value("png")
+        .help(r#"The comma-separated extensions with which "Small.png", "Medium.png" and "Fulllength.png" are accepted, in order of preference, e.g. "png,jpg,webp" to also accept "Small.jpg""#);
+    let convert_to_png_arg = clap::Arg::new(CONVERT_TO_PNG_ARG)
+        .required(false)
+        .long(CONVERT_TO_PNG_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Write the portraits accepted through --image-extensions, e.g. with a "Small.jpg", as PNGs into the Portraits directory, as the games expect. Only the required files are written, and directories holding only PNGs are moved as usual."#);
+    let config_arg = clap::Arg::new(CONFIG_ARG)
+        .required(false)
+        .long(CONFIG_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("TOML")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(format!(r#"Read options from this TOML file, whose keys are the long names of the options, e.g. `portraits = "C:/Portraits"` or `remove-duplicate-dirs = true`. The options given on the command line take precedence over those of the file, except for the ones that may be given multiple times, which are combined. Defaults to "{}" in the current directory, which is only read if it exists."#, CONFIG_FILE_NAME));
+    let completions_arg = clap::Arg::new(COMPLETIONS_ARG)
+        .required(false)
+        .long(COMPLETIONS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("SHELL")
+        .value_parser(clap::value_parser!(clap_complete::Shell))
+        .hide(true)
+        .help(r#"Print the completion script for this shell, e.g. "bash", and exit"#);
+    let bucket_arg = clap::Arg::new(BUCKET_ARG)
+        .required(false)
+        .long(BUCKET_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("BUCKET")
+        .value_parser(["by-letter", "by-source-top", "none"])
+        .default_value("none")
+        .help(r#"Group the moved portraits into subdirectories of the Portraits directory: by the first letter of their original name, by the top directory they came from in the downloads directory, or not at all."#);
+    let min_image_size_arg = clap::Arg::new(MIN_IMAGE_SIZE_ARG)
+        .required(false)
+        .long(MIN_IMAGE_SIZE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("BYTES")
+        .value_parser(clap::value_parser!(u64))
+        .help(r#"Skip the directories whose required files are all smaller than this, such as the 1x1 placeholders of some packs. In the Portraits directory, they are useless dirs (see --remove-useless-dirs)."#);
+    let dedup_on_arg = clap::Arg::new(DEDUP_ON_ARG)
+        .required(false)
+        .long(DEDUP_ON_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILES")
+        .value_parser(["all", "full", "small"])
+        .default_value("all")
+        .help(r#"Which files to compare when looking for duplicates: every required file, only "Fulllength.png" or only "Small.png". Comparing a single file is faster, at the risk of treating portraits that only differ in the other files as duplicates."#);
+    let collapse_redundant_arg = clap::Arg::new(COLLAPSE_REDUNDANT_ARG)
+        .required(false)
+        .long(COLLAPSE_REDUNDANT_ARG)
+        .alias("flatten-single-child")
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"When naming the moved dirs after their original path, drop the components that repeat their parent, so that "Pack/Pack/gimli/gimli" gives "pf_portrait_Pack_gimli" rather than "pf_portrait_Pack_Pack_gimli_gimli"."#);
+    let max_name_length_arg = clap::Arg::new(MAX_NAME_LENGTH_ARG)
+        .required(false)
+        .long(MAX_NAME_LENGTH_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("BYTES")
+        .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(16..))
+        .default_value("255")
+        .help(r#"Shorten the names of the moved dirs that would be longer than this, e.g. with --keep-original-path and deep packs, by cutting out their middle and appending a hash that keeps them distinct"#);
+    let sanitize_replacement_arg = clap::Arg::new(SANITIZE_REPLACEMENT_ARG)
+        .required(false)
+        .long(SANITIZE_REPLACEMENT_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("STR")
+        .value_parser(name_part)
+        .default_value("_")
+        .help(r#"Replaces the characters of the original path that are illegal in file names on this platform, e.g. ':' and '?' on Windows, when keeping it"#);
+    let windows_names_arg = clap::Arg::new(WINDOWS_NAMES_ARG)
+        .required(false)
+        .long(WINDOWS_NAMES_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Sanitize the kept original paths as on Windows on every platform, replacing e.g. ':' and '?' and avoiding names like "CON", for a Portraits dir shared with Windows"#);
+    let no_move_arg = clap::Arg::new(NO_MOVE_ARG)
+        .required(false)
+        .long(NO_MOVE_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with(WATCH_ARG)
+        .help(r#"Only clean up, e.g. erase the duplicates with --remove-duplicate-dirs, without moving anything into the Portraits dir"#);
+    let check_consistency_arg = clap::Arg::new(CHECK_CONSISTENCY_ARG)
+        .required(false)
+        .long(CHECK_CONSISTENCY_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Warn about the dirs whose Small, Medium and Fulllength images have such different colors that they seem to come from different artwork, and skip them with --strict"#);
+    let progress_format_arg = clap::Arg::new(PROGRESS_FORMAT_ARG)
+        .required(false)
+        .long(PROGRESS_FORMAT_ARG)
+        .action(clap::ArgAction::Set)
+        .value_parser(["human", "ndjson"])
+        .default_value("human")
+        .help(r#"With "ndjson", also write one JSON object per event (scan_started, dir_found, move_done, move_failed, dedup_removed, useless_removed, remove_failed, summary) to stdout, or to --progress-fd, for front-ends. The human output then goes to stderr."#);
+    let progress_fd_arg = clap::Arg::new(PROGRESS_FD_ARG)
+        .required(false)
+        .long(PROGRESS_FD_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FD")
+        .value_parser(clap::value_parser!(i32).range(1..))
+        .requires(PROGRESS_FORMAT_ARG)
+        .help(r#"The open file descriptor to which the events of --progress-format go instead of stdout, Unix only"#);
+    let retries_arg = clap::Arg::new(RETRIES_ARG)
+        .required(false)
+        .long(RETRIES_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(u32))
+        .default_value("0")
+        .help(r#"Retry a move or copy up to this many times when it fails with an error that may be transient, e.g. a busy or timed out network share"#);
+    let retry_delay_arg = clap::Arg::new(RETRY_DELAY_ARG)
+        .required(false)
+        .long(RETRY_DELAY_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("MS")
+        .value_parser(clap::value_parser!(u64))
+        .default_value("200")
+        .help(r#"How long to wait before the first retry of --retries, doubled for every further one"#);
+    let blocklist_arg = clap::Arg::new(BLOCKLIST_ARG)
+        .required(false)
+        .long(BLOCKLIST_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Never install the dirs whose checksum of --hash-algorithm over the files of --dedup-on, as in the manifest by default, is listed in this file, one per line with anything after it ignored. They are erased with --remove-duplicate-dirs."#);
+    let keep_arg = clap::Arg::new(KEEP_ARG)
+        .required(false)
+        .long(KEEP_ARG)
+        .action(clap::ArgAction::Set)
+        .value_parser(["first", "newest", "shortest-path", "longest-name"])
+        .default_value("first")
+        .help(r#"Which dir of a set of duplicates to keep: the first one found, the one with the most recently modified files, the one with the shortest relative path, or the one with the longest name. Ties go to the first relative path in alphabetical order."#);
+    let include_list_arg = clap::Arg::new(INCLUDE_LIST_ARG)
+        .required(false)
+        .long(INCLUDE_LIST_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Only move the portrait dirs listed in this file, one per line, by name or by path relative to the downloads dir, e.g. "gimli" or "packA/gimli". The dirs matching --exclude stay excluded."#);
+    let write_checksums_arg = clap::Arg::new(WRITE_CHECKSUMS_ARG)
+        .required(false)
+        .long(WRITE_CHECKSUMS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .conflicts_with(CHECK_CHECKSUMS_ARG)
+        .help(r#"Instead of moving anything, write the checksum of every portrait dir in the Portraits dir to this file, as sorted md5sum-style "digest  dir" lines, to fingerprint the library"#);
+    let check_checksums_arg = clap::Arg::new(CHECK_CHECKSUMS_ARG)
+        .required(false)
+        .long(CHECK_CHECKSUMS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Instead of moving anything, compare the portrait dirs in the Portraits dir to a file written by --write-checksums with the same --hash-algorithm, and report the added, removed and changed ones"#);
+    let report_incomplete_arg = clap::Arg::new(REPORT_INCOMPLETE_ARG)
+        .required(false)
+        .long(REPORT_INCOMPLETE_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Report the dirs holding some but not all of the required files, along with the missing ones, and keep those in the Portraits dir from being erased as useless"#);
+    let hash_buffer_size_arg = clap::Arg::new(HASH_BUFFER_SIZE_ARG)
+        .required(false)
+        .long(HASH_BUFFER_SIZE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("BYTES")
+        .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(4096..=1 << 30))
+        .default_value("65536")
+        .help(r#"How many bytes of a file to read at a time when hashing it, 64 KiB by default, e.g. more on slow network shares or less on systems short of memory"#);
+    let force_collisions_arg = clap::Arg::new(FORCE_COLLISIONS_ARG)
+        .required(false)
+        .long(FORCE_COLLISIONS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(u32))
+        .default_value("0")
+        .hide(true)
+        .help(r#"For testing: pretend the first N candidate destinations of every directory already exist"#);
+    let prune_empty_arg = clap::Arg::new(PRUNE_EMPTY_ARG)
+        .required(false)
+        .long(PRUNE_EMPTY_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with(COPY_ARG)
+        .help(r#"After moving, remove the directories of Downloads that were left empty, up to but not including Downloads itself"#);
+    let use_descriptor_arg = clap::Arg::new(USE_DESCRIPTOR_ARG)
+        .required(false)
+        .long(USE_DESCRIPTOR_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"When naming the directories after their original path, use the "name" of the portrait.json that some packs ship instead of the folder name, falling back to the folder name when the file is missing or invalid"#);
+    let min_age_arg = clap::Arg::new(MIN_AGE_ARG)
+        .required(false)
+        .long(MIN_AGE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("SECONDS")
+        .value_parser(clap::value_parser!(u64))
+        .help(r#"Skip the directories in which a file was modified less than this many seconds ago, as they may still be being extracted. They are picked up by a later run."#);
+    let self_test_arg = clap::Arg::new(SELF_TEST_ARG)
+        .required(false)
+        .long(SELF_TEST_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Check that the tool works here: move, deduplicate and erase a few portraits in a temporary directory, print PASS or FAIL, and clean up. Downloads and Portraits are left alone."#);
+    let max_attempts_arg = clap::Arg::new(MAX_ATTEMPTS_ARG)
+        .required(false)
+        .long(MAX_ATTEMPTS_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(u32).range(1..))
+        .default_value("1000000")
+        .help(r#"The most names to try for each directory before giving up on it. The numbered names are zero-padded to the width of the largest number, e.g. "pf_portrait_000042" for a million, so raising it past a million pads the new names to 7 digits or more. The attempts appended to the original path are further limited by --attempt-padding."#);
+    let aspect_min_arg = clap::Arg::new(ASPECT_MIN_ARG)
+        .required(false)
+        .long(ASPECT_MIN_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("RATIO")
+        .value_parser(positive_ratio)
+        .help(r#"Skip the directories whose Fulllength.png is less than this many times as high as it is wide, e.g. 1.2 to reject landscape and square images (the expected 692x1024 is about 1.48)"#);
+    let aspect_max_arg = clap::Arg::new(ASPECT_MAX_ARG)
+        .required(false)
+        .long(ASPECT_MAX_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("RATIO")
+        .value_parser(positive_ratio)
+        .help(r#"Skip the directories whose Fulllength.png is more than this many times as high as it is wide"#);
+    let review_arg = clap::Arg::new(REVIEW_ARG)
+        .required(false)
+        .long(REVIEW_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .conflicts_with_all([WATCH_ARG, NO_MOVE_ARG])
+        .help(r#"Step through the portrait directories before moving them, showing a preview of their Medium image in the terminal along with their destination, and only move the approved ones"#);
+    let name_from_metadata_arg = clap::Arg::new(NAME_FROM_METADATA_ARG)
+        .required(false)
+        .long(NAME_FROM_METADATA_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"When naming the directories after their original path, use the "Title", or else the "Description", that some artists put in the text metadata of Medium.png instead of the folder name. A descriptor read with --use-descriptor takes precedence."#);
+    let compare_arg = clap::Arg::new(COMPARE_ARG)
+        .required(false)
+        .long(COMPARE_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("OTHER_DIR")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"Instead of moving anything, compare the portrait dirs in the Portraits dir to those in OTHER_DIR by checksum, and list the ones only in either, e.g. before merging two collections"#);
+    let prefix_from_source_arg = clap::Arg::new(PREFIX_FROM_SOURCE_ARG)
+        .required(false)
+        .long(PREFIX_FROM_SOURCE_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Follow the prefix with the name of the top folder of Downloads each portrait comes from, e.g. "pf_portrait_packA_000000", to tell the packs apart in game"#);
+    let fix_casing_arg = clap::Arg::new(FIX_CASING_ARG)
+        .required(false)
+        .long(FIX_CASING_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Before moving, rename the files named like a required file but for their case to its exact name, e.g. "small.png" to "Small.png". The dirs holding both, which only case-sensitive filesystems allow, are reported either way and left as they are."#);
+    let limit_bytes_arg = clap::Arg::new(LIMIT_BYTES_ARG)
+        .required(false)
+        .long(LIMIT_BYTES_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("N")
+        .value_parser(clap::value_parser!(u64))
+        .help(r#"Stop moving portrait dirs, in the --sort order, once their required files would add up to more than N bytes, e.g. for a device short of space. The deferred dirs are listed, to be moved by a later run."#);
+    let plan_arg = clap::Arg::new(PLAN_ARG)
+        .required(false)
+        .long(PLAN_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .conflicts_with_all([WATCH_ARG, APPLY_ARG])
+        .help(r#"Write the moves to this JSON file as "operation", "src" and "dst" entries instead of performing them, like --dry-run, so that they can be reviewed, edited and performed with --apply"#);
+    let apply_arg = clap::Arg::new(APPLY_ARG)
+        .required(false)
+        .long(APPLY_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .conflicts_with(WATCH_ARG)
+        .help(r#"Instead of scanning Downloads, perform exactly the moves of a plan written with --plan, refusing those whose source is gone or whose destination is taken"#);
+    let prefer_richer_arg = clap::Arg::new(PREFER_RICHER_ARG)
+        .required(false)
+        .long(PREFER_RICHER_ARG)
+        .action(clap::ArgAction::Set)
+        .num_args(0..=1)
+        .default_missing_value("")
+        .value_name("FILE")
+        .help(r#"Among duplicate dirs, keep one holding the most files that are not required, before applying --keep. With a file name, e.g. "license.txt", keep one holding that file first"#);
+    let ignore_permission_errors_arg = clap::Arg::new(IGNORE_PERMISSION_ERRORS_ARG)
+        .required(false)
+        .long(IGNORE_PERMISSION_ERRORS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Only report the dirs that cannot be read or changed for lack of permissions with -v, they are still counted in the summary"#);
+    let clean_move_arg = clap::Arg::new(CLEAN_MOVE_ARG)
+        .required(false)
+        .long(CLEAN_MOVE_ARG)
+        .action(clap::ArgAction::Set)
+        .num_args(0..=1)
+        .default_missing_value("subfolder")
+        .value_parser(["subfolder", "leave"])
+        .value_name("EXTRAS")
+        .conflicts_with(COPY_ARG)
+        .help(r#"Move only the required files of every portrait dir into a new dir, rather than the whole dir, putting the other files in an "extras" subfolder of it or leaving them in the original dir"#);
+    let prefix_separator_arg = clap::Arg::new(PREFIX_SEPARATOR_ARG)
+        .required(false)
+        .long(PREFIX_SEPARATOR_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("SEPARATOR")
+        .value_parser(name_part)
+        .default_value("")
+        .help(r#"Put this between the prefix and the rest of every name, e.g. "_" for "myprefix_gimli" rather than "myprefixgimli", unless the prefix is empty"#);
+    let list_duplicates_arg = clap::Arg::new(LIST_DUPLICATES_ARG)
+        .required(false)
+        .long(LIST_DUPLICATES_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"Instead of moving, list the sets of portrait dirs in the downloads dir whose files are the same, without erasing any, to decide which to keep"#);
+    let slot_map_arg = clap::Arg::new(SLOT_MAP_ARG)
+        .required(false)
+        .long(SLOT_MAP_ARG)
+        .action(clap::ArgAction::Set)
+        .value_name("FILE")
+        .value_parser(clap::builder::PathBufValueParser::new())
+        .help(r#"A file of "name = slot" lines, with # starting a comment, giving the portrait dirs with one of the names, ignoring case, exactly the slot as their name in the Portraits dir instead of the usual one. Two dirs mapped to the same slot are an error, while a dir whose slot already exists is skipped"#);
+    let dedupe_across_formats_arg = clap::Arg::new(DEDUPE_ACROSS_FORMATS_ARG)
+        .required(false)
+        .long(DEDUPE_ACROSS_FORMATS_ARG)
+        .action(clap::ArgAction::SetTrue)
+        .help(r#"When looking for duplicates, compare the decoded pixels of the images rather than their bytes, so that e.g. a PNG and a JPEG of the same portrait are duplicates. Decoding every image is much slower"#);
     clap::Command::new("Portraits")
         .before_help(r#"This program is intended to use with Owlcat's Pathfinder games Custom Portraits.
 As a first step, you must unpack all custom portraits into a directory structure (downloads dir).
 Next, this program will recursively scan the contents of that directory structure for directories which contain "Small.png", "Medium.png" and "Fulllength.png".
 Then, it will move those directories into the Portraits directory (portraits dir)."#)
         .version(env!("CARGO_PKG_VERSION"))
+        // As the options of the config file come first, those given again override them.
+        .args_override_self(true)
         .arg(downloads_dir_arg)
         .arg(portraits_dir_arg)
         .arg(prefix_arg)
         .arg(keep_original_path_arg)
         .arg(remove_useless_dirs_arg)
         .arg(remove_duplicate_dirs_arg)
-        .get_matches()
+        .arg(dry_run_arg)
+        .arg(copy_arg)
+        .arg(hash_algorithm_arg)
+        .arg(perceptual_dedup_arg)
+        .arg(perceptual_threshold_arg)
+        .arg(validate_png_arg)
+        .arg(check_dimensions_arg)
+        .arg(strict_dimensions_arg)
+        .arg(generate_missing_arg)
+        .arg(no_progress_arg)
+        .arg(report_arg)
+        .arg(undo_log_arg)
+        .arg(undo_arg)
+        .arg(max_depth_arg)
+        .arg(jobs_arg)
+        .arg(follow_symlinks_arg)
+        .arg(include_hidden_arg)
+        .arg(verbose_arg)
+        .arg(quiet_arg)
+        .arg(exclude_arg)
+        .arg(case_sensitive_arg)
+        .arg(required_files_arg)
+        .arg(dedupe_portraits_arg)
+        .arg(confirm_arg)
+        .arg(trash_dir_arg)
+        .arg(log_file_arg)
+        .arg(name_template_arg)
+        .arg(separator_arg)
+        .arg(attempt_padding_arg)
+        .arg(preserve_tree_arg)
+        .arg(strip_prefix_repeat_arg)
+        .arg(sort_arg)
+        .arg(skip_installed_arg)
+        .arg(max_portraits_arg)
+        .arg(gallery_arg)
+        .arg(embed_arg)
+        .arg(manifest_arg)
+        .arg(verify_arg)
+        .arg(create_target_arg)
+        .arg(strict_arg)
+        .arg(scan_archives_arg)
+        .arg(watch_arg)
+        .arg(cache_file_arg)
+        .arg(threads_arg)
+        .arg(preserve_times_arg)
+        .arg(no_preserve_times_arg)
+        .arg(report_extras_arg)
+        .arg(strip_extras_arg)
+        .arg(stats_arg)
+        .arg(image_extensions_arg)
+        .arg(convert_to_png_arg)
+        .arg(config_arg)
+        .arg(completions_arg)
+        .arg(bucket_arg)
+        .arg(min_image_size_arg)
+        .arg(dedup_on_arg)
+        .arg(collapse_redundant_arg)
+        .arg(max_name_length_arg)
+        .arg(sanitize_replacement_arg)
+        .arg(windows_names_arg)
+        .arg(no_move_arg)
+        .arg(check_consistency_arg)
+        .arg(progress_format_arg)
+        .arg(progress_fd_arg)
+        .arg(retries_arg)
+        .arg(retry_delay_arg)
+        .arg(blocklist_arg)
+        .arg(keep_arg)
+        .arg(include_list_arg)
+        .arg(write_checksums_arg)
+        .arg(check_checksums_arg)
+        .arg(report_incomplete_arg)
+        .arg(hash_buffer_size_arg)
+        .arg(force_collisions_arg)
+        .arg(prune_empty_arg)
+        .arg(use_descriptor_arg)
+        .arg(min_age_arg)
+        .arg(self_test_arg)
+        .arg(max_attempts_arg)
+        .arg(aspect_min_arg)
+        .arg(aspect_max_arg)
+        .arg(review_arg)
+        .arg(name_from_metadata_arg)
+        .arg(compare_arg)
+        .arg(prefix_from_source_arg)
+        .arg(fix_casing_arg)
+        .arg(limit_bytes_arg)
+        .arg(plan_arg)
+        .arg(apply_arg)
+        .arg(prefer_richer_arg)
+        .arg(ignore_permission_errors_arg)
+        .arg(clean_move_arg)
+        .arg(prefix_separator_arg)
+        .arg(list_duplicates_arg)
+        .arg(slot_map_arg)
+        .arg(dedupe_across_formats_arg)
 }
 
-fn assert_is_dir(path: &std::path::Path) {
+/// The value of the argument `id`, which has a default value.
+fn value<T: Clone + Send + Sync + 'static>(
+    matches: &clap::ArgMatches,
+    id: &str,
+) -> anyhow::Result<T> {
+    matches
+        .get_one::<T>(id)
+        .cloned()
+        .ok_or_else(|| anyhow!("Missing --{}", id))
+}
+
+/// The checksums listed in the file at `path`, skipping blank lines and "#" comments.
+fn read_blocklist(path: &Path) -> anyhow::Result<HashSet<String>> {
+    let blocklist = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("Failed to read blocklist {}: {}", path.display(), err))?;
+    Ok(blocklist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
+
+/// The slots of the slot map at `path` by the lowercase names of the directories, skipping
+/// blank lines and "#" comments.
+fn read_slot_map(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let slot_map = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("Failed to read slot map {}: {}", path.display(), err))?;
+    let mut slots = HashMap::new();
+    let mut names = HashSet::new();
+    for line in slot_map.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, slot) = line
+            .split_once('=')
+            .map(|(name, slot)| (name.trim(), slot.trim()))
+            .filter(|(name, slot)| !name.is_empty() && !slot.is_empty())
+            .ok_or_else(|| anyhow!("Invalid slot map line in {}: {}", path.display(), line))?;
+        if slot.contains(['/', '\\']) || slot == "." || slot == ".." {
+            return Err(anyhow!(
+                "{:?} in {} is not a directory name",
+                slot,
+                path.display()
+            ));
+        }
+        if !names.insert(slot.to_lowercase()) {
+            return Err(anyhow!(
+                "The slot {} is mapped twice in {}",
+                slot,
+                path.display()
+            ));
+        }
+        if slots.insert(name.to_lowercase(), slot.to_owned()).is_some() {
+            return Err(anyhow!("{} is mapped twice in {}", name, path.display()));
+        }
+    }
+    Ok(slots)
+}
+
+/// The entries of the include list at `path`, skipping blank lines and "#" comments.
+fn read_include_list(path: &Path) -> anyhow::Result<Vec<String>> {
+    let include_list = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("Failed to read include list {}: {}", path.display(), err))?;
+    Ok(include_list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+fn positive_ratio(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio.is_finite() && ratio > 0.0 => Ok(ratio),
+        _ => Err(format!("{} is not a positive number", value)),
+    }
+}
+
+/// `value` if it can be part of a file name, i.e. has no '/' or NUL.
+fn name_part(value: &str) -> Result<String, String> {
+    if value.contains(['/', '\0']) {
+        Err(format!("{:?} may not hold '/' or NUL", value))
+    } else {
+        Ok(value.to_owned())
+    }
+}
+
+/// `path` made absolute and without "." or ".." components, if it or its parent exists, e.g. a
+/// Portraits dir yet to be created.
+fn canonical(path: PathBuf) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return path;
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    match parent.canonicalize() {
+        Ok(parent) => parent.join(name),
+        Err(_) => path,
+    }
+}
+
+fn check_is_dir(path: &std::path::Path, arg: &str) -> anyhow::Result<()> {
     if path.is_dir() {
-        return;
+        return Ok(());
+    }
+    Err(anyhow!(
+        "--{} \"{}\" does not point to a directory, please create it or pass another path",
+        arg,
+        path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The config parsed from `args`, with the downloads and Portraits dirs given as
+    /// `downloads` and `portraits` in a new directory for the test `name`.
+    fn config_with(name: &str, downloads: &str, portraits: &str, args: &[&str]) -> Config {
+        let dir = std::env::temp_dir().join(format!("portraits-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("downloads")).unwrap();
+        std::fs::create_dir_all(dir.join("portraits")).unwrap();
+        let mut argv: Vec<OsString> = vec![
+            "portraits".into(),
+            "--downloads".into(),
+            dir.join(downloads).into(),
+            "--portraits".into(),
+            dir.join(portraits).into(),
+        ];
+        argv.extend(args.iter().map(OsString::from));
+        let config = from_matches(command().try_get_matches_from(argv).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    fn config(name: &str, args: &[&str]) -> Config {
+        config_with(name, "downloads", "portraits", args)
+    }
+
+    #[test]
+    fn prefix_separator() {
+        let separated = ["--prefix", "x", "--prefix-separator", "_"];
+        assert_eq!(config("prefix-separated", &separated).prefix, "x_");
+        let empty = ["--prefix", "", "--prefix-separator", "_"];
+        assert_eq!(config("prefix-empty", &empty).prefix, "");
+        assert_eq!(config("prefix-default", &[]).prefix, "pf_portrait_");
+    }
+
+    #[test]
+    fn canonical_dirs() {
+        let dotted = config_with("canonical-dotted", "./downloads", "./portraits", &[]);
+        let parent = config_with(
+            "canonical-parent",
+            "downloads/../downloads",
+            "portraits/../portraits",
+            &[],
+        );
+        for config in [&dotted, &parent] {
+            assert!(!config.downloads_dir.ends_with(".."));
+            assert_eq!(
+                config.undo_log,
+                config.portraits_dir.join(UNDO_LOG_FILE_NAME)
+            );
+            assert_eq!(
+                config.cache_file,
+                config.downloads_dir.join(CACHE_FILE_NAME)
+            );
+        }
+        let relative = |config: &Config, path: &Path| {
+            path.strip_prefix(config.downloads_dir.parent().unwrap())
+                .unwrap()
+                .to_path_buf()
+        };
+        assert_eq!(
+            relative(&dotted, &dotted.undo_log),
+            relative(&parent, &parent.undo_log)
+        );
+        assert_eq!(
+            relative(&dotted, &dotted.cache_file),
+            relative(&parent, &parent.cache_file)
+        );
+    }
+
+    #[test]
+    fn canonical_target_to_create() {
+        let config = config_with(
+            "canonical-create",
+            "downloads",
+            "downloads/../new",
+            &["--create-target"],
+        );
+        assert!(config.portraits_dir.ends_with("new"));
+        assert!(!config.undo_log.to_string_lossy().contains(".."));
+    }
+
+    #[test]
+    fn config_file() {
+        let dir = std::env::temp_dir().join(format!("portraits-{}-config", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let missing = dir.join("missing.toml");
+        assert!(config_file_args(&command(), &missing, false)
+            .unwrap()
+            .is_empty());
+        assert!(config_file_args(&command(), &missing, true).is_err());
+
+        let file = dir.join("portraits.toml");
+        std::fs::write(&file, "prefix = \"file_\"\nremove-duplicate-dirs = true\n").unwrap();
+        let args = with_config_file(vec![
+            "portraits".into(),
+            "--config".into(),
+            file.into(),
+            "--prefix".into(),
+            "cli_".into(),
+        ])
+        .unwrap();
+        assert_eq!(
+            &args[1..4],
+            ["--prefix", "file_", "--remove-duplicate-dirs"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        // The command line overrides the file, as repeated options do.
+        let repeated = ["--prefix", "file_", "--prefix", "cli_"];
+        assert_eq!(config("config-repeated", &repeated).prefix, "cli_");
     }
-    panic!("\"{}\" does not point to a directory", path.display());
 }
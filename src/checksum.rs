@@ -0,0 +1,390 @@
+use crate::{DedupOn, HashAlgorithm, PortraitDir, PORTRAIT_FILES};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+        match hash_algorithm {
+            HashAlgorithm::Md5 => Self::Md5(md5::Context::new()),
+            HashAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Md5(context) => context.consume(bytes),
+            Self::Sha256(hasher) => hasher.update(bytes),
+            Self::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md5(context) => context.compute().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+/// The digests of the required files of a portrait directory.
+#[derive(Eq, Hash, PartialEq)]
+pub struct Checksum {
+    digests: Vec<Vec<u8>>,
+}
+
+impl Checksum {
+    /// The checksum of the required files in `dir`, read `buffer_size` bytes at a time.
+    pub fn from_dir(
+        dir: &Path,
+        portrait_dir: &PortraitDir,
+        hash_algorithm: HashAlgorithm,
+        buffer_size: usize,
+    ) -> Option<Self> {
+        let digests = portrait_dir
+            .files(dir)?
+            .iter()
+            .map(|file| Self::check_file(file, hash_algorithm, buffer_size))
+            .collect::<Option<_>>()?;
+        Some(Self { digests })
+    }
+
+    /// Like `from_dir`, but reusing the digests of `cache` for the files that did not change.
+    /// Only the files selected by `dedup_on` are hashed.
+    pub fn from_dir_cached(
+        dir: &Path,
+        portrait_dir: &PortraitDir,
+        hash_algorithm: HashAlgorithm,
+        dedup_on: DedupOn,
+        cache: &mut ChecksumCache,
+    ) -> Option<Self> {
+        let [small, _, full] = PORTRAIT_FILES;
+        let files = match dedup_on {
+            DedupOn::All => portrait_dir.files(dir)?,
+            DedupOn::Full => vec![portrait_dir.find(dir, full)?],
+            DedupOn::Small => vec![portrait_dir.find(dir, small)?],
+        };
+        let digests = files
+            .iter()
+            .map(|file| cache.digest(file, hash_algorithm))
+            .collect::<Option<_>>()?;
+        Some(Self { digests })
+    }
+
+    /// The digest of the pixels of the image `file` as 8-bit RGBA, preceded by its size, which
+    /// is the same whatever its format.
+    fn check_pixels(file: &Path, hash_algorithm: HashAlgorithm) -> Option<Vec<u8>> {
+        let image = image::open(file).ok()?.into_rgba8();
+        let mut hasher = Hasher::new(hash_algorithm);
+        hasher.update(&image.width().to_le_bytes());
+        hasher.update(&image.height().to_le_bytes());
+        hasher.update(image.as_raw());
+        Some(hasher.finalize())
+    }
+
+    fn check_file(
+        file: &Path,
+        hash_algorithm: HashAlgorithm,
+        buffer_size: usize,
+    ) -> Option<Vec<u8>> {
+        let mut file = std::fs::File::open(file).ok()?;
+        let mut hasher = Hasher::new(hash_algorithm);
+        let mut buffer = vec![0; buffer_size];
+        loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => hasher.update(&buffer[..read]),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return None,
+            }
+        }
+        Some(hasher.finalize())
+    }
+}
+
+/// The size and modification time of a file, which tell whether its digest is still valid.
+fn file_stamp(file: &Path) -> Option<(u64, u64)> {
+    let metadata = std::fs::metadata(file).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos().try_into().ok()?))
+}
+
+#[derive(Deserialize, Serialize)]
+struct CacheEntry {
+    #[serde(with = "crate::encoded_path")]
+    path: PathBuf,
+    hash_algorithm: String,
+    size: u64,
+    modified_nanos: u64,
+    digest: Vec<u8>,
+}
+
+/// The digests of files computed by previous runs, keyed by their path, size and
+/// modification time.
+pub struct ChecksumCache {
+    entries: HashMap<(PathBuf, String), CacheEntry>,
+    changed: bool,
+    /// The size of the buffer into which the files missing from the cache are read.
+    buffer_size: usize,
+    /// Hash the decoded pixels of the images rather than their bytes, which is much slower.
+    pub decode_images: bool,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl ChecksumCache {
+    /// An empty cache.
+    pub fn new(buffer_size: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            changed: false,
+            buffer_size,
+            decode_images: false,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Reads the cache at `path`, starting afresh if there is none or it cannot be read.
+    pub fn load(path: &Path, buffer_size: usize) -> Self {
+        let entries: Vec<CacheEntry> = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                log::warn!("Ignoring checksum cache {}: {}", path.display(), err);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        let entries = entries
+            .into_iter()
+            .map(|entry| ((entry.path.clone(), entry.hash_algorithm.clone()), entry))
+            .collect();
+        Self {
+            entries,
+            ..Self::new(buffer_size)
+        }
+    }
+
+    /// Writes the cache to `path`, if anything changed since it was loaded.
+    pub fn save(&self, path: &Path) {
+        if !self.changed {
+            return;
+        }
+        let mut entries: Vec<&CacheEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| (&a.path, &a.hash_algorithm).cmp(&(&b.path, &b.hash_algorithm)));
+        let written = serde_json::to_vec(&entries)
+            .map_err(std::io::Error::from)
+            .and_then(|bytes| std::fs::write(path, bytes));
+        if let Err(err) = written {
+            log::error!("Failed to write checksum cache {}: {}", path.display(), err);
+        }
+    }
+
+    fn digest(&mut self, file: &Path, hash_algorithm: HashAlgorithm) -> Option<Vec<u8>> {
+        let (size, modified_nanos) = file_stamp(file)?;
+        // The digests of the pixels are kept apart from those of the bytes.
+        let key = if self.decode_images {
+            (file.to_path_buf(), format!("{}-pixels", hash_algorithm))
+        } else {
+            (file.to_path_buf(), hash_algorithm.to_string())
+        };
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.size == size && entry.modified_nanos == modified_nanos {
+                log::trace!("Cached checksum of {}", file.display());
+                self.hits += 1;
+                return Some(entry.digest.clone());
+            }
+        }
+        log::trace!("Hashing {}", file.display());
+        self.misses += 1;
+        let digest = if self.decode_images {
+            Checksum::check_pixels(file, hash_algorithm)?
+        } else {
+            Checksum::check_file(file, hash_algorithm, self.buffer_size)?
+        };
+        let entry = CacheEntry {
+            path: key.0.clone(),
+            hash_algorithm: key.1.clone(),
+            size,
+            modified_nanos,
+            digest: digest.clone(),
+        };
+        self.entries.insert(key, entry);
+        self.changed = true;
+        Some(digest)
+    }
+}
+
+/// The hexadecimal digests, separated by "-".
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, digest) in self.digests.iter().enumerate() {
+            if i > 0 {
+                f.write_str("-")?;
+            }
+            for byte in digest {
+                write!(f, "{:02x}", byte)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A difference hash of the "Medium.png" of a portrait directory, which stays close for
+/// images that look alike even if their bytes differ.
+#[derive(Clone, Copy)]
+pub struct PerceptualHash(u64);
+
+impl PerceptualHash {
+    pub fn from_dir(dir: &Path, portrait_dir: &PortraitDir) -> Option<Self> {
+        let medium = portrait_dir.find(dir, "Medium.png")?;
+        let image = image::open(medium).ok()?;
+        let image = image
+            .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+            .into_luma8();
+        let mut hash: u64 = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                hash <<= 1;
+                if image.get_pixel(x, y)[0] < image.get_pixel(x + 1, y)[0] {
+                    hash |= 1;
+                }
+            }
+        }
+        Some(Self(hash))
+    }
+
+    pub fn distance(self, other: Self) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+/// Perceptual hashes as a BK-tree, every child lying at its distance from its parent, so that
+/// looking for a close hash only visits the branches that may hold one.
+#[derive(Default)]
+pub(crate) struct PerceptualIndex {
+    /// Every hash along with its children, by their distance to it, the first being the root.
+    nodes: Vec<(PerceptualHash, HashMap<u32, usize>)>,
+}
+
+impl PerceptualIndex {
+    /// Whether a hash at most `threshold` away from `hash` was inserted.
+    pub fn contains_near(&self, hash: PerceptualHash, threshold: u32) -> bool {
+        let mut pending = if self.nodes.is_empty() {
+            Vec::new()
+        } else {
+            vec![0]
+        };
+        while let Some(node) = pending.pop() {
+            let (other, children) = &self.nodes[node];
+            let distance = hash.distance(*other);
+            if distance <= threshold {
+                return true;
+            }
+            // By the triangle inequality, the other children are too far away.
+            pending.extend(
+                children
+                    .iter()
+                    .filter(|(child_distance, _)| child_distance.abs_diff(distance) <= threshold)
+                    .map(|(_, child)| *child),
+            );
+        }
+        false
+    }
+
+    pub fn insert(&mut self, hash: PerceptualHash) {
+        let index = self.nodes.len();
+        let mut node = 0;
+        while node < index {
+            let distance = hash.distance(self.nodes[node].0);
+            match self.nodes[node].1.get(&distance) {
+                Some(child) => node = *child,
+                None => {
+                    self.nodes[node].1.insert(distance, index);
+                    break;
+                }
+            }
+        }
+        self.nodes.push((hash, HashMap::new()));
+    }
+}
+
+/// The share of the opaque pixels of an image falling in each of 4x4x4 color bins. Unlike
+/// `PerceptualHash`, it barely depends on the framing, so that the close-up "Small.png" and
+/// the whole "Fulllength.png" of the same artwork still match.
+pub(crate) struct ColorHistogram([f32; 64]);
+
+impl ColorHistogram {
+    pub fn from_file(path: &Path) -> Option<Self> {
+        let image = image::open(path).ok()?.thumbnail(64, 64).into_rgba8();
+        let mut bins = [0f32; 64];
+        let mut opaque = 0;
+        for pixel in image.pixels() {
+            let [r, g, b, a] = pixel.0;
+            if a < 128 {
+                continue;
+            }
+            bins[(r as usize >> 6) << 4 | (g as usize >> 6) << 2 | b as usize >> 6] += 1.0;
+            opaque += 1;
+        }
+        if opaque == 0 {
+            return None;
+        }
+        for bin in &mut bins {
+            *bin /= opaque as f32;
+        }
+        Some(Self(bins))
+    }
+
+    /// From 0 for images sharing no color to 1 for images with the same colors.
+    pub fn similarity(&self, other: &Self) -> f32 {
+        self.0.iter().zip(&other.0).map(|(a, b)| a.min(*b)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_md5() {
+        let file = std::env::temp_dir().join(format!("portraits-{}-streamed", std::process::id()));
+        // Longer than the buffer, and not a multiple of it.
+        let contents: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        std::fs::write(&file, &contents).unwrap();
+        let digest = Checksum::check_file(&file, HashAlgorithm::Md5, 4096).unwrap();
+        assert_eq!(digest, md5::compute(std::fs::read(&file).unwrap()).to_vec());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn perceptual_index() {
+        let hashes: Vec<PerceptualHash> = (0..200u64)
+            .map(|i| PerceptualHash(i.wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(i as u32)))
+            .collect();
+        let mut index = PerceptualIndex::default();
+        for (i, hash) in hashes.iter().enumerate() {
+            for threshold in [0, 8, 24, 32] {
+                let near = hashes[..i]
+                    .iter()
+                    .any(|other| hash.distance(*other) <= threshold);
+                assert_eq!(index.contains_near(*hash, threshold), near);
+            }
+            index.insert(*hash);
+        }
+        assert!(index.contains_near(hashes[0], 0));
+    }
+}
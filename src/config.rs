@@ -0,0 +1,391 @@
+use crate::{EraseOptions, EventStream, NameTemplate, PortraitDir, ScanOptions};
+use anyhow::anyhow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The hash algorithm used to detect duplicate portrait directories.
+#[derive(Clone, Copy, Debug)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Md5 => "md5",
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+        })
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "md5" => Ok(Self::Md5),
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(anyhow!("Unknown hash algorithm {}", s)),
+        }
+    }
+}
+
+/// The order in which the portrait directories are moved, and thus numbered.
+#[derive(Clone, Copy, Debug)]
+pub enum SortOrder {
+    /// The order in which they were found.
+    None,
+    Name,
+    /// Oldest first.
+    Mtime,
+    /// Smallest first.
+    Size,
+}
+
+/// The files of the portrait directories that are compared to find duplicates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DedupOn {
+    /// Every required file.
+    All,
+    /// Only "Fulllength.png", which rarely differs while the others are equal.
+    Full,
+    /// Only "Small.png", the quickest to hash.
+    Small,
+}
+
+/// Which directory of a set of duplicates is kept. Ties are broken by the relative path.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Keep {
+    /// The first one found, in the order of the scan.
+    First,
+    /// The one whose newest file was modified last.
+    Newest,
+    /// The one with the shortest path relative to the scanned directory.
+    ShortestPath,
+    /// The one with the longest name, which says most with `keep_original_path`.
+    LongestName,
+}
+
+/// Where a clean move puts the files of a portrait directory other than the required ones.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CleanMove {
+    /// In an "extras" subdirectory of the moved portrait directory.
+    Subfolder,
+    /// In the original directory, which is then not removed.
+    Leave,
+}
+
+/// The subdirectories of the Portraits directory into which the moved portraits are grouped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Bucket {
+    /// Right into the Portraits directory.
+    None,
+    /// The first letter of the original name, e.g. "G" for "gimli".
+    ByLetter,
+    /// The top directory of the original path in the downloads directory, e.g. "packA" for
+    /// "packA/elves/legolas".
+    BySourceTop,
+}
+
+/// How the events of a run are reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProgressFormat {
+    /// Only the human readable output.
+    Human,
+    /// Also as newline-delimited JSON, see `EventStream`.
+    Ndjson,
+}
+
+/// Everything that controls a run: where to look, where to put the found portraits and
+/// which of the optional steps to perform.
+#[derive(Debug)]
+pub struct Config {
+    /// The directory structure that is scanned for portrait directories.
+    pub downloads_dir: PathBuf,
+    /// The game's "Portraits" directory, into which the found portraits are moved.
+    pub portraits_dir: PathBuf,
+    /// The prefix given to every directory put into the Portraits directory.
+    pub prefix: String,
+    /// Name the moved directories after their original path in the downloads directory.
+    pub keep_original_path: bool,
+    /// Erase the directories in the Portraits directory that are not portraits.
+    pub remove_useless_dirs: bool,
+    /// Erase the portrait directories in the downloads directory that duplicate another.
+    pub remove_duplicate_dirs: bool,
+    /// Only report what would be done, without touching the filesystem.
+    pub dry_run: bool,
+    /// Copy the portrait files instead of moving the directories.
+    pub copy: bool,
+    /// The hash algorithm used by `remove_duplicate_dirs`.
+    pub hash_algorithm: HashAlgorithm,
+    /// Also erase portraits whose "Medium.png" looks like that of another.
+    pub perceptual_dedup: bool,
+    /// The maximum Hamming distance between two perceptual hashes deemed duplicates.
+    pub perceptual_threshold: u32,
+    /// Skip portrait directories whose files are not valid PNGs.
+    pub validate_png: bool,
+    /// Warn about portrait directories whose files do not have the expected dimensions.
+    pub check_dimensions: bool,
+    /// Skip, rather than only warn about, directories with unexpected dimensions.
+    pub strict_dimensions: bool,
+    /// Generate a missing "Small.png" and "Medium.png" from "Fulllength.png".
+    pub generate_missing: bool,
+    /// Show progress bars while scanning, checking for duplicates and moving.
+    pub progress: bool,
+    /// Where to write a JSON report of the run, "-" meaning stdout.
+    pub report: Option<PathBuf>,
+    /// The append-only log to which every move and deletion is recorded before it happens.
+    pub undo_log: PathBuf,
+    /// Instead of a regular run, replay this undo log in reverse.
+    pub undo: Option<PathBuf>,
+    /// Do not scan the downloads directory deeper than this, 0 being its immediate children.
+    pub max_depth: Option<usize>,
+    /// Scan the downloads directory on this many threads.
+    pub jobs: Option<usize>,
+    /// Descend into symbolically linked directories while scanning the downloads directory.
+    pub follow_symlinks: bool,
+    /// Descend into the directories whose name starts with a ".", e.g. ".git".
+    pub include_hidden: bool,
+    /// How much is logged: errors only, warnings and what is done, details or every visited
+    /// directory.
+    pub log_level: log::LevelFilter,
+    /// The patterns of the paths, relative to the downloads directory, that are not scanned.
+    pub exclude: globset::GlobSet,
+    /// Only accept the exact names "Small.png", "Medium.png" and "Fulllength.png", rather than e.g.
+    /// "small.png" too.
+    pub case_sensitive: bool,
+    /// The files that make up a portrait directory, by default "Small.png", "Medium.png" and
+    /// "Fulllength.png".
+    pub required_files: Vec<String>,
+    /// Erase the portrait directories in the Portraits directory that duplicate another.
+    pub dedupe_portraits: bool,
+    /// List the directories to be erased and ask for confirmation before erasing them.
+    pub confirm: bool,
+    /// Move the erased directories in here rather than deleting them.
+    pub trash_dir: Option<PathBuf>,
+    /// The file to which every operation is appended, with a timestamp.
+    pub log_file: Option<PathBuf>,
+    /// How to name the directories moved into the Portraits directory, instead of numbering them or
+    /// following `keep_original_path`.
+    pub name_template: Option<NameTemplate>,
+    /// Joins the components of the original path in the names of the moved directories, and the
+    /// attempt on collisions.
+    pub separator: String,
+    /// The width to which the attempt is zero-padded on collisions.
+    pub attempt_padding: u32,
+    /// Recreate the original directory structure below the Portraits directory instead of joining
+    /// the original path into one name.
+    pub preserve_tree: bool,
+    /// Strip every leading occurrence of the prefix from the original names, rather than only the
+    /// first.
+    pub strip_prefix_repeat: bool,
+    /// The order in which the portrait directories are moved, and thus numbered.
+    pub sort: SortOrder,
+    /// Leave alone the portrait directories whose files are already in the Portraits directory,
+    /// making re-runs incremental.
+    pub skip_installed: bool,
+    /// Do not move more portraits than would make the Portraits directory hold this many.
+    pub max_portraits: Option<usize>,
+    /// Instead of a regular run, write an HTML page showing the portraits in the Portraits
+    /// directory here.
+    pub gallery: Option<PathBuf>,
+    /// Embed the images into the gallery rather than referencing them.
+    pub embed: bool,
+    /// Where to write the original path of every directory moved into the Portraits directory, as
+    /// JSON or CSV.
+    pub manifest: Option<PathBuf>,
+    /// Instead of a regular run, check the directories of this manifest against the checksums it
+    /// records.
+    pub verify: Option<PathBuf>,
+    /// Create the Portraits directory if it does not exist yet.
+    pub create_target: bool,
+    /// Exit with a failure if any directory was skipped or could not be handled, not only if a move
+    /// failed.
+    pub strict: bool,
+    /// Also install the portrait directories inside the zip archives of the downloads directory.
+    pub scan_archives: bool,
+    /// After the regular run, keep installing the portrait directories that appear in the downloads
+    /// directory.
+    pub watch: bool,
+    /// Where the checksums of the files are kept between runs, so that unchanged files need not be
+    /// hashed again.
+    pub cache_file: PathBuf,
+    /// Move (or copy) the directories on this many threads.
+    pub threads: Option<usize>,
+    /// Keep the modification times of the files and directories that are copied, including when
+    /// moving across filesystems.
+    pub preserve_times: bool,
+    /// Instead of moving, list the files of every portrait directory that are not required.
+    pub report_extras: bool,
+    /// Remove the files that are not required from the portrait directories before moving them.
+    pub strip_extras: bool,
+    /// Instead of moving, count the portrait directories, their duplicates and the useless
+    /// directories, and their sizes.
+    pub stats: bool,
+    /// The extensions with which the required PNGs are also accepted, e.g. "jpg".
+    pub image_extensions: Vec<String>,
+    /// Write the required files that are not PNGs, e.g. "Small.jpg", as PNGs in the Portraits
+    /// directory.
+    pub convert_to_png: bool,
+    /// Group the moved portraits into these subdirectories of the Portraits directory.
+    pub bucket: Bucket,
+    /// Treat the directories whose required files are all smaller than this many bytes as
+    /// placeholders rather than portraits.
+    pub min_image_size: Option<u64>,
+    /// The files compared by `remove_duplicate_dirs`, `dedupe_portraits` and `skip_installed`.
+    pub dedup_on: DedupOn,
+    /// Drop the repeated components of the original paths, e.g. "Pack/Pack/gimli" becoming
+    /// "Pack/gimli", when naming after them.
+    pub collapse_redundant: bool,
+    /// The length in bytes beyond which the names of the moved directories are shortened.
+    pub max_name_length: usize,
+    /// Replaces the characters of the original names that are illegal in file names.
+    pub sanitize_replacement: String,
+    /// Also sanitize the characters and names that Windows refuses, whatever the platform.
+    pub windows_names: bool,
+    /// Only prepare the downloads dir and clean up, without moving anything.
+    pub no_move: bool,
+    /// Warn about the directories whose images look like different artwork, skipping them if
+    /// `strict`.
+    pub check_consistency: bool,
+    /// How the events of the run are reported, besides the human readable output.
+    pub progress_format: ProgressFormat,
+    /// The file descriptor to which the events go with `ProgressFormat::Ndjson`, stdout if `None`.
+    pub progress_fd: Option<i32>,
+    /// How many times a move or copy failing with a seemingly transient error is retried.
+    pub retries: u32,
+    /// How long to wait before the first retry, doubled for every further one.
+    pub retry_delay: Duration,
+    /// The checksums, in lowercase hex, of the portrait directories that are never installed.
+    pub blocklist: HashSet<String>,
+    /// Which directory of a set of duplicates is kept, the others being erased.
+    pub keep: Keep,
+    /// The names, or paths relative to the downloads directory, of the only directories that are
+    /// moved.
+    pub include_list: Option<Vec<String>>,
+    /// Instead of moving anything, write the checksum of every directory of the Portraits directory
+    /// to this file.
+    pub write_checksums: Option<PathBuf>,
+    /// Instead of moving anything, compare the directories of the Portraits directory to this file
+    /// written by `write_checksums`.
+    pub check_checksums: Option<PathBuf>,
+    /// Report the directories holding some but not all of the required files, and keep them from
+    /// being erased as useless.
+    pub report_incomplete: bool,
+    /// The size in bytes of the buffer into which the files are read to hash them.
+    pub hash_buffer_size: usize,
+    /// Pretend the first this many candidate destinations of every directory already exist, to
+    /// exercise the numbering on collisions.
+    pub force_collisions: u32,
+    /// Remove the directories of the downloads directory left empty by the moves.
+    pub prune_empty: bool,
+    /// Name the directories after the "name" of their "portrait.json" rather than their folder
+    /// name, when there is one.
+    pub use_descriptor: bool,
+    /// Skip the directories in which a file was modified more recently than this, as they may still
+    /// be being written.
+    pub min_age: Option<Duration>,
+    /// Run the pipeline on a fixture in a temporary directory instead, to check that it works.
+    pub self_test: bool,
+    /// The most names tried for each directory before giving up on it, which also sets the width of
+    /// the numbers naming the directories.
+    pub max_attempts: u32,
+    /// Skip the directories whose "Fulllength.png" is less than this many times as high as it is
+    /// wide.
+    pub aspect_min: Option<f64>,
+    /// Skip the directories whose "Fulllength.png" is more than this many times as high as it is
+    /// wide.
+    pub aspect_max: Option<f64>,
+    /// Show every directory about to be moved and ask whether to move it.
+    pub review: bool,
+    /// Name the directories after the title or description in the text metadata of their
+    /// "Medium.png", when it has one.
+    pub name_from_metadata: bool,
+    /// Instead of moving anything, compare the portrait directories of the Portraits directory to
+    /// those of this one.
+    pub compare: Option<PathBuf>,
+    /// Follow the prefix with the name of the top directory of the downloads directory each
+    /// directory comes from.
+    pub prefix_from_source: bool,
+    /// Rename the files named like a required file but for their case to the exact name before
+    /// moving.
+    pub fix_casing: bool,
+    /// Stop moving once the required files of the moved directories add up to this many bytes.
+    pub limit_bytes: Option<u64>,
+    /// Write the moves to this file instead of performing them, to be applied later.
+    pub plan: Option<PathBuf>,
+    /// Instead of scanning, perform the moves of this plan written with `plan`.
+    pub apply: Option<PathBuf>,
+    /// Among duplicates, keep one holding this extra file, if not empty, else the one with the most
+    /// extra files.
+    pub prefer_richer: Option<String>,
+    /// Only log the directories that cannot be read or changed for lack of permissions at the debug
+    /// level, still counting them.
+    pub ignore_permission_errors: bool,
+    /// Move only the required files of every portrait directory into a new one, putting the others
+    /// as this says.
+    pub clean_move: Option<CleanMove>,
+    /// Instead of moving, list the sets of portrait directories of the downloads directory that
+    /// duplicate each other.
+    pub list_duplicates: bool,
+    /// The exact names, by the lowercase name of their original directory, of the portrait
+    /// directories that are not named as usual.
+    pub slot_map: HashMap<String, String>,
+    /// Hash the decoded pixels of the images when looking for duplicates, so that the same portrait
+    /// in another format is a duplicate too.
+    pub dedupe_across_formats: bool,
+}
+
+impl Config {
+    /// The portrait directories as configured, e.g. by `required_files`.
+    pub fn portrait_dir(&self) -> PortraitDir {
+        PortraitDir {
+            required_files: self.required_files.clone(),
+            case_sensitive: self.case_sensitive,
+            extensions: self.image_extensions.clone(),
+            min_image_size: self.min_image_size,
+        }
+    }
+
+    /// How the downloads directory is scanned, leaving out the archives.
+    pub fn scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            progress: self.progress,
+            max_depth: self.max_depth,
+            jobs: self.jobs,
+            follow_symlinks: self.follow_symlinks,
+            include_hidden: self.include_hidden,
+            exclude: self.exclude.clone(),
+            ignore_permission_errors: self.ignore_permission_errors,
+            ..ScanOptions::default()
+        }
+    }
+
+    /// How the Portraits directory, or one like it, is scanned: all of it, as it only holds
+    /// what was installed.
+    pub fn portraits_scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            progress: self.progress,
+            include_hidden: self.include_hidden,
+            ignore_permission_errors: self.ignore_permission_errors,
+            ..ScanOptions::default()
+        }
+    }
+
+    /// How directories are erased, the outcome of each going to `events`.
+    pub fn erase_options<'a>(&'a self, events: &'a EventStream) -> EraseOptions<'a> {
+        EraseOptions {
+            dry_run: self.dry_run,
+            confirm: self.confirm,
+            trash_dir: self.trash_dir.as_deref(),
+            events: Some(events),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! (De)serialization of paths that are not valid UTF-8, which serde refuses by default, e.g.
+//! the names of directories unpacked from archives with odd encodings. Such paths are written
+//! as an array of their bytes instead of a string.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum EncodedPath {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&Path> for EncodedPath {
+    fn from(path: &Path) -> Self {
+        match path.to_str() {
+            Some(path) => Self::Utf8(path.to_owned()),
+            None => Self::Bytes(path.as_os_str().as_encoded_bytes().to_vec()),
+        }
+    }
+}
+
+impl From<EncodedPath> for PathBuf {
+    fn from(path: EncodedPath) -> Self {
+        match path {
+            EncodedPath::Utf8(path) => path.into(),
+            EncodedPath::Bytes(bytes) => from_bytes(bytes),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+pub(crate) fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    EncodedPath::from(path).serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    EncodedPath::deserialize(deserializer).map(PathBuf::from)
+}
+
+pub(crate) mod option {
+    use super::EncodedPath;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::PathBuf;
+
+    pub(crate) fn serialize<S: Serializer>(
+        path: &Option<PathBuf>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        path.as_deref().map(EncodedPath::from).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<PathBuf>, D::Error> {
+        Option::<EncodedPath>::deserialize(deserializer).map(|path| path.map(PathBuf::from))
+    }
+}
+
+pub(crate) mod vec {
+    use super::EncodedPath;
+    use serde::Serializer;
+    use std::path::PathBuf;
+
+    pub(crate) fn serialize<S: Serializer>(
+        paths: &[PathBuf],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| EncodedPath::from(path.as_path())))
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{Counts, Operation, OperationOutcome};
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// What happened during a run, for front-ends to follow.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    ScanStarted {
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        dir: &'a Path,
+    },
+    DirFound {
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        dir: &'a Path,
+        index: usize,
+        total: usize,
+    },
+    MoveDone {
+        operation: Operation,
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        src: &'a Path,
+        #[serde(serialize_with = "crate::encoded_path::option::serialize")]
+        dst: &'a Option<PathBuf>,
+        planned: bool,
+    },
+    MoveFailed {
+        operation: Operation,
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        src: &'a Path,
+        #[serde(serialize_with = "crate::encoded_path::option::serialize")]
+        dst: &'a Option<PathBuf>,
+        error: String,
+    },
+    DedupRemoved {
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        src: &'a Path,
+        #[serde(serialize_with = "crate::encoded_path::option::serialize")]
+        dst: &'a Option<PathBuf>,
+        planned: bool,
+    },
+    UselessRemoved {
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        src: &'a Path,
+        #[serde(serialize_with = "crate::encoded_path::option::serialize")]
+        dst: &'a Option<PathBuf>,
+        planned: bool,
+    },
+    RemoveFailed {
+        operation: Operation,
+        #[serde(serialize_with = "crate::encoded_path::serialize")]
+        src: &'a Path,
+        error: String,
+    },
+    Summary {
+        dry_run: bool,
+        #[serde(flatten)]
+        counts: &'a Counts,
+    },
+}
+
+/// A stream of events as newline-delimited JSON, one object per event with its name in
+/// "event". Events may be emitted from several threads, e.g. as the directories are moved.
+pub struct EventStream {
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+    to_stdout: bool,
+}
+
+impl std::fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream")
+            .field("to_stdout", &self.to_stdout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EventStream {
+    pub fn stdout() -> Self {
+        Self {
+            out: Mutex::new(Some(Box::new(std::io::stdout()))),
+            to_stdout: true,
+        }
+    }
+
+    /// A stream writing to the already open file descriptor `fd`, e.g. a pipe set up by the
+    /// front-end. Fails if `fd` is not open, or is stderr, which takes the human output.
+    #[cfg(unix)]
+    pub fn from_fd(fd: i32) -> std::io::Result<Self> {
+        use std::os::fd::FromRawFd;
+        if fd == 1 {
+            return Ok(Self::stdout());
+        }
+        if fd == 2 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "stderr takes the human output",
+            ));
+        }
+        // SAFETY: Only queries the flags of the file descriptor, failing if it is not open.
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: The file descriptor is open, handed over by whoever started this process, and
+        // used by nothing else.
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        Ok(Self {
+            out: Mutex::new(Some(Box::new(file))),
+            to_stdout: false,
+        })
+    }
+
+    /// A stream that emits nothing.
+    pub fn disabled() -> Self {
+        Self {
+            out: Mutex::new(None),
+            to_stdout: false,
+        }
+    }
+
+    /// Whether the events take stdout, in which case the human output belongs on stderr.
+    pub fn is_stdout(&self) -> bool {
+        self.to_stdout
+    }
+
+    pub fn scan_started(&self, dir: &Path) {
+        self.emit(&Event::ScanStarted { dir });
+    }
+
+    /// The directory `index` of the `total` ones to be moved, right before it is.
+    pub fn dir_found(&self, dir: &Path, index: usize, total: usize) {
+        self.emit(&Event::DirFound { dir, index, total });
+    }
+
+    /// The outcome of an operation, as soon as it is performed.
+    pub fn outcome(&self, outcome: &OperationOutcome) {
+        let event = match outcome {
+            OperationOutcome::Done {
+                operation,
+                src,
+                dst,
+            }
+            | OperationOutcome::Planned {
+                operation,
+                src,
+                dst,
+            } => {
+                let planned = matches!(outcome, OperationOutcome::Planned { .. });
+                match operation {
+                    Operation::EraseDuplicate => Event::DedupRemoved { src, dst, planned },
+                    Operation::Erase => Event::UselessRemoved { src, dst, planned },
+                    operation => Event::MoveDone {
+                        operation: *operation,
+                        src,
+                        dst,
+                        planned,
+                    },
+                }
+            }
+            OperationOutcome::Skipped {
+                operation: operation @ (Operation::Erase | Operation::EraseDuplicate),
+                src,
+            } => Event::RemoveFailed {
+                operation: *operation,
+                src,
+                error: "Not confirmed".to_owned(),
+            },
+            OperationOutcome::Skipped { operation, src } => Event::MoveFailed {
+                operation: *operation,
+                src,
+                dst: &None,
+                error: "No destination could be found".to_owned(),
+            },
+            OperationOutcome::Failed {
+                operation: operation @ (Operation::Erase | Operation::EraseDuplicate),
+                src,
+                error,
+                ..
+            } => Event::RemoveFailed {
+                operation: *operation,
+                src,
+                error: error.to_string(),
+            },
+            OperationOutcome::Failed {
+                operation,
+                src,
+                dst,
+                error,
+            } => Event::MoveFailed {
+                operation: *operation,
+                src,
+                dst,
+                error: error.to_string(),
+            },
+        };
+        self.emit(&event);
+    }
+
+    pub fn summary(&self, counts: &Counts, dry_run: bool) {
+        self.emit(&Event::Summary { dry_run, counts });
+    }
+
+    fn emit(&self, event: &Event) {
+        let mut out = self.out.lock().unwrap();
+        let Some(writer) = &mut *out else {
+            return;
+        };
+        let written = serde_json::to_writer(&mut *writer, event)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writeln!(writer))
+            .and_then(|()| writer.flush());
+        if let Err(err) = written {
+            log::error!("Failed to write progress events: {}", err);
+            *out = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_fds() {
+        assert!(EventStream::from_fd(2).is_err());
+        assert!(EventStream::from_fd(9999).is_err());
+        assert!(EventStream::from_fd(-1).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{PortraitDir, Scan};
+use base64::Engine;
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+const STYLE: &str = "body{font-family:sans-serif;background:#222;color:#eee}\
+main{display:grid;grid-template-columns:repeat(auto-fill,minmax(160px,1fr));gap:1em}\
+figure{margin:0;text-align:center}\
+img{width:152px;height:175px;object-fit:contain}\
+figcaption{font-size:small;overflow-wrap:anywhere}";
+
+/// Writes to `output` an HTML page showing the "Medium.png" of every directory of `scan`,
+/// labeled with the name of the directory, returning how many are shown. The images are
+/// referenced relative to `output`, or embedded into the page if `embed`.
+pub(crate) fn write_gallery(
+    scan: &Scan<'_, PortraitDir>,
+    output: &Path,
+    embed: bool,
+) -> anyhow::Result<usize> {
+    let output_dir = output
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .canonicalize()?;
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>")?;
+    writeln!(
+        html,
+        "<html><head><meta charset=\"utf-8\"><title>Portraits</title><style>{}</style></head>",
+        STYLE
+    )?;
+    writeln!(html, "<body><main>")?;
+    let mut shown = 0;
+    for dir in scan.dirs() {
+        let Some(medium) = scan.scan_dir().find(dir, "Medium.png") else {
+            log::warn!("No Medium.png in {}", dir.display());
+            continue;
+        };
+        let src = if embed {
+            match std::fs::read(&medium) {
+                Ok(bytes) => format!(
+                    "data:{};base64,{}",
+                    media_type(&medium),
+                    base64::engine::general_purpose::STANDARD.encode(bytes)
+                ),
+                Err(err) => {
+                    log::error!("Failed to read {}: {}", medium.display(), err);
+                    continue;
+                }
+            }
+        } else {
+            let medium = medium.canonicalize().unwrap_or(medium);
+            url(&relative(&output_dir, &medium))
+        };
+        let name = dir.file_name().unwrap_or(dir.as_os_str()).to_string_lossy();
+        writeln!(
+            html,
+            "<figure><img src=\"{}\" alt=\"{}\" loading=\"lazy\"><figcaption>{}</figcaption></figure>",
+            escape(&src),
+            escape(&name),
+            escape(&name)
+        )?;
+        shown += 1;
+    }
+    writeln!(html, "</main></body></html>")?;
+    std::fs::write(output, html)?;
+    Ok(shown)
+}
+
+fn media_type(image: &Path) -> &'static str {
+    let extension = image.extension().unwrap_or_default().to_ascii_lowercase();
+    match extension.to_str() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        _ => "image/png",
+    }
+}
+
+/// `path` relative to `dir`, both being absolute.
+fn relative(dir: &Path, path: &Path) -> PathBuf {
+    let dir: Vec<Component> = dir.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = dir
+        .iter()
+        .zip(&path)
+        .take_while(|(dir, path)| dir == path)
+        .count();
+    if common == 0 {
+        return path.iter().collect();
+    }
+    std::iter::repeat_n(Component::ParentDir, dir.len() - common)
+        .chain(path[common..].iter().copied())
+        .collect()
+}
+
+/// `path` as a URL, percent-encoding everything but the unreserved characters.
+fn url(path: &Path) -> String {
+    let mut url = String::new();
+    for (i, component) in path.components().enumerate() {
+        match component {
+            Component::RootDir => {
+                url.push_str(if url.is_empty() { "file:///" } else { "/" });
+                continue;
+            }
+            Component::Prefix(prefix) => {
+                url.push_str("file:///");
+                url.push_str(&prefix.as_os_str().to_string_lossy());
+                continue;
+            }
+            _ if i > 0 && !url.ends_with('/') => url.push('/'),
+            _ => {}
+        }
+        for byte in component.as_os_str().as_encoded_bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    url.push(*byte as char)
+                }
+                _ => write!(url, "%{:02X}", byte).unwrap(),
+            }
+        }
+    }
+    url
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,785 @@
+//! Ordering of custom portraits for Owlcat's Pathfinder games: finding portrait directories
+//! in a downloads directory structure, removing duplicates and moving them into the game's
+//! "Portraits" directory.
+
+mod archive;
+mod checksum;
+mod config;
+mod encoded_path;
+mod events;
+mod gallery;
+mod library;
+mod log_file;
+mod manifest;
+mod moving;
+mod outcome;
+mod plan;
+mod png;
+mod progress;
+mod report;
+mod review;
+mod scan;
+mod template;
+mod undo;
+
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub use archive::ArchivedPortrait;
+pub use checksum::{Checksum, ChecksumCache, PerceptualHash};
+pub use config::{
+    Bucket, CleanMove, Config, DedupOn, HashAlgorithm, Keep, ProgressFormat, SortOrder,
+};
+pub use events::EventStream;
+pub use library::{LibraryCheck, LibraryComparison};
+pub use log_file::LogFile;
+pub use manifest::Verification;
+pub use moving::{Move, MoveOptions, NameSources};
+pub use outcome::{Operation, OperationOutcome};
+pub use report::{Counts, Failed, Moved, Report};
+pub use scan::{
+    EraseOptions, Extras, GeneratableDir, NonPortraitDir, PartialPortraitDir, PortraitDir, Scan,
+    ScanDir, ScanOptions,
+};
+pub use template::NameTemplate;
+pub use undo::{undo, Undo, UndoEntry, UndoLog};
+
+const PORTRAIT_FILES: [&str; 3] = ["Small.png", "Medium.png", "Fulllength.png"];
+const EXPECTED_DIMENSIONS: [(u32, u32); 3] = [(64, 76), (152, 175), (692, 1024)];
+
+/// The portrait directories found in the downloads directory, ready to be moved, along with
+/// the counts of what happened while looking for them.
+pub struct Preparation<'a> {
+    pub scan: Scan<'a, PortraitDir>,
+    pub duplicates: Vec<OperationOutcome>,
+    pub skipped_invalid: usize,
+    pub wrong_dimensions: usize,
+    pub generated: usize,
+    pub skipped_hidden: usize,
+    pub excluded: Vec<PathBuf>,
+    pub skipped_installed: usize,
+    pub skipped_over_limit: usize,
+    pub stripped_extras: usize,
+    pub skipped_placeholders: usize,
+    /// The bytes freed by deleting the duplicates.
+    pub reclaimed: u64,
+    /// The directories whose images seem to come from different artwork.
+    pub inconsistent: usize,
+    /// The directories skipped, or erased, for being in the blocklist.
+    pub blocklisted: usize,
+    /// The directories skipped for not being in the include list.
+    pub not_included: usize,
+    /// The directories holding some but not all of the required files.
+    pub incomplete: usize,
+    /// The directories skipped for having been modified too recently.
+    pub recent: usize,
+    /// The directories skipped for the shape of their full-length portrait.
+    pub wrong_aspect: usize,
+    /// The directories holding files named alike but for their case.
+    pub casing_conflicts: usize,
+    /// The files renamed to the exact name of a required file.
+    pub fixed_casing: usize,
+    /// The directories left for a later run by the byte limit.
+    pub deferred: usize,
+    /// The directories that could not be scanned for lack of permissions.
+    pub permission_denied: usize,
+}
+
+/// An inventory of the downloads and Portraits directories.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// The portrait directories in the downloads directory.
+    pub portraits: usize,
+    pub portraits_bytes: u64,
+    /// The portrait directories in the downloads directory that duplicate another.
+    pub duplicates: usize,
+    pub duplicates_bytes: u64,
+    /// The directories of the Portraits directory that are not portraits.
+    pub useless: usize,
+    pub useless_bytes: u64,
+}
+
+/// Scans the downloads directory, generating missing files, validating and removing
+/// duplicates as configured.
+pub fn prepare<'a>(config: &'a Config, events: &EventStream) -> Preparation<'a> {
+    let scan_options = ScanOptions {
+        scan_archives: config.scan_archives,
+        ..config.scan_options()
+    };
+    let generated = if config.generate_missing {
+        Scan::new(
+            &config.downloads_dir,
+            GeneratableDir(config.portrait_dir()),
+            &scan_options,
+        )
+        .generate_missing(config.dry_run)
+    } else {
+        Vec::new()
+    };
+    let incomplete = if config.report_incomplete {
+        let incomplete = Scan::new(
+            &config.downloads_dir,
+            PartialPortraitDir(config.portrait_dir()),
+            &config.scan_options(),
+        );
+        incomplete.report();
+        incomplete.dirs().len()
+    } else {
+        0
+    };
+    let mut undo_log = UndoLog::from_config(&config.undo_log, config.dry_run);
+    let erase_options = config.erase_options(events);
+    // The placeholders are skipped below rather than left out, to count them.
+    let mut scan = Scan::new(
+        &config.downloads_dir,
+        PortraitDir {
+            min_image_size: None,
+            ..config.portrait_dir()
+        },
+        &scan_options,
+    );
+    // A dry run generates nothing, but plans to move them as the files would be there.
+    if config.dry_run {
+        scan.add(&generated);
+    }
+    let skipped_hidden = scan.skipped_hidden();
+    let permission_denied = scan.permission_denied();
+    let excluded = scan.excluded().to_vec();
+    // Otherwise the portraits installed by an earlier run would be moved again.
+    if contains(&config.downloads_dir, &config.portraits_dir) {
+        scan.skip_in_place(&config.portraits_dir, config.bucket, config.preserve_tree);
+    }
+    // Before anything reads the files, which may be incomplete.
+    let recent = match config.min_age {
+        Some(min_age) => scan.skip_recent(min_age),
+        None => Vec::new(),
+    };
+    let (casing_conflicts, fixed_casing) = scan.check_casing(config.fix_casing, config.dry_run);
+    let skipped_placeholders = match config.min_image_size {
+        Some(min_image_size) => scan.skip_placeholders(min_image_size),
+        None => 0,
+    };
+    let skipped_invalid = if config.validate_png {
+        scan.skip_invalid_png()
+    } else {
+        0
+    };
+    let wrong_dimensions = if config.check_dimensions {
+        scan.check_dimensions(config.strict_dimensions)
+    } else {
+        0
+    };
+    let wrong_aspect = if config.aspect_min.is_some() || config.aspect_max.is_some() {
+        scan.skip_wrong_aspect(config.aspect_min, config.aspect_max)
+    } else {
+        0
+    };
+    let inconsistent = if config.check_consistency {
+        scan.check_consistency(config.strict)
+    } else {
+        0
+    };
+    let mut cache =
+        if config.remove_duplicate_dirs || config.skip_installed || !config.blocklist.is_empty() {
+            ChecksumCache::load(&config.cache_file, config.hash_buffer_size)
+        } else {
+            ChecksumCache::new(config.hash_buffer_size)
+        };
+    cache.decode_images = config.dedupe_across_formats;
+    let (mut duplicates, mut reclaimed) = if config.remove_duplicate_dirs {
+        let duplicates = scan.duplicates(
+            config.hash_algorithm,
+            config.dedup_on,
+            config.keep,
+            config.prefer_richer.as_deref(),
+            config.progress,
+            &mut cache,
+        );
+        scan.erase_duplicates(&duplicates, &erase_options, &mut undo_log)
+    } else {
+        (Vec::new(), 0)
+    };
+    let blocklisted = if config.blocklist.is_empty() {
+        Vec::new()
+    } else {
+        // The blocklist holds the checksums of the files as they are.
+        cache.decode_images = false;
+        let blocklisted = scan.skip_blocklisted(
+            &config.blocklist,
+            config.hash_algorithm,
+            config.dedup_on,
+            config.progress,
+            &mut cache,
+        );
+        cache.decode_images = config.dedupe_across_formats;
+        blocklisted
+    };
+    if config.remove_duplicate_dirs {
+        let (outcomes, bytes) = scan::erase_measured(&blocklisted, &erase_options, &mut undo_log);
+        duplicates.extend(outcomes);
+        reclaimed += bytes;
+    }
+    if config.perceptual_dedup {
+        duplicates.extend(scan.erase_perceptual_duplicates(
+            &erase_options,
+            config.perceptual_threshold,
+            &mut undo_log,
+        ));
+    }
+    let installed = (!target_pending(config)
+        && (config.skip_installed || config.max_portraits.is_some()))
+    .then(|| {
+        Scan::new(
+            &config.portraits_dir,
+            config.portrait_dir(),
+            &config.portraits_scan_options(),
+        )
+    });
+    let installed_dirs = match &installed {
+        Some(installed) if config.skip_installed => scan.skip_installed(
+            installed,
+            config.hash_algorithm,
+            config.dedup_on,
+            config.progress,
+            &mut cache,
+        ),
+        _ => Vec::new(),
+    };
+    // The downloaded copies of installed portraits are duplicates too.
+    if config.remove_duplicate_dirs {
+        let (outcomes, bytes) =
+            scan::erase_measured(&installed_dirs, &erase_options, &mut undo_log);
+        duplicates.extend(outcomes);
+        reclaimed += bytes;
+    }
+    let skipped_installed = installed_dirs.len();
+    save_cache(&cache, &config.cache_file, config.dry_run);
+    let not_included = match &config.include_list {
+        Some(include_list) => {
+            let not_included = scan.keep_listed(include_list);
+            log::info!(
+                "{} dirs match the include list, {} do not",
+                scan.dirs().len(),
+                not_included
+            );
+            not_included
+        }
+        None => 0,
+    };
+    scan.sort(config.sort);
+    let skipped_over_limit = match (config.max_portraits, &installed) {
+        (Some(max_portraits), Some(installed)) => {
+            scan.limit(max_portraits.saturating_sub(installed.dirs().len()))
+        }
+        (Some(max_portraits), None) => scan.limit(max_portraits),
+        _ => 0,
+    };
+    let deferred = match config.limit_bytes {
+        Some(limit_bytes) => {
+            let (deferred, kept_bytes) = scan.limit_bytes(limit_bytes);
+            log::info!(
+                "Moving {} bytes of portraits in {} dirs, deferring {} dirs",
+                kept_bytes,
+                scan.dirs().len(),
+                deferred
+            );
+            deferred
+        }
+        None => 0,
+    };
+    let stripped_extras = if config.strip_extras {
+        scan.strip_extras(config.dry_run)
+    } else {
+        0
+    };
+    Preparation {
+        scan,
+        duplicates,
+        skipped_invalid,
+        wrong_dimensions,
+        generated: generated.len(),
+        skipped_hidden,
+        excluded,
+        skipped_installed,
+        skipped_over_limit,
+        stripped_extras,
+        skipped_placeholders,
+        reclaimed,
+        inconsistent,
+        blocklisted: blocklisted.len(),
+        not_included,
+        incomplete,
+        recent: recent.len(),
+        wrong_aspect,
+        casing_conflicts,
+        fixed_casing,
+        deferred,
+        permission_denied,
+    }
+}
+
+/// Moves (or copies) the scanned portrait directories into the Portraits directory,
+/// returning the outcome for each of them, none with `no_move`.
+pub fn run(
+    config: &Config,
+    scan: Scan<'_, PortraitDir>,
+    events: &EventStream,
+) -> anyhow::Result<Vec<OperationOutcome>> {
+    if config.no_move {
+        return Ok(Vec::new());
+    }
+    let move_options = MoveOptions {
+        prefix: &config.prefix,
+        keep_original_path: config.keep_original_path,
+        name_template: config.name_template.as_ref(),
+        separator: &config.separator,
+        attempt_padding: config.attempt_padding,
+        max_attempts: config.max_attempts,
+        preserve_tree: config.preserve_tree,
+        strip_prefix_repeat: config.strip_prefix_repeat,
+        bucket: config.bucket,
+        collapse_redundant: config.collapse_redundant,
+        sanitize_replacement: &config.sanitize_replacement,
+        windows_names: config.windows_names,
+        max_name_length: config.max_name_length,
+        force_collisions: config.force_collisions,
+        use_descriptor: config.use_descriptor,
+        name_from_metadata: config.name_from_metadata,
+        prefix_from_source: config.prefix_from_source,
+        slot_map: &config.slot_map,
+        target_pending: target_pending(config),
+    };
+    let archived: Vec<ArchivedPortrait> = scan
+        .archives()
+        .iter()
+        .flat_map(|archive| {
+            archive::find_portraits(archive, scan.scan_dir()).unwrap_or_else(|err| {
+                log::warn!("Failed to read archive {}: {}", archive.display(), err);
+                Vec::new()
+            })
+        })
+        .collect();
+    // The archived portraits are named as if they were extracted in place.
+    let dirs: Vec<PathBuf> = scan
+        .dirs()
+        .iter()
+        .cloned()
+        .chain(
+            archived
+                .iter()
+                .map(|portrait| portrait.archive.with_extension("").join(&portrait.dir)),
+        )
+        .collect();
+    let mv = Move::with_dirs(scan.root(), &dirs, &config.portraits_dir, &move_options)?;
+    let approved = if config.review {
+        let moves: Vec<_> = mv.iter().collect();
+        let approved = review::review(&moves, scan.scan_dir())?;
+        let rejected = approved.iter().filter(|approved| !**approved).count();
+        if rejected > 0 {
+            log::info!("Not moving the {} directories rejected in review", rejected);
+        }
+        Some(approved)
+    } else {
+        None
+    };
+    let is_approved =
+        |(index, _): &(usize, _)| approved.as_ref().is_none_or(|approved| approved[*index]);
+    if config.use_descriptor || config.name_from_metadata {
+        let NameSources {
+            folders,
+            descriptors,
+            metadata,
+        } = mv.name_sources();
+        log::info!(
+            "{} names came from descriptors, {} from metadata and {} from folder names",
+            descriptors,
+            metadata,
+            folders
+        );
+    }
+    // The destinations do not collide, so the directories can be moved in any order.
+    let undo_log = Mutex::new(UndoLog::from_config(&config.undo_log, config.dry_run));
+    let bar = progress::bar(config.progress, dirs.len(), "Moving");
+    let install = |(index, (src, dst)): (usize, (&Path, Option<&Path>))| {
+        events.dir_found(src, index, dirs.len());
+        let archived = index
+            .checked_sub(scan.dirs().len())
+            .map(|index| &archived[index]);
+        let archived_path = archived.map(ArchivedPortrait::path);
+        let src = archived_path.as_deref().unwrap_or(src);
+        let operation = match archived {
+            Some(_) => Operation::Extract,
+            None if config.convert_to_png && moving::needs_conversion(src, scan.scan_dir()) => {
+                Operation::Convert
+            }
+            None if config.copy => Operation::Copy,
+            None if config.clean_move.is_some() => Operation::CleanMove,
+            None => Operation::Move,
+        };
+        let outcome = if let Some(dst) = dst {
+            let result = if config.dry_run {
+                None
+            } else {
+                undo_log.lock().unwrap().record(operation, src, Some(dst));
+                let result = match dst.parent() {
+                    Some(parent) if config.preserve_tree || config.bucket != Bucket::None => {
+                        std::fs::create_dir_all(parent)
+                    }
+                    _ => Ok(()),
+                };
+                Some(result.and_then(|()| match archived {
+                    Some(archived) => archived.extract(dst),
+                    None if operation == Operation::Convert => {
+                        moving::convert_portrait(src, dst, scan.scan_dir(), config.copy)
+                    }
+                    None if config.copy => {
+                        moving::retrying(config.retries, config.retry_delay, src, || {
+                            moving::copy_portrait(src, dst, scan.scan_dir(), config.preserve_times)
+                        })
+                    }
+                    None => moving::retrying(config.retries, config.retry_delay, src, || {
+                        match config.clean_move {
+                            Some(extras) => moving::clean_move_portrait(
+                                src,
+                                dst,
+                                scan.scan_dir(),
+                                extras,
+                                config.preserve_times,
+                            ),
+                            None => moving::move_portrait(src, dst, config.preserve_times),
+                        }
+                    }),
+                }))
+            };
+            let (src, dst) = (src.to_path_buf(), Some(dst.to_path_buf()));
+            match result {
+                Some(result) => OperationOutcome::new(operation, src, dst, result),
+                None => OperationOutcome::Planned {
+                    operation,
+                    src,
+                    dst,
+                },
+            }
+        } else {
+            OperationOutcome::Skipped {
+                operation,
+                src: src.to_path_buf(),
+            }
+        };
+        events.outcome(&outcome);
+        bar.inc(1);
+        outcome
+    };
+    let pool = config
+        .threads
+        .filter(|threads| *threads > 1)
+        .and_then(|threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .inspect_err(|_| log::warn!("Failed to start {} moving threads", threads))
+                .ok()
+        });
+    let outcomes: Vec<OperationOutcome> = match pool {
+        Some(pool) => {
+            let moves: Vec<_> = mv.iter().enumerate().filter(is_approved).collect();
+            pool.install(|| moves.into_par_iter().map(install).collect())
+        }
+        None => mv
+            .iter()
+            .enumerate()
+            .filter(is_approved)
+            .map(install)
+            .collect(),
+    };
+    bar.finish_and_clear();
+    if config.prune_empty && !config.dry_run {
+        let pruned: usize = outcomes
+            .iter()
+            .filter_map(|outcome| match outcome {
+                OperationOutcome::Done {
+                    operation: Operation::Move | Operation::CleanMove | Operation::Convert,
+                    src,
+                    ..
+                } if !src.exists() => Some(moving::prune_empty_parents(src, scan.root())),
+                _ => None,
+            })
+            .sum();
+        if pruned > 0 {
+            log::info!("Removed {} empty directories", pruned);
+        }
+    }
+    if let Some(path) = &config.plan {
+        let planned = plan::write_plan(path, &outcomes)?;
+        log::info!("Wrote {} moves to the plan {}", planned, path.display());
+    }
+    if let (Some(path), false) = (&config.manifest, config.dry_run) {
+        if let Err(err) = manifest::write_manifest(
+            path,
+            &config.portraits_dir,
+            &outcomes,
+            scan.scan_dir(),
+            config.hash_algorithm,
+            config.hash_buffer_size,
+        ) {
+            log::error!("Failed to write manifest {}: {}", path.display(), err);
+        }
+    }
+    Ok(outcomes)
+}
+
+/// Erases the non-portrait directories in the Portraits directory, if configured, returning
+/// the outcome for each of them.
+pub fn cleanup(config: &Config, events: &EventStream) -> Vec<OperationOutcome> {
+    if !config.remove_useless_dirs || target_pending(config) {
+        return Vec::new();
+    }
+    let mut undo_log = UndoLog::from_config(&config.undo_log, config.dry_run);
+    let erase_options = config.erase_options(events);
+    let scan_options = config.portraits_scan_options();
+    let mut scan = Scan::new(
+        &config.portraits_dir,
+        NonPortraitDir(config.portrait_dir()),
+        &scan_options,
+    );
+    // Buckets, the recreated tree and the downloads directory hold portraits, without being
+    // portraits themselves.
+    if config.preserve_tree
+        || config.bucket != Bucket::None
+        || contains(&config.portraits_dir, &config.downloads_dir)
+    {
+        let portraits = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+        scan.skip_ancestors_of(portraits.dirs());
+    }
+    if config.report_incomplete {
+        let incomplete = Scan::new(
+            &config.portraits_dir,
+            PartialPortraitDir(config.portrait_dir()),
+            &scan_options,
+        );
+        incomplete.report();
+        scan.skip(&incomplete.dirs().iter().cloned().collect());
+    }
+    scan.erase(&erase_options, &mut undo_log)
+}
+
+/// Erases the portrait directories in the Portraits directory that duplicate another, if
+/// configured, returning the outcome for each of them and the bytes freed.
+pub fn dedupe_portraits(config: &Config, events: &EventStream) -> (Vec<OperationOutcome>, u64) {
+    if !config.dedupe_portraits || target_pending(config) {
+        return (Vec::new(), 0);
+    }
+    let mut undo_log = UndoLog::from_config(&config.undo_log, config.dry_run);
+    let erase_options = config.erase_options(events);
+    let scan_options = config.portraits_scan_options();
+    let mut scan = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+    let mut cache = ChecksumCache::load(&config.cache_file, config.hash_buffer_size);
+    cache.decode_images = config.dedupe_across_formats;
+    let duplicates = scan.duplicates(
+        config.hash_algorithm,
+        config.dedup_on,
+        config.keep,
+        config.prefer_richer.as_deref(),
+        config.progress,
+        &mut cache,
+    );
+    save_cache(&cache, &config.cache_file, config.dry_run);
+    scan.erase_duplicates(&duplicates, &erase_options, &mut undo_log)
+}
+
+/// Whether the Portraits directory is yet to be created, which only a dry run with
+/// `create_target` gets to see, so that it holds nothing yet.
+fn target_pending(config: &Config) -> bool {
+    config.create_target && config.dry_run && !config.portraits_dir.exists()
+}
+
+/// Whether `path` is `dir` or below it, once both are canonicalized.
+fn contains(dir: &Path, path: &Path) -> bool {
+    match (dir.canonicalize(), path.canonicalize()) {
+        (Ok(dir), Ok(path)) => path.starts_with(dir),
+        _ => false,
+    }
+}
+
+fn save_cache(cache: &ChecksumCache, path: &Path, dry_run: bool) {
+    if cache.hits + cache.misses > 0 {
+        log::debug!(
+            "Checksum cache: {} hits, {} misses",
+            cache.hits,
+            cache.misses
+        );
+    }
+    if !dry_run {
+        cache.save(path);
+    }
+}
+
+/// Lists the sets of portrait directories of the downloads directory that share a checksum,
+/// if configured, without erasing any of them.
+pub fn list_duplicates(config: &Config) -> Option<Vec<Vec<PathBuf>>> {
+    if !config.list_duplicates {
+        return None;
+    }
+    let scan_options = config.scan_options();
+    let scan = Scan::new(&config.downloads_dir, config.portrait_dir(), &scan_options);
+    let mut cache = ChecksumCache::load(&config.cache_file, config.hash_buffer_size);
+    cache.decode_images = config.dedupe_across_formats;
+    let groups = scan.duplicate_groups(
+        config.hash_algorithm,
+        config.dedup_on,
+        config.progress,
+        &mut cache,
+    );
+    save_cache(&cache, &config.cache_file, config.dry_run);
+    Some(groups)
+}
+
+/// Lists the files that are not required in every portrait directory of the downloads
+/// directory, if configured.
+pub fn extras(config: &Config) -> Option<Vec<Extras>> {
+    if !config.report_extras {
+        return None;
+    }
+    let scan_options = config.scan_options();
+    let scan = Scan::new(&config.downloads_dir, config.portrait_dir(), &scan_options);
+    Some(scan.extras())
+}
+
+/// Counts the portrait directories of the downloads directory, their duplicates and the
+/// useless directories of the Portraits directory along with their sizes, if configured.
+/// Nothing is moved or erased.
+pub fn stats(config: &Config) -> Option<Stats> {
+    if !config.stats {
+        return None;
+    }
+    let scan = Scan::new(
+        &config.downloads_dir,
+        config.portrait_dir(),
+        &config.scan_options(),
+    );
+    let mut cache = ChecksumCache::load(&config.cache_file, config.hash_buffer_size);
+    cache.decode_images = config.dedupe_across_formats;
+    let duplicates = scan.duplicates(
+        config.hash_algorithm,
+        config.dedup_on,
+        config.keep,
+        config.prefer_richer.as_deref(),
+        config.progress,
+        &mut cache,
+    );
+    save_cache(&cache, &config.cache_file, config.dry_run);
+    let scan_options = config.portraits_scan_options();
+    let mut useless = Scan::new(
+        &config.portraits_dir,
+        NonPortraitDir(config.portrait_dir()),
+        &scan_options,
+    );
+    // Buckets and the recreated tree hold portraits, without being portraits themselves.
+    if config.preserve_tree || config.bucket != Bucket::None {
+        let portraits = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+        useless.skip_ancestors_of(portraits.dirs());
+    }
+    let size = |dirs: &[PathBuf]| dirs.iter().map(|dir| scan::dir_size(dir)).sum();
+    Some(Stats {
+        portraits: scan.dirs().len(),
+        portraits_bytes: size(scan.dirs()),
+        duplicates: duplicates.len(),
+        duplicates_bytes: size(&duplicates),
+        useless: useless.dirs().len(),
+        useless_bytes: size(useless.dirs()),
+    })
+}
+
+/// Writes an HTML page showing the portraits in the Portraits directory, if configured,
+/// returning how many are shown.
+pub fn gallery(config: &Config) -> anyhow::Result<Option<usize>> {
+    let Some(output) = &config.gallery else {
+        return Ok(None);
+    };
+    let scan_options = config.portraits_scan_options();
+    let scan = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+    gallery::write_gallery(&scan, output, config.embed).map(Some)
+}
+
+/// Writes the checksums of the portrait directories in the Portraits directory, if
+/// configured, returning how many were written.
+pub fn write_checksums(config: &Config) -> anyhow::Result<Option<usize>> {
+    let Some(path) = &config.write_checksums else {
+        return Ok(None);
+    };
+    let scan_options = config.portraits_scan_options();
+    let scan = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+    library::write_checksums(path, &scan, config.hash_algorithm, config.hash_buffer_size).map(Some)
+}
+
+/// Compares the portrait directories in the Portraits directory to a file written by
+/// `write_checksums`, if configured.
+pub fn check_checksums(config: &Config) -> anyhow::Result<Option<LibraryCheck>> {
+    let Some(path) = &config.check_checksums else {
+        return Ok(None);
+    };
+    let scan_options = config.portraits_scan_options();
+    let scan = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+    library::check_checksums(path, &scan, config.hash_algorithm, config.hash_buffer_size).map(Some)
+}
+
+/// Compares the portrait directories of the Portraits directory to those of another
+/// directory by checksum, if configured.
+pub fn compare(config: &Config) -> Option<LibraryComparison> {
+    let other_dir = config.compare.as_ref()?;
+    let scan_options = config.portraits_scan_options();
+    let here = Scan::new(&config.portraits_dir, config.portrait_dir(), &scan_options);
+    let there = Scan::new(other_dir, config.portrait_dir(), &scan_options);
+    Some(library::compare(
+        &here,
+        &there,
+        config.hash_algorithm,
+        config.hash_buffer_size,
+    ))
+}
+
+/// Performs the moves of a plan written with `--plan`, if configured, returning the outcome
+/// of each.
+pub fn apply(config: &Config) -> anyhow::Result<Option<Vec<OperationOutcome>>> {
+    let Some(path) = &config.apply else {
+        return Ok(None);
+    };
+    let mut undo_log = UndoLog::from_config(&config.undo_log, config.dry_run);
+    plan::apply_plan(
+        path,
+        &config.portrait_dir(),
+        config.clean_move.unwrap_or(CleanMove::Subfolder),
+        config.copy,
+        config.dry_run,
+        config.preserve_times,
+        &mut undo_log,
+    )
+    .map(Some)
+}
+
+/// Checks the directories of a manifest against the checksums it records, if configured.
+pub fn verify(config: &Config) -> anyhow::Result<Option<Verification>> {
+    let Some(path) = &config.verify else {
+        return Ok(None);
+    };
+    manifest::verify_manifest(
+        path,
+        &config.portraits_dir,
+        &config.portrait_dir(),
+        config.hash_buffer_size,
+    )
+    .map(Some)
+}
+
+/// Creates the Portraits directory if configured and it does not exist yet, returning
+/// whether it was created.
+pub fn create_target(config: &Config) -> std::io::Result<bool> {
+    if !config.create_target || config.portraits_dir.is_dir() {
+        return Ok(false);
+    }
+    if !config.dry_run {
+        std::fs::create_dir_all(&config.portraits_dir)?;
+    }
+    Ok(true)
+}
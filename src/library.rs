@@ -0,0 +1,132 @@
+use crate::{Checksum, HashAlgorithm, PortraitDir, Scan};
+use anyhow::anyhow;
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The differences between the portrait directories of the Portraits directory and a
+/// checksums file written by `write_checksums`.
+#[derive(Debug, Default)]
+pub struct LibraryCheck {
+    pub matched: usize,
+    /// The directories that are not in the checksums file.
+    pub added: Vec<PathBuf>,
+    /// The directories of the checksums file that are gone.
+    pub removed: Vec<PathBuf>,
+    /// The directories whose files changed.
+    pub mismatched: Vec<PathBuf>,
+}
+
+/// Writes the checksum of every directory of `scan` to `path`, sorted by directory, as
+/// md5sum-style "digest  directory" lines, the directories being relative to the scanned one
+/// and separated by "/". Returns how many are written.
+pub(crate) fn write_checksums(
+    path: &Path,
+    scan: &Scan<'_, PortraitDir>,
+    hash_algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> anyhow::Result<usize> {
+    let checksums = checksums(scan, hash_algorithm, buffer_size);
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for (name, checksum) in &checksums {
+        writeln!(file, "{}  {}", checksum, name)?;
+    }
+    file.flush()?;
+    Ok(checksums.len())
+}
+
+/// Compares the directories of `scan` to the checksums file at `path`.
+pub(crate) fn check_checksums(
+    path: &Path,
+    scan: &Scan<'_, PortraitDir>,
+    hash_algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> anyhow::Result<LibraryCheck> {
+    let mut expected = BTreeMap::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (checksum, name) = line
+            .split_once("  ")
+            .ok_or_else(|| anyhow!("Invalid checksums line in {}: {}", path.display(), line))?;
+        expected.insert(name.to_owned(), checksum.to_owned());
+    }
+    let mut check = LibraryCheck::default();
+    let mut actual = checksums(scan, hash_algorithm, buffer_size);
+    for (name, checksum) in expected {
+        match actual.remove(&name) {
+            Some(actual) if actual == checksum => check.matched += 1,
+            Some(_) => check.mismatched.push(scan.root.join(&name)),
+            None => check.removed.push(scan.root.join(&name)),
+        }
+    }
+    check
+        .added
+        .extend(actual.into_keys().map(|name| scan.root.join(name)));
+    Ok(check)
+}
+
+/// The portrait directories of two libraries, told apart by their checksums.
+#[derive(Debug, Default)]
+pub struct LibraryComparison {
+    /// The directories of the first library whose portrait is not in the second.
+    pub only_here: Vec<PathBuf>,
+    /// The directories of the second library whose portrait is not in the first.
+    pub only_there: Vec<PathBuf>,
+    /// The directories of the first library whose portrait is in the second too.
+    pub both: Vec<PathBuf>,
+}
+
+/// Compares the directories of `here` to those of `there` by checksum, whatever their names.
+pub(crate) fn compare(
+    here: &Scan<'_, PortraitDir>,
+    there: &Scan<'_, PortraitDir>,
+    hash_algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> LibraryComparison {
+    let here_checksums = checksums(here, hash_algorithm, buffer_size);
+    let there_checksums = checksums(there, hash_algorithm, buffer_size);
+    let here_set: HashSet<&String> = here_checksums.values().collect();
+    let there_set: HashSet<&String> = there_checksums.values().collect();
+    let mut comparison = LibraryComparison::default();
+    for (name, checksum) in &here_checksums {
+        let dir = here.root.join(name);
+        if there_set.contains(checksum) {
+            comparison.both.push(dir);
+        } else {
+            comparison.only_here.push(dir);
+        }
+    }
+    comparison.only_there = there_checksums
+        .iter()
+        .filter(|(_, checksum)| !here_set.contains(checksum))
+        .map(|(name, _)| there.root.join(name))
+        .collect();
+    comparison
+}
+
+/// The checksum of every directory of `scan` by its relative name.
+fn checksums(
+    scan: &Scan<'_, PortraitDir>,
+    hash_algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> BTreeMap<String, String> {
+    let mut checksums = BTreeMap::new();
+    for dir in scan.dirs() {
+        let Some(checksum) = Checksum::from_dir(dir, scan.scan_dir(), hash_algorithm, buffer_size)
+        else {
+            log::warn!("Failed to get checksum for {}", dir.display());
+            continue;
+        };
+        let name = dir
+            .strip_prefix(scan.root)
+            .unwrap_or(dir)
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        checksums.insert(name, checksum.to_string());
+    }
+    checksums
+}
@@ -0,0 +1,67 @@
+use crate::OperationOutcome;
+use std::io::Write;
+use std::path::Path;
+
+/// An append-only, human readable log of the operations of every run, one timestamped line
+/// per operation.
+pub struct LogFile {
+    file: Option<std::fs::File>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self { file: Some(file) })
+    }
+
+    /// A log that records nothing.
+    pub fn disabled() -> Self {
+        Self { file: None }
+    }
+
+    pub fn from_config(path: Option<&Path>) -> Self {
+        let Some(path) = path else {
+            return Self::disabled();
+        };
+        Self::open(path).unwrap_or_else(|err| {
+            log::error!("Failed to open log file {}: {}", path.display(), err);
+            Self::disabled()
+        })
+    }
+
+    pub fn write(&mut self, outcomes: &[OperationOutcome]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        for outcome in outcomes {
+            let (status, src, dst, error) = match outcome {
+                OperationOutcome::Done { src, dst, .. } => ("done", src, dst.as_ref(), None),
+                OperationOutcome::Planned { src, dst, .. } => ("planned", src, dst.as_ref(), None),
+                OperationOutcome::Skipped { src, .. } => ("skipped", src, None, None),
+                OperationOutcome::Failed {
+                    src, dst, error, ..
+                } => ("failed", src, dst.as_ref(), Some(error)),
+            };
+            let mut line = format!(
+                "{} {} {} {}",
+                humantime::format_rfc3339_seconds(std::time::SystemTime::now()),
+                outcome.operation(),
+                status,
+                src.display()
+            );
+            if let Some(dst) = dst {
+                line.push_str(&format!(" -> {}", dst.display()));
+            }
+            if let Some(error) = error {
+                line.push_str(&format!(": {}", error));
+            }
+            if let Err(err) = writeln!(file, "{}", line) {
+                log::error!("Failed to write to the log file: {}", err);
+                return;
+            }
+        }
+    }
+}
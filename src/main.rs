@@ -1,375 +1,604 @@
-use anyhow::anyhow;
 use std::collections::HashSet;
-use std::ffi::{OsStr, OsString};
-use std::io::Read;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc;
+use std::time::Duration;
+use wrathoftherighteousportraits::{
+    apply, check_checksums, cleanup, compare, create_target, dedupe_portraits, extras, gallery,
+    list_duplicates, prepare, run, stats, undo, verify, write_checksums, Config, EventStream,
+    Extras, LibraryCheck, LibraryComparison, LogFile, Operation, OperationOutcome, Preparation,
+    ProgressFormat, Report, Stats, Undo, Verification,
+};
 
 mod args;
+mod self_test;
 
-const MAX_ATTEMPTS_WHEN_NEED_TO_KEEP_ORIGINAL_FILENAME: u32 = 1000;
-const MAX_ATTEMPTS_WHEN_NO_NEED_TO_KEEP_ORIGINAL_FILENAME: u32 = 1000000;
+/// How long the downloads dir must be quiet before `watch` handles what changed.
+const WATCH_DEBOUNCE: Duration = Duration::from_secs(2);
 
-trait ScanDir {
-    fn include(&self, path: &Path) -> bool;
-}
-
-struct PortraitDir;
-
-impl ScanDir for PortraitDir {
-    fn include(&self, path: &Path) -> bool {
-        path.join("Small.png").exists()
-            && path.join("Medium.png").exists()
-            && path.join("Fulllength.png").exists()
+fn print(outcomes: &[OperationOutcome], planned_to_stderr: bool, ignore_permission_errors: bool) {
+    for outcome in outcomes {
+        match outcome {
+            OperationOutcome::Done { .. } => log::debug!("{}", outcome),
+            OperationOutcome::Planned { .. } if planned_to_stderr => log::info!("{}", outcome),
+            OperationOutcome::Planned { .. } => {
+                if log::log_enabled!(log::Level::Info) {
+                    println!("{}", outcome)
+                }
+            }
+            OperationOutcome::Skipped { .. } => log::warn!("{}", outcome),
+            OperationOutcome::Failed { .. }
+                if ignore_permission_errors && outcome.is_permission_denied() =>
+            {
+                log::debug!("{}", outcome)
+            }
+            OperationOutcome::Failed { .. } => log::error!("{}", outcome),
+        }
     }
 }
 
-struct NonPortraitDir;
-
-impl ScanDir for NonPortraitDir {
-    fn include(&self, path: &Path) -> bool {
-        !PortraitDir {}.include(path)
+fn run_undo(config: &Config, log: &Path) -> anyhow::Result<ExitCode> {
+    let Undo {
+        outcomes,
+        irreversible,
+    } = undo(log, config.dry_run)?;
+    print(&outcomes, false, config.ignore_permission_errors);
+    LogFile::from_config(config.log_file.as_deref()).write(&outcomes);
+    for entry in &irreversible {
+        match entry.operation {
+            Operation::Convert => log::warn!(
+                "Cannot undo converting {}, the originals are gone",
+                entry.src.display()
+            ),
+            _ => log::warn!("Cannot undo erasing {}", entry.src.display()),
+        }
     }
+    let undone = outcomes
+        .iter()
+        .filter(|outcome| outcome.is_success())
+        .count();
+    if config.dry_run {
+        println!("Dry run, nothing was changed.");
+    }
+    println!(
+        r#"Done!
+Undone                = {}
+Failed to undo        = {}
+Not reversible        = {}"#,
+        undone,
+        outcomes.len() - undone,
+        irreversible.len()
+    );
+    Ok(exit_code(
+        undone < outcomes.len() || (config.strict && !irreversible.is_empty()),
+    ))
 }
 
-#[derive(Eq, Hash, PartialEq)]
-struct Checksum {
-    small: md5::Digest,
-    medium: md5::Digest,
-    full: md5::Digest,
+fn report_applied(config: &Config, outcomes: &[OperationOutcome]) -> ExitCode {
+    print(outcomes, false, config.ignore_permission_errors);
+    LogFile::from_config(config.log_file.as_deref()).write(outcomes);
+    let applied = outcomes
+        .iter()
+        .filter(|outcome| outcome.is_success())
+        .count();
+    if config.dry_run {
+        println!("Dry run, nothing was changed.");
+    }
+    println!(
+        r#"Done!
+Successfully renamed  = {}
+Failed to rename      = {}"#,
+        applied,
+        outcomes.len() - applied
+    );
+    exit_code(applied < outcomes.len())
 }
 
-impl Checksum {
-    pub fn from_dir(dir: &Path) -> Option<Self> {
-        let small = Self::check_file(&dir.join("Small.png"))?;
-        let medium = Self::check_file(&dir.join("Medium.png"))?;
-        let full = Self::check_file(&dir.join("Fulllength.png"))?;
-        Some(Self {
-            small,
-            medium,
-            full,
-        })
+fn report_verification(verification: &Verification) -> anyhow::Result<ExitCode> {
+    let Verification {
+        verified,
+        mismatched,
+        missing,
+        unchecked,
+    } = verification;
+    for dir in mismatched {
+        log::error!("{} no longer matches its source", dir.display());
     }
-
-    fn check_file(file: &Path) -> Option<md5::Digest> {
-        let mut file = std::fs::File::open(file).ok()?;
-        let mut buffer = Vec::new();
-        let _ = file.read_to_end(&mut buffer).ok()?;
-        Some(md5::compute(&buffer))
+    for dir in missing {
+        log::error!("{} is missing", dir.display());
     }
+    for dir in unchecked {
+        log::warn!("No checksum recorded for {}", dir.display());
+    }
+    println!(
+        r#"Done!
+Verified              = {}
+Mismatched            = {}
+Missing               = {}
+Unchecked             = {}"#,
+        verified,
+        mismatched.len(),
+        missing.len(),
+        unchecked.len()
+    );
+    Ok(exit_code(!mismatched.is_empty() || !missing.is_empty()))
 }
 
-#[derive(Debug)]
-struct Scan<'a, T>
-where
-    T: ScanDir,
-{
-    root: &'a Path,
-    dirs: Vec<PathBuf>,
-    scan_dir: T,
-}
-
-impl<'a, T> Scan<'a, T>
-where
-    T: ScanDir,
-{
-    pub fn new(root: &'a Path, scan_dir: T) -> Self {
-        let dirs = Vec::new();
-        let mut scan = Self {
-            root,
-            dirs,
-            scan_dir,
-        };
-        scan.scan_dir(root);
-        scan
+fn report_library_check(check: &LibraryCheck) -> ExitCode {
+    let LibraryCheck {
+        matched,
+        added,
+        removed,
+        mismatched,
+    } = check;
+    for dir in added {
+        log::warn!("{} was added", dir.display());
+    }
+    for dir in removed {
+        log::error!("{} was removed", dir.display());
     }
+    for dir in mismatched {
+        log::error!("{} changed", dir.display());
+    }
+    println!(
+        r#"Done!
+Matched               = {}
+Added                 = {}
+Removed               = {}
+Mismatched            = {}"#,
+        matched,
+        added.len(),
+        removed.len(),
+        mismatched.len()
+    );
+    exit_code(!added.is_empty() || !removed.is_empty() || !mismatched.is_empty())
+}
 
-    fn scan_dir(&mut self, dir: &Path) {
-        let mut dirs_to_scan = Vec::new();
-        let contents = match std::fs::read_dir(dir) {
-            Ok(contents) => contents,
-            Err(_) => {
-                eprintln!("Failed to scan the contents of {}", dir.display());
-                return;
+fn report_comparison(config: &Config, comparison: &LibraryComparison) {
+    let LibraryComparison {
+        only_here,
+        only_there,
+        both,
+    } = comparison;
+    let other_dir = config.compare.as_deref().unwrap_or(Path::new(""));
+    for (dirs, heading) in [
+        (
+            only_here,
+            format!("Only in {}:", config.portraits_dir.display()),
+        ),
+        (only_there, format!("Only in {}:", other_dir.display())),
+        (both, "In both:".to_owned()),
+    ] {
+        if !dirs.is_empty() {
+            println!("{}", heading);
+            for dir in dirs {
+                println!("  {}", dir.display());
             }
-        };
-        for dir in contents
-            .filter_map(Result::ok)
-            .filter(|entry| entry.path().is_dir())
-        {
-            let path = dir.path();
-            if self.scan_dir.include(&path) {
-                self.dirs.push(path.clone());
-            }
-            dirs_to_scan.push(path);
-        }
-        for dir in dirs_to_scan {
-            self.scan_dir(&dir)
         }
     }
+    println!(
+        r#"Done!
+Only in Portraits     = {}
+Only in other         = {}
+In both               = {}"#,
+        only_here.len(),
+        only_there.len(),
+        both.len()
+    );
 }
 
-impl Scan<'_, NonPortraitDir> {
-    pub fn erase(self) -> usize {
-        let mut erased = 0;
-        for dir in &self.dirs {
-            if std::fs::remove_dir_all(dir).is_err() {
-                eprintln!("Failed to erase {}", dir.display());
-            } else {
-                erased += 1;
-            }
+/// The stream of events requested by `config`.
+fn event_stream(config: &Config) -> anyhow::Result<EventStream> {
+    match (config.progress_format, config.progress_fd) {
+        (ProgressFormat::Human, _) => Ok(EventStream::disabled()),
+        (ProgressFormat::Ndjson, None) => Ok(EventStream::stdout()),
+        #[cfg(unix)]
+        (ProgressFormat::Ndjson, Some(fd)) => EventStream::from_fd(fd)
+            .map_err(|err| anyhow::anyhow!("Invalid --progress-fd {}: {}", fd, err)),
+        #[cfg(not(unix))]
+        (ProgressFormat::Ndjson, Some(_)) => {
+            Err(anyhow::anyhow!("--progress-fd is only supported on Unix"))
         }
-        erased
     }
 }
 
-impl Scan<'_, PortraitDir> {
-    pub fn erase_duplicates(&mut self) -> usize {
-        let mut checksums: HashSet<Checksum> = HashSet::new();
-        let mut erased = 0;
-        self.dirs.retain(|dir| {
-            let dir = dir.as_path();
-            let checksum = match Checksum::from_dir(dir) {
-                Some(checksum) => checksum,
-                None => {
-                    eprintln!("Failed to get checksum for {}", dir.display());
-                    return true;
-                }
-            };
-            if checksums.contains(&checksum) {
-                if std::fs::remove_dir_all(dir).is_err() {
-                    eprintln!("Failed to erase duplicate {}", dir.display());
-                } else {
-                    erased += 1;
-                }
-                false
+/// Installs the portrait dirs that appear in the downloads dir, until interrupted. The dirs
+/// in `handled` are neither moved again nor reported again.
+fn watch(
+    config: &Config,
+    mut handled: HashSet<PathBuf>,
+    events: &EventStream,
+) -> anyhow::Result<()> {
+    use notify::Watcher;
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(&config.downloads_dir, notify::RecursiveMode::Recursive)?;
+    log::info!(
+        "Watching {} for new portraits, press Ctrl+C to stop",
+        config.downloads_dir.display()
+    );
+    let mut log_file = LogFile::from_config(config.log_file.as_deref());
+    loop {
+        let event = receiver.recv()??;
+        if !(event.kind.is_create() || event.kind.is_modify()) {
+            continue;
+        }
+        // Wait for e.g. an unpacked archive to be complete.
+        while receiver.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+        let Preparation {
+            mut scan,
+            duplicates,
+            ..
+        } = prepare(config, events);
+        scan.skip(&handled);
+        print(
+            &duplicates,
+            events.is_stdout(),
+            config.ignore_permission_errors,
+        );
+        log_file.write(&duplicates);
+        if scan.dirs().is_empty() {
+            continue;
+        }
+        let moves = run(config, scan, events).unwrap_or_else(|err| {
+            log::error!("{}", err);
+            Vec::new()
+        });
+        for outcome in &moves {
+            if let OperationOutcome::Done { src, .. } | OperationOutcome::Planned { src, .. } =
+                outcome
+            {
+                handled.insert(src.clone());
+            }
+            if let OperationOutcome::Done { .. } = outcome {
+                log::info!("{}", outcome);
             } else {
-                checksums.insert(checksum);
-                true
+                print(
+                    std::slice::from_ref(outcome),
+                    events.is_stdout(),
+                    config.ignore_permission_errors,
+                );
             }
-        });
-        erased
+        }
+        log_file.write(&moves);
     }
 }
 
-struct OriginalFileName<'a> {
-    dir_components: Vec<&'a OsStr>,
-    file_name: OsString,
+fn report_stats(stats: &Stats) {
+    let Stats {
+        portraits,
+        portraits_bytes,
+        duplicates,
+        duplicates_bytes,
+        useless,
+        useless_bytes,
+    } = stats;
+    println!(
+        r#"Done!
+Portrait dirs         = {} ({})
+Duplicate dirs        = {} ({})
+Useless dirs          = {} ({})"#,
+        portraits,
+        human_bytes(*portraits_bytes),
+        duplicates,
+        human_bytes(*duplicates_bytes),
+        useless,
+        human_bytes(*useless_bytes)
+    );
 }
 
-impl<'a> OriginalFileName<'a> {
-    pub fn new(dir_prefix: &str, scan_skip_components: usize, dir: &'a Path) -> Option<Self> {
-        let mut dir_components: Vec<&OsStr> = dir
-            .components()
-            .skip(scan_skip_components)
-            .map(std::path::Component::as_os_str)
-            .collect();
-        let file_name = dir_components.pop()?;
-        let file_name = Self::stripped(file_name, dir_prefix);
-        Some(Self {
-            dir_components,
-            file_name,
-        })
-    }
-
-    fn stripped(file_name: &OsStr, dir_prefix: &str) -> OsString {
-        let base_bytes: &[u8] = Path::new(dir_prefix).as_os_str().as_encoded_bytes();
-        let mut bytes: &[u8] = file_name.as_encoded_bytes();
-        while bytes.len() >= base_bytes.len() && &bytes[..base_bytes.len()] == base_bytes {
-            bytes = &bytes[base_bytes.len()..];
-        }
-        unsafe { OsString::from_encoded_bytes_unchecked(bytes.to_vec()) }
+/// `bytes` in the largest binary unit in which it is at least 1, e.g. "1.5 MiB".
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
     }
-
-    pub fn as_ref(&'a self) -> OriginalFileNameRef<'a> {
-        let Self {
-            dir_components,
-            file_name,
-        } = self;
-        let dir_components = &dir_components[..];
-        let file_name = file_name.as_os_str();
-        OriginalFileNameRef {
-            dir_components,
-            file_name,
-        }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
     }
+    format!("{:.1} {}", size, UNITS[unit])
 }
 
-#[derive(Clone, Copy)]
-struct OriginalFileNameRef<'a> {
-    dir_components: &'a [&'a OsStr],
-    file_name: &'a OsStr,
+/// Whether the run should exit with a failure status: if it or a move failed, or with --strict,
+/// if anything was skipped or could not be handled.
+fn failed(config: &Config, report: &Report, run_failed: bool) -> bool {
+    run_failed
+        || report.counts.failed_to_rename > 0
+        || (config.strict && (!report.failed.is_empty() || report.counts.skipped_total() > 0))
 }
 
-struct Move<'a: 'b, 'b> {
-    scan: &'a Scan<'b, PortraitDir>,
-    output: Vec<Option<PathBuf>>,
+fn exit_code(failed: bool) -> ExitCode {
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
 
-impl<'a: 'b, 'b> Move<'a, 'b> {
-    pub fn new(
-        scan: &'a Scan<'b, PortraitDir>,
-        target: &Path,
-        dir_prefix: &str,
-        keep_original_path: bool,
-    ) -> anyhow::Result<Self> {
-        if !target.is_dir() {
-            return Err(anyhow!("{} is not a directory", target.display()));
-        }
-        let mut output: Vec<Option<PathBuf>> = Vec::new();
-        let mut output_set: HashSet<PathBuf> = HashSet::new();
-
-        let scan_skip_components = scan.root.components().count();
-        for dir in &scan.dirs {
-            let (original_filename, max_attempts) = {
-                if keep_original_path {
-                    let original_filename =
-                        OriginalFileName::new(dir_prefix, scan_skip_components, dir);
-                    if original_filename.is_none() {
-                        output.push(None);
-                        continue;
-                    }
-                    (
-                        original_filename,
-                        MAX_ATTEMPTS_WHEN_NEED_TO_KEEP_ORIGINAL_FILENAME,
-                    )
-                } else {
-                    (None, MAX_ATTEMPTS_WHEN_NO_NEED_TO_KEEP_ORIGINAL_FILENAME)
-                }
-            };
-            let original_filename = original_filename.as_ref().map(OriginalFileName::as_ref);
-            let mut attempt: u32 = 0;
-            let mut rename = Self::rename(target, dir_prefix, attempt, original_filename);
-            output.push(loop {
-                let r = &rename;
-                if !(output_set.contains(r) || r.exists()) {
-                    output_set.insert(rename.clone());
-                    break Some(rename);
-                }
-                attempt += 1;
-                if attempt >= max_attempts {
-                    break None;
-                }
-                rename = Self::rename(target, dir_prefix, attempt, original_filename);
-            });
+fn main() -> anyhow::Result<ExitCode> {
+    let config = args::fetch()?;
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_default_env()
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+    if config.self_test {
+        return self_test::run_self_test();
+    }
+    if let Some(log) = &config.undo {
+        return run_undo(&config, log);
+    }
+    if let Some(outcomes) = apply(&config)? {
+        return Ok(report_applied(&config, &outcomes));
+    }
+    if create_target(&config)? {
+        if config.dry_run {
+            log::info!("Would create {}", config.portraits_dir.display());
+        } else {
+            log::info!("Created {}", config.portraits_dir.display());
         }
-        Ok(Self { scan, output })
+    } else if config.create_target {
+        log::info!("{} already exists", config.portraits_dir.display());
     }
-
-    fn rename(
-        target: &Path,
-        dir_prefix: &str,
-        attempt: u32,
-        original_filename: Option<OriginalFileNameRef<'_>>,
-    ) -> PathBuf {
-        let mut new_filename = OsString::new();
-        new_filename.push(dir_prefix);
-        if let Some(OriginalFileNameRef {
-            dir_components,
-            file_name,
-        }) = original_filename
-        {
-            for component in dir_components.iter() {
-                new_filename.push(component);
-                new_filename.push("_");
+    if let Some(verification) = verify(&config)? {
+        return report_verification(&verification);
+    }
+    if let Some(written) = write_checksums(&config)? {
+        println!("Done!\nChecksummed dirs      = {}", written);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(check) = check_checksums(&config)? {
+        return Ok(report_library_check(&check));
+    }
+    if let Some(comparison) = compare(&config) {
+        report_comparison(&config, &comparison);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(shown) = gallery(&config)? {
+        println!("Done!\nPortraits in gallery  = {}", shown);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(stats) = stats(&config) {
+        report_stats(&stats);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(extras) = extras(&config) {
+        for Extras { dir, files } in &extras {
+            println!("{}", dir.display());
+            for file in files {
+                println!(
+                    "  {}",
+                    file.file_name().unwrap_or_default().to_string_lossy()
+                );
             }
-            new_filename.push(file_name);
-            if attempt > 0 {
-                assert_eq!(MAX_ATTEMPTS_WHEN_NEED_TO_KEEP_ORIGINAL_FILENAME, 1000);
-                new_filename.push(format!("_{:03}", attempt));
+        }
+        println!("Done!\nDirs with extra files = {}", extras.len());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(groups) = list_duplicates(&config) {
+        for group in &groups {
+            for dir in group {
+                println!("{}", dir.display());
             }
-        } else {
-            assert_eq!(MAX_ATTEMPTS_WHEN_NO_NEED_TO_KEEP_ORIGINAL_FILENAME, 1000000);
-            new_filename.push(format!("{:06}", attempt));
+            println!();
         }
-        target.join(new_filename)
+        println!(
+            r#"Done!
+Duplicate groups      = {}
+Duplicate dirs        = {}"#,
+            groups.len(),
+            groups.iter().map(|group| group.len() - 1).sum::<usize>()
+        );
+        return Ok(ExitCode::SUCCESS);
     }
-
-    pub fn iter(&self) -> impl Iterator<Item = (&Path, Option<&Path>)> {
-        self.scan.dirs.iter().map(PathBuf::as_path).zip(
-            self.output
-                .iter()
-                .map(|output| output.as_ref().map(PathBuf::as_path)),
-        )
+    let events = event_stream(&config)?;
+    // Only one of the report and the events can take stdout.
+    if events.is_stdout() && config.report.as_deref() == Some(Path::new("-")) {
+        return Err(anyhow::anyhow!(
+            "--report - and --progress-format ndjson both write to stdout"
+        ));
     }
-}
-
-fn prepare(args: &args::Args) -> (Scan<'_, PortraitDir>, usize) {
-    let args::Args {
-        downloads_dir,
-        portraits_dir: _,
-        prefix: _,
-        keep_original_path: _,
-        remove_useless_dirs: _,
-        remove_duplicate_dirs,
-    } = args;
-    let mut scan = Scan::new(downloads_dir, PortraitDir);
-    let erased = if *remove_duplicate_dirs {
-        scan.erase_duplicates()
+    let report_to_stdout = config.report.as_deref() == Some(Path::new("-"));
+    let human_to_stderr = report_to_stdout || events.is_stdout();
+    let mut report = Report::new(config.dry_run);
+    events.scan_started(&config.downloads_dir);
+    let Preparation {
+        scan,
+        duplicates,
+        skipped_invalid,
+        wrong_dimensions,
+        generated,
+        skipped_hidden,
+        excluded,
+        skipped_installed,
+        skipped_over_limit,
+        stripped_extras,
+        skipped_placeholders,
+        reclaimed,
+        inconsistent,
+        blocklisted,
+        not_included,
+        incomplete,
+        recent,
+        wrong_aspect,
+        casing_conflicts,
+        fixed_casing,
+        deferred,
+        permission_denied,
+    } = prepare(&config, &events);
+    let mut log_file = LogFile::from_config(config.log_file.as_deref());
+    print(
+        &duplicates,
+        human_to_stderr,
+        config.ignore_permission_errors,
+    );
+    log_file.write(&duplicates);
+    report.add(&duplicates);
+    let mut run_failed = false;
+    let moves = run(&config, scan, &events).unwrap_or_else(|err| {
+        log::error!("{}", err);
+        run_failed = true;
+        Vec::new()
+    });
+    print(&moves, human_to_stderr, config.ignore_permission_errors);
+    log_file.write(&moves);
+    report.add(&moves);
+    let useless = cleanup(&config, &events);
+    print(&useless, human_to_stderr, config.ignore_permission_errors);
+    log_file.write(&useless);
+    report.add(&useless);
+    let (portrait_duplicates, portraits_reclaimed) = dedupe_portraits(&config, &events);
+    print(
+        &portrait_duplicates,
+        human_to_stderr,
+        config.ignore_permission_errors,
+    );
+    log_file.write(&portrait_duplicates);
+    report.add_portrait_duplicates(&portrait_duplicates);
+    report.counts.skipped_invalid = skipped_invalid;
+    report.counts.wrong_dimensions = wrong_dimensions;
+    report.counts.generated = generated;
+    report.counts.skipped_hidden = skipped_hidden;
+    report.counts.skipped_installed = skipped_installed;
+    report.counts.skipped_over_limit = skipped_over_limit;
+    report.counts.stripped_extras = stripped_extras;
+    report.counts.skipped_placeholders = skipped_placeholders;
+    report.counts.reclaimed_bytes = reclaimed + portraits_reclaimed;
+    report.counts.inconsistent = inconsistent;
+    report.counts.blocklisted = blocklisted;
+    report.counts.not_included = not_included;
+    report.counts.incomplete = incomplete;
+    report.counts.recent = recent;
+    report.counts.wrong_aspect = wrong_aspect;
+    report.counts.casing_conflicts = casing_conflicts;
+    report.counts.fixed_casing = fixed_casing;
+    report.counts.deferred = deferred;
+    report.counts.permission_denied += permission_denied;
+    if let Some(path) = &config.report {
+        report.write(path)?;
+    }
+    events.summary(&report.counts, config.dry_run);
+    let failed = failed(&config, &report, run_failed);
+    let counts = &report.counts;
+    if report_to_stdout {
+        return Ok(exit_code(failed));
+    }
+    let mut human: Box<dyn Write> = if events.is_stdout() {
+        Box::new(std::io::stderr())
     } else {
-        0
+        Box::new(std::io::stdout())
     };
-    (scan, erased)
-}
-
-fn run(args: &args::Args, scan: Scan<'_, PortraitDir>) -> anyhow::Result<(usize, usize)> {
-    let args::Args {
-        downloads_dir: _,
-        portraits_dir,
-        prefix,
-        keep_original_path,
-        remove_useless_dirs: _,
-        remove_duplicate_dirs: _,
-    } = args;
-    let mv = Move::new(&scan, portraits_dir, prefix, *keep_original_path)?;
-    let mut success: usize = 0;
-    let mut failure: usize = 0;
-    for (src, dst) in mv.iter() {
-        if let Some(dst) = dst {
-            if std::fs::rename(src, dst).is_ok() {
-                success += 1;
-            } else {
-                failure += 1;
-                eprintln!("Unable to rename {} to {}", src.display(), dst.display());
-            }
-        } else {
-            failure += 1;
-            eprintln!("Unable to rename {}", src.display());
-        }
+    if config.dry_run {
+        writeln!(human, "Dry run, nothing was changed.")?;
     }
-    Ok((success, failure))
-}
-
-fn cleanup(args: &args::Args) -> usize {
-    let args::Args {
-        downloads_dir: _,
-        portraits_dir,
-        prefix: _,
-        keep_original_path: _,
-        remove_useless_dirs,
-        remove_duplicate_dirs: _,
-    } = args;
-    if !remove_useless_dirs {
-        return 0;
-    }
-    let scan = Scan::new(portraits_dir, NonPortraitDir);
-    scan.erase()
-}
-
-fn main() -> anyhow::Result<()> {
-    let args = args::Args::fetch();
-    let (scan, erased_duplicates) = prepare(&args);
-    let (success, failure) = run(&args, scan).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        (0, 0)
-    });
-    let erased_useless = cleanup(&args);
-    println!(
+    if config.no_move {
+        writeln!(human, "No moves were attempted.")?;
+    }
+    writeln!(
+        human,
         r#"Done!
-Sucessesfully renamed = {}
+Successfully renamed  = {}
 Failed to rename      = {}
 Erased useless dirs   = {}
 Erased duplicate dirs = {}"#,
-        success, failure, erased_useless, erased_duplicates
-    );
-    Ok(())
+        counts.renamed, counts.failed_to_rename, counts.erased_useless, counts.erased_duplicates,
+    )?;
+    // The other counters only matter when something happened, --report lists them all.
+    for (label, count) in [
+        ("Erased portrait dupes", counts.erased_portrait_duplicates),
+        ("Trashed dirs", counts.trashed),
+        ("Skipped invalid dirs", counts.skipped_invalid),
+        ("Wrong dimension dirs", counts.wrong_dimensions),
+        ("Generated portraits", counts.generated),
+        ("Already installed", counts.skipped_installed),
+        ("Over the limit", counts.skipped_over_limit),
+        ("Stripped extra files", counts.stripped_extras),
+        ("Placeholder dirs", counts.skipped_placeholders),
+        ("Inconsistent dirs", counts.inconsistent),
+        ("Blocklisted dirs", counts.blocklisted),
+        ("Not in include list", counts.not_included),
+        ("Incomplete dirs", counts.incomplete),
+        ("Recently modified", counts.recent),
+        ("Wrong aspect dirs", counts.wrong_aspect),
+        ("Casing conflicts", counts.casing_conflicts),
+        ("Fixed file casing", counts.fixed_casing),
+        ("Over the byte limit", counts.deferred),
+        ("Permission denied", counts.permission_denied),
+    ] {
+        if count > 0 {
+            writeln!(human, "{:<21} = {}", label, count)?;
+        }
+    }
+    if counts.reclaimed_bytes > 0 {
+        writeln!(
+            human,
+            "Space reclaimed       = {}",
+            human_bytes(counts.reclaimed_bytes)
+        )?;
+    }
+    if log::log_enabled!(log::Level::Debug) {
+        writeln!(human, "Skipped hidden dirs   = {}", counts.skipped_hidden)?;
+    }
+    for dir in &excluded {
+        log::debug!("Excluded {}", dir.display());
+    }
+    if config.watch {
+        let handled = moves
+            .iter()
+            .filter_map(|outcome| match outcome {
+                OperationOutcome::Done { src, .. } | OperationOutcome::Planned { src, .. } => {
+                    Some(src.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        watch(&config, handled, &events)?;
+    }
+    Ok(exit_code(failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_skips_fail() {
+        let dir = std::env::temp_dir().join(format!("portraits-{}-strict", std::process::id()));
+        std::fs::create_dir_all(dir.join("Downloads")).unwrap();
+        std::fs::create_dir_all(dir.join("Portraits")).unwrap();
+        let mut config =
+            args::self_test_config(&dir.join("Downloads"), &dir.join("Portraits")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut report = Report::new(false);
+        report.counts.skipped_installed = 1;
+        assert_eq!(
+            exit_code(failed(&config, &report, false)),
+            ExitCode::SUCCESS
+        );
+        config.strict = true;
+        assert_eq!(
+            exit_code(failed(&config, &report, false)),
+            ExitCode::FAILURE
+        );
+        let mut report = Report::new(false);
+        report.add(&[OperationOutcome::Skipped {
+            operation: Operation::Move,
+            src: dir.join("Downloads").join("a"),
+        }]);
+        report.counts.failed_to_rename = 0;
+        assert_eq!(
+            exit_code(failed(&config, &report, false)),
+            ExitCode::FAILURE
+        );
+        let mut report = Report::new(false);
+        report.counts.not_included = 1;
+        assert_eq!(
+            exit_code(failed(&config, &report, false)),
+            ExitCode::SUCCESS
+        );
+    }
 }
@@ -0,0 +1,256 @@
+use crate::{Checksum, HashAlgorithm, Operation, OperationOutcome, PortraitDir};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+const CSV_HEADER: &str = "destination,source,hash_algorithm,checksum";
+
+/// Where one directory of the Portraits directory came from, and the checksum of its files
+/// when it was moved.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ManifestEntry {
+    #[serde(with = "crate::encoded_path")]
+    pub destination: PathBuf,
+    #[serde(with = "crate::encoded_path")]
+    pub source: PathBuf,
+    pub hash_algorithm: String,
+    pub checksum: Option<String>,
+}
+
+/// Writes the name, relative to `target`, of every directory moved (or copied) into it along
+/// with its original path and checksum to `path`, as JSON if its extension is "json" and as
+/// CSV otherwise, which fails if a path is not valid UTF-8.
+pub(crate) fn write_manifest(
+    path: &Path,
+    target: &Path,
+    outcomes: &[OperationOutcome],
+    portrait_dir: &PortraitDir,
+    hash_algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> anyhow::Result<()> {
+    let entries: Vec<ManifestEntry> = outcomes
+        .iter()
+        .filter_map(|outcome| match outcome {
+            OperationOutcome::Done {
+                operation:
+                    Operation::Move
+                    | Operation::CleanMove
+                    | Operation::Copy
+                    | Operation::Extract
+                    | Operation::Convert,
+                src,
+                dst: Some(dst),
+            } => Some(ManifestEntry {
+                destination: dst.strip_prefix(target).unwrap_or(dst).to_path_buf(),
+                source: src.clone(),
+                hash_algorithm: hash_algorithm.to_string(),
+                checksum: Checksum::from_dir(dst, portrait_dir, hash_algorithm, buffer_size)
+                    .map(|checksum| checksum.to_string()),
+            }),
+            _ => None,
+        })
+        .collect();
+    // Rather than writing paths that would read back as other ones.
+    if !is_json(path) {
+        let non_utf8 = entries.iter().find_map(|entry| {
+            [&entry.destination, &entry.source]
+                .into_iter()
+                .find(|path| path.to_str().is_none())
+        });
+        if let Some(non_utf8) = non_utf8 {
+            return Err(anyhow!(
+                "A CSV manifest cannot hold {}, which is not valid UTF-8, use a .json one",
+                non_utf8.display()
+            ));
+        }
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    if is_json(path) {
+        serde_json::to_writer_pretty(&mut file, &entries)?;
+        writeln!(file)?;
+    } else {
+        writeln!(file, "{}", CSV_HEADER)?;
+        for entry in &entries {
+            writeln!(
+                file,
+                "{},{},{},{}",
+                csv_field(&entry.destination.to_string_lossy()),
+                csv_field(&entry.source.to_string_lossy()),
+                entry.hash_algorithm,
+                entry.checksum.as_deref().unwrap_or_default()
+            )?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Reads a manifest written by `write_manifest`.
+pub(crate) fn read_manifest(path: &Path) -> anyhow::Result<Vec<ManifestEntry>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    if is_json(path) {
+        return Ok(serde_json::from_reader(file)?);
+    }
+    let mut lines = file.lines();
+    match lines.next().transpose()? {
+        Some(header) if header == CSV_HEADER => {}
+        _ => return Err(anyhow!("{} is not a manifest", path.display())),
+    }
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let [destination, source, hash_algorithm, checksum] =
+            <[String; 4]>::try_from(csv_fields(&line))
+                .map_err(|_| anyhow!("Invalid manifest line in {}: {}", path.display(), line))?;
+        entries.push(ManifestEntry {
+            destination: destination.into(),
+            source: source.into(),
+            hash_algorithm,
+            checksum: Some(checksum).filter(|checksum| !checksum.is_empty()),
+        });
+    }
+    Ok(entries)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    fields
+}
+
+/// The outcome of checking the directories of a manifest against their recorded checksums.
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub verified: usize,
+    /// The directories whose files changed since they were moved.
+    pub mismatched: Vec<PathBuf>,
+    /// The directories that are gone, or no longer hold every required file.
+    pub missing: Vec<PathBuf>,
+    /// The directories for which the manifest records no checksum.
+    pub unchecked: Vec<PathBuf>,
+}
+
+/// Recomputes the checksum of every directory of the manifest at `path`, `target` being the
+/// directory they were moved into.
+pub(crate) fn verify_manifest(
+    path: &Path,
+    target: &Path,
+    portrait_dir: &PortraitDir,
+    buffer_size: usize,
+) -> anyhow::Result<Verification> {
+    let mut verification = Verification::default();
+    for entry in read_manifest(path)? {
+        let dir = target.join(&entry.destination);
+        let Some(checksum) = entry.checksum else {
+            verification.unchecked.push(dir);
+            continue;
+        };
+        let hash_algorithm: HashAlgorithm = entry.hash_algorithm.parse()?;
+        match Checksum::from_dir(&dir, portrait_dir, hash_algorithm, buffer_size) {
+            Some(actual) if actual.to_string() == checksum => verification.verified += 1,
+            Some(_) => verification.mismatched.push(dir),
+            None => verification.missing.push(dir),
+        }
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The manifest `name` written in a new directory for the test `test` and read back, for
+    /// the move of `src` to `dst` below the Portraits dir.
+    fn round_trip(
+        test: &str,
+        name: &str,
+        src: &Path,
+        dst: &Path,
+    ) -> anyhow::Result<Vec<ManifestEntry>> {
+        let dir = std::env::temp_dir().join(format!("portraits-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("portraits");
+        let outcomes = [OperationOutcome::Done {
+            operation: Operation::Move,
+            src: src.to_path_buf(),
+            dst: Some(target.join(dst)),
+        }];
+        let portrait_dir = PortraitDir {
+            required_files: vec!["Small.png".to_owned()],
+            case_sensitive: false,
+            extensions: vec!["png".to_owned()],
+            min_image_size: None,
+        };
+        let path = dir.join(name);
+        let entries = write_manifest(
+            &path,
+            &target,
+            &outcomes,
+            &portrait_dir,
+            HashAlgorithm::Md5,
+            4096,
+        )
+        .and_then(|()| read_manifest(&path));
+        std::fs::remove_dir_all(&dir).unwrap();
+        entries
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let entries = round_trip(
+            "manifest-csv",
+            "manifest.csv",
+            Path::new("/downloads/pack, \"the\" first/gimli"),
+            Path::new("pf_portrait_000000"),
+        )
+        .unwrap();
+        assert_eq!(entries[0].destination, Path::new("pf_portrait_000000"));
+        assert_eq!(
+            entries[0].source,
+            Path::new("/downloads/pack, \"the\" first/gimli")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths() {
+        use std::os::unix::ffi::OsStringExt;
+        let name = PathBuf::from(std::ffi::OsString::from_vec(vec![0x66, 0xff]));
+        let src = Path::new("/downloads").join(&name);
+        let entries = round_trip("manifest-json", "manifest.json", &src, &name).unwrap();
+        assert_eq!(entries[0].destination, name);
+        assert_eq!(entries[0].source, src);
+        assert!(round_trip("manifest-non-utf8", "manifest.csv", &src, &name).is_err());
+    }
+}
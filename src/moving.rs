@@ -0,0 +1,1585 @@
+use crate::png::png_text;
+use crate::scan::find_files;
+use crate::{Bucket, CleanMove, NameTemplate, PortraitDir, Scan};
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// The file in which some portrait packs describe their character.
+const DESCRIPTOR_FILE_NAME: &str = "portrait.json";
+/// The subdirectory of a portrait directory moved with `CleanMove::Subfolder` which holds
+/// the files that are not required.
+const EXTRAS_DIR_NAME: &str = "extras";
+/// The keys of the PNG text metadata that may name the artwork, by preference.
+const METADATA_NAME_KEYS: [&str; 2] = ["Title", "Description"];
+
+/// The characters that Windows refuses in file names, besides '/', NUL and the controls.
+const WINDOWS_ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+/// The characters that may not appear in file names, besides '/' and NUL.
+#[cfg(windows)]
+const ILLEGAL_CHARS: &[char] = WINDOWS_ILLEGAL_CHARS;
+#[cfg(target_os = "macos")]
+const ILLEGAL_CHARS: &[char] = &[':'];
+#[cfg(not(any(windows, target_os = "macos")))]
+const ILLEGAL_CHARS: &[char] = &[];
+
+/// The names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The length from which Windows only accepts a path with the "\\?\" prefix, which for
+/// directories is 260 less room for an 8.3 file name in them.
+#[cfg(windows)]
+const MAX_DIR_PATH: usize = 248;
+
+/// The description a portrait pack gives of its character in its "portrait.json", of which
+/// only the name matters here, the class and source being ignored.
+#[derive(Deserialize)]
+struct Descriptor {
+    name: String,
+}
+
+impl Descriptor {
+    /// The name given by the descriptor of `dir`, unless it has none or it cannot be used as a
+    /// file name.
+    fn name(dir: &Path) -> Option<OsString> {
+        let path = dir.join(DESCRIPTOR_FILE_NAME);
+        let json = std::fs::read_to_string(&path).ok()?;
+        let descriptor: Descriptor = match serde_json::from_str(&json) {
+            Ok(descriptor) => descriptor,
+            Err(err) => {
+                log::warn!("Ignoring invalid descriptor {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        usable_name(&descriptor.name, &path)
+    }
+}
+
+/// The title, or else the description, in the text metadata of the "Medium.png" of `dir`,
+/// unless it cannot be used as a file name.
+fn metadata_name(dir: &Path) -> Option<OsString> {
+    let medium = find_files(dir, &["Medium.png"], false)?.pop()?;
+    let text = png_text(&medium, &METADATA_NAME_KEYS)?;
+    usable_name(&text, &medium)
+}
+
+/// `name`, taken from `source`, without surrounding whitespace, unless it holds a path
+/// separator or is no name at all.
+fn usable_name(name: &str, source: &Path) -> Option<OsString> {
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        log::warn!("Ignoring the name {:?} of {}", name, source.display());
+        return None;
+    }
+    Some(name.into())
+}
+
+/// Where the name of an original directory comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum NameSource {
+    Folder,
+    Descriptor,
+    Metadata,
+}
+
+/// How many of the names derived from the original directories came from each source.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NameSources {
+    pub folders: usize,
+    pub descriptors: usize,
+    pub metadata: usize,
+}
+
+struct OriginalFileName<'a> {
+    dir_components: Vec<Cow<'a, OsStr>>,
+    file_name: OsString,
+    name_source: NameSource,
+}
+
+impl<'a> OriginalFileName<'a> {
+    pub fn new(options: &MoveOptions, scan_skip_components: usize, dir: &'a Path) -> Option<Self> {
+        let mut dir_components: Vec<Cow<OsStr>> = dir
+            .components()
+            .skip(scan_skip_components)
+            .map(|component| Cow::Borrowed(component.as_os_str()))
+            .collect();
+        let folder_name = dir_components.pop()?;
+        let name = options
+            .use_descriptor
+            .then(|| Descriptor::name(dir))
+            .flatten()
+            .map(|name| (name, NameSource::Descriptor))
+            .or_else(|| {
+                options
+                    .name_from_metadata
+                    .then(|| metadata_name(dir))
+                    .flatten()
+                    .map(|name| (name, NameSource::Metadata))
+            });
+        let (file_name, name_source) = match name {
+            Some((name, source)) => (Cow::Owned(name), source),
+            None => (folder_name, NameSource::Folder),
+        };
+        if options.collapse_redundant {
+            dir_components.dedup_by(|component, parent| component.eq_ignore_ascii_case(parent));
+            if dir_components
+                .last()
+                .is_some_and(|parent| parent.eq_ignore_ascii_case(&file_name))
+            {
+                dir_components.pop();
+            }
+        }
+        // The source is already in the prefix, unless the tree is recreated from it.
+        if options.prefix_from_source && !options.preserve_tree && !dir_components.is_empty() {
+            dir_components.remove(0);
+        }
+        let file_name = Self::stripped(&file_name, options.prefix, options.strip_prefix_repeat);
+        let file_name = sanitized(
+            &file_name,
+            options.sanitize_replacement,
+            options.windows_names,
+        )
+        .into_owned();
+        for component in &mut dir_components {
+            if let Cow::Owned(sanitized) = sanitized(
+                component,
+                options.sanitize_replacement,
+                options.windows_names,
+            ) {
+                *component = Cow::Owned(sanitized);
+            }
+        }
+        Some(Self {
+            dir_components,
+            file_name,
+            name_source,
+        })
+    }
+
+    /// `file_name` without the leading `dir_prefix`, ignoring ASCII case, e.g. "PF_Portrait_"
+    /// for "pf_portrait_". With `repeat`, every leading occurrence is stripped, so that
+    /// "pf_portrait_pf_portrait_gimli" becomes "gimli" rather than "pf_portrait_gimli".
+    fn stripped(file_name: &OsStr, dir_prefix: &str, repeat: bool) -> OsString {
+        let base_bytes: &[u8] = dir_prefix.as_bytes();
+        let file_name_bytes: &[u8] = file_name.as_encoded_bytes();
+        let mut bytes = file_name_bytes;
+        // An empty prefix would never stop matching.
+        while !base_bytes.is_empty()
+            && bytes.len() >= base_bytes.len()
+            && bytes[..base_bytes.len()].eq_ignore_ascii_case(base_bytes)
+        {
+            bytes = &bytes[base_bytes.len()..];
+            if !repeat {
+                break;
+            }
+        }
+        let stripped = &file_name_bytes[..file_name_bytes.len() - bytes.len()];
+        if std::str::from_utf8(stripped).is_err() {
+            return file_name.to_os_string();
+        }
+        // SAFETY: `bytes` follows `stripped`, a valid UTF-8 substring of `file_name`, which the
+        // documentation of `from_encoded_bytes_unchecked` lists as a valid boundary.
+        unsafe { OsString::from_encoded_bytes_unchecked(bytes.to_vec()) }
+    }
+
+    pub fn as_ref(&'a self) -> OriginalFileNameRef<'a> {
+        let Self {
+            dir_components,
+            file_name,
+            name_source: _,
+        } = self;
+        let dir_components = &dir_components[..];
+        let file_name = file_name.as_os_str();
+        OriginalFileNameRef {
+            dir_components,
+            file_name,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct OriginalFileNameRef<'a> {
+    dir_components: &'a [Cow<'a, OsStr>],
+    file_name: &'a OsStr,
+}
+
+impl OriginalFileNameRef<'_> {
+    /// The components and the file name, joined with `separator`.
+    fn joined(self, separator: &str) -> OsString {
+        let mut joined = OsString::new();
+        for component in self.dir_components.iter() {
+            joined.push(component);
+            joined.push(separator);
+        }
+        joined.push(self.file_name);
+        joined
+    }
+}
+
+/// How `Move` names the directories in the target directory.
+#[derive(Clone, Copy, Debug)]
+pub struct MoveOptions<'a> {
+    /// The prefix of every name.
+    pub prefix: &'a str,
+    /// Name the directories after their original path instead of numbering them.
+    pub keep_original_path: bool,
+    /// Name the directories following this template instead.
+    pub name_template: Option<&'a NameTemplate>,
+    /// Joins the components of the original path, and the attempt on collisions.
+    pub separator: &'a str,
+    /// The width to which the attempt is zero-padded on collisions, which also limits the
+    /// number of attempts.
+    pub attempt_padding: u32,
+    /// The most names tried for each directory, further capped by `attempt_padding` when
+    /// the attempt is appended to the original path.
+    pub max_attempts: u32,
+    /// Recreate the original directory structure below the target directory, rather than
+    /// joining the original path into one name.
+    pub preserve_tree: bool,
+    /// Strip every leading occurrence of the prefix from the original names, not just the
+    /// first.
+    pub strip_prefix_repeat: bool,
+    /// Group the directories into these subdirectories of the target directory.
+    pub bucket: Bucket,
+    /// Drop the components of the original path that equal their parent, ignoring ASCII case.
+    pub collapse_redundant: bool,
+    /// Replaces the characters of the original path that are illegal in file names.
+    pub sanitize_replacement: &'a str,
+    /// Sanitize as on Windows whatever the platform, for a Portraits dir shared with it.
+    pub windows_names: bool,
+    /// Shorten the names longer than this many bytes, which most filesystems reject beyond
+    /// 255.
+    pub max_name_length: usize,
+    /// Treat the first this many candidate destinations of every directory as taken, for
+    /// testing.
+    pub force_collisions: u32,
+    /// Take the name of the original directory from its "portrait.json", when it has a valid
+    /// one.
+    pub use_descriptor: bool,
+    /// Take the name of the original directory from the text metadata of its "Medium.png",
+    /// when it has a title or a description and no descriptor names it.
+    pub name_from_metadata: bool,
+    /// Follow the prefix with the first component of the original path, naming the pack the
+    /// directory comes from.
+    pub prefix_from_source: bool,
+    /// The exact names of the directories named, in lowercase, by the keys, which are not
+    /// numbered nor prefixed.
+    pub slot_map: &'a HashMap<String, String>,
+    /// The target directory does not exist yet, as in a dry run that would create it, and is
+    /// planned for as an empty one.
+    pub target_pending: bool,
+}
+
+/// The non-colliding destinations in the target directory for every directory of a scan.
+pub struct Move<'a> {
+    dirs: &'a [PathBuf],
+    output: Vec<Option<PathBuf>>,
+    name_sources: NameSources,
+}
+
+impl<'a> Move<'a> {
+    pub fn new(
+        scan: &'a Scan<'_, PortraitDir>,
+        target: &Path,
+        options: &MoveOptions,
+    ) -> anyhow::Result<Self> {
+        Self::with_dirs(scan.root, &scan.dirs, target, options)
+    }
+
+    /// Like `new`, for `dirs` below `root` that need not come from a scan.
+    pub fn with_dirs(
+        root: &Path,
+        dirs: &'a [PathBuf],
+        target: &Path,
+        options: &MoveOptions,
+    ) -> anyhow::Result<Self> {
+        if !options.target_pending && !target.is_dir() {
+            return Err(anyhow!("{} is not a directory", target.display()));
+        }
+        if options.sanitize_replacement.contains(ILLEGAL_CHARS)
+            || options.windows_names && options.sanitize_replacement.contains(WINDOWS_ILLEGAL_CHARS)
+        {
+            return Err(anyhow!(
+                "The replacement {:?} holds characters illegal in file names",
+                options.sanitize_replacement
+            ));
+        }
+        let mut output: Vec<Option<PathBuf>> = Vec::new();
+        let mut output_set: HashSet<PathBuf> = HashSet::new();
+        let mut name_sources = NameSources::default();
+
+        let scan_skip_components = root.components().count();
+        let needs_original_path = options.keep_original_path
+            || options.preserve_tree
+            || options.name_template.is_some_and(NameTemplate::has_path);
+        let max_attempts_with_suffix = 10u32
+            .saturating_pow(options.attempt_padding)
+            .min(options.max_attempts);
+        // Reserved before naming anything, so that no other directory takes a slot.
+        let slots = Self::slots(dirs, target, options)?;
+        output_set.extend(slots.iter().flatten().cloned());
+        for (index, dir) in dirs.iter().enumerate() {
+            if let Some(slot) = &slots[index] {
+                // Unlike a numbered name, a slot cannot move aside, so only this dir is skipped.
+                if slot.exists() {
+                    log::warn!(
+                        "Cannot move {} to its slot {}, which already exists",
+                        dir.display(),
+                        slot.display()
+                    );
+                    output.push(None);
+                } else {
+                    output.push(Some(slot.clone()));
+                }
+                continue;
+            }
+            let (original_filename, max_attempts) = {
+                if needs_original_path {
+                    let original_filename =
+                        OriginalFileName::new(options, scan_skip_components, dir);
+                    if original_filename.is_none() {
+                        output.push(None);
+                        continue;
+                    }
+                    (original_filename, max_attempts_with_suffix)
+                } else {
+                    (None, options.max_attempts)
+                }
+            };
+            let max_attempts = match options.name_template {
+                Some(name_template) if !name_template.has_attempt() => max_attempts_with_suffix,
+                _ => max_attempts,
+            };
+            match original_filename.as_ref().map(|name| name.name_source) {
+                Some(NameSource::Folder) => name_sources.folders += 1,
+                Some(NameSource::Descriptor) => name_sources.descriptors += 1,
+                Some(NameSource::Metadata) => name_sources.metadata += 1,
+                None => {}
+            }
+            let original_filename = original_filename.as_ref().map(OriginalFileName::as_ref);
+            let prefix = Self::source_prefix(options, scan_skip_components, dir);
+            let options = &MoveOptions {
+                prefix: prefix.as_deref().unwrap_or(options.prefix),
+                ..*options
+            };
+            // Names only need to be unique within their bucket.
+            let target = &Self::bucket(target, options, scan_skip_components, dir);
+            let mut attempt: u32 = 0;
+            let rename = |attempt| match options.name_template {
+                Some(name_template) => Self::render(
+                    target,
+                    name_template,
+                    options,
+                    index,
+                    attempt,
+                    original_filename,
+                ),
+                None => Self::rename(target, options, attempt, original_filename),
+            };
+            let mut new_path = rename(attempt);
+            output.push(loop {
+                let r = &new_path;
+                let forced = attempt < options.force_collisions;
+                if !(forced || output_set.contains(r) || r.exists()) {
+                    output_set.insert(new_path.clone());
+                    break Some(new_path);
+                }
+                attempt += 1;
+                if attempt >= max_attempts {
+                    break None;
+                }
+                new_path = rename(attempt);
+            });
+        }
+        Ok(Self {
+            dirs,
+            output,
+            name_sources,
+        })
+    }
+
+    /// The destination of every directory of `dirs` that `options.slot_map` names, failing
+    /// if two directories are mapped to the same one.
+    fn slots(
+        dirs: &[PathBuf],
+        target: &Path,
+        options: &MoveOptions,
+    ) -> anyhow::Result<Vec<Option<PathBuf>>> {
+        let mut taken: HashMap<PathBuf, &Path> = HashMap::new();
+        let mut slots = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            let name = dir.file_name().unwrap_or_default().to_string_lossy();
+            let Some(slot) = options.slot_map.get(&name.to_lowercase()) else {
+                slots.push(None);
+                continue;
+            };
+            let dst = target.join(slot);
+            if let Some(other) = taken.insert(dst.clone(), dir) {
+                return Err(anyhow!(
+                    "Both {} and {} are mapped to the slot {}",
+                    other.display(),
+                    dir.display(),
+                    dst.display()
+                ));
+            }
+            slots.push(Some(dst));
+        }
+        Ok(slots)
+    }
+
+    /// How many of the names derived from the original directories came from each source.
+    pub fn name_sources(&self) -> NameSources {
+        self.name_sources
+    }
+
+    fn rename(
+        target: &Path,
+        options: &MoveOptions,
+        attempt: u32,
+        original_filename: Option<OriginalFileNameRef<'_>>,
+    ) -> PathBuf {
+        let mut new_filename = OsString::new();
+        new_filename.push(options.prefix);
+        if let (true, Some(original_filename)) = (options.preserve_tree, original_filename) {
+            new_filename.push(original_filename.file_name);
+            if attempt > 0 {
+                new_filename.push(Self::attempt_suffix(options, attempt));
+            }
+            return Self::tree(target, original_filename).join(Self::fit(new_filename, options));
+        }
+        if let Some(original_filename) = original_filename {
+            new_filename.push(original_filename.joined(options.separator));
+            if attempt > 0 {
+                new_filename.push(Self::attempt_suffix(options, attempt));
+            }
+        } else {
+            new_filename.push(format!(
+                "{:0width$}",
+                attempt,
+                width = Self::number_width(options.max_attempts)
+            ));
+        }
+        target.join(Self::fit(new_filename, options))
+    }
+
+    /// Like `rename`, but following `name_template`. Unless the template places the attempt
+    /// itself, it is appended on collisions.
+    fn render(
+        target: &Path,
+        name_template: &NameTemplate,
+        options: &MoveOptions,
+        index: usize,
+        attempt: u32,
+        original_filename: Option<OriginalFileNameRef<'_>>,
+    ) -> PathBuf {
+        let path = original_filename
+            .map(|original_filename| original_filename.joined(options.separator))
+            .unwrap_or_default();
+        let mut new_filename = name_template.render(options.prefix, index, &path, attempt);
+        if attempt > 0 && !name_template.has_attempt() {
+            new_filename.push(Self::attempt_suffix(options, attempt));
+        }
+        let new_filename = Self::fit(new_filename, options);
+        match (options.preserve_tree, original_filename) {
+            (true, Some(original_filename)) => {
+                Self::tree(target, original_filename).join(new_filename)
+            }
+            _ => target.join(new_filename),
+        }
+    }
+
+    /// `new_filename`, if longer than `options.max_name_length` bytes, with its middle cut
+    /// out and a hash of the whole name appended, which keeps shortened names distinct.
+    fn fit(new_filename: OsString, options: &MoveOptions) -> OsString {
+        if new_filename.len() <= options.max_name_length {
+            return new_filename;
+        }
+        let name = new_filename.as_encoded_bytes();
+        let hash = format!("{:x}", md5::compute(name));
+        let hash = &hash[..8];
+        // Room for the name around the "~" marking the cut and the "_" before the hash.
+        let budget = options.max_name_length.saturating_sub(hash.len() + 2);
+        let head = floor_boundary(
+            name,
+            (budget - budget / 2).max(options.prefix.len().min(budget)),
+        );
+        let tail = ceil_boundary(name, name.len() - (budget - head));
+        let mut fitted = Vec::with_capacity(options.max_name_length);
+        fitted.extend_from_slice(&name[..head]);
+        fitted.push(b'~');
+        fitted.extend_from_slice(&name[tail..]);
+        fitted.push(b'_');
+        fitted.extend_from_slice(hash.as_bytes());
+        // SAFETY: `name` is cut at boundaries next to valid UTF-8, joined with ASCII.
+        unsafe { OsString::from_encoded_bytes_unchecked(fitted) }
+    }
+
+    /// The subdirectory of `target` into which `dir` is put, according to `options.bucket`.
+    fn bucket(
+        target: &Path,
+        options: &MoveOptions,
+        scan_skip_components: usize,
+        dir: &Path,
+    ) -> PathBuf {
+        let components: Vec<&OsStr> = dir
+            .components()
+            .skip(scan_skip_components)
+            .map(std::path::Component::as_os_str)
+            .collect();
+        match (options.bucket, &components[..]) {
+            (Bucket::ByLetter, [.., file_name]) => {
+                let file_name = OriginalFileName::stripped(
+                    file_name,
+                    options.prefix,
+                    options.strip_prefix_repeat,
+                );
+                let letter = file_name
+                    .to_string_lossy()
+                    .chars()
+                    .find(|c| c.is_alphanumeric())
+                    .map_or_else(|| "#".to_owned(), |c| c.to_uppercase().collect());
+                target.join(letter)
+            }
+            (Bucket::BySourceTop, [top, _, ..]) => target.join(sanitized(
+                top,
+                options.sanitize_replacement,
+                options.windows_names,
+            )),
+            _ => target.to_path_buf(),
+        }
+    }
+
+    /// With `options.prefix_from_source`, the prefix followed by the sanitized name of the top
+    /// directory `dir` comes from and the separator, unless `dir` is at the top itself.
+    fn source_prefix(
+        options: &MoveOptions,
+        scan_skip_components: usize,
+        dir: &Path,
+    ) -> Option<String> {
+        if !options.prefix_from_source {
+            return None;
+        }
+        let mut components = dir.components().skip(scan_skip_components);
+        let source = components.next()?.as_os_str();
+        components.next()?;
+        Some(format!(
+            "{}{}{}",
+            options.prefix,
+            sanitized(source, options.sanitize_replacement, options.windows_names)
+                .to_string_lossy(),
+            options.separator
+        ))
+    }
+
+    /// The directory below `target` corresponding to the parent of the original directory.
+    fn tree(target: &Path, original_filename: OriginalFileNameRef<'_>) -> PathBuf {
+        original_filename
+            .dir_components
+            .iter()
+            .fold(target.to_path_buf(), |tree, component| tree.join(component))
+    }
+
+    /// The digits needed for the numbers below `max_attempts`, e.g. 6 for a million.
+    fn number_width(max_attempts: u32) -> usize {
+        max_attempts
+            .saturating_sub(1)
+            .checked_ilog10()
+            .map_or(1, |digits| digits as usize + 1)
+    }
+
+    fn attempt_suffix(options: &MoveOptions, attempt: u32) -> String {
+        format!(
+            "{}{:0width$}",
+            options.separator,
+            attempt,
+            width = options.attempt_padding as usize
+        )
+    }
+
+    /// Every scanned directory along with its destination, if one could be found.
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, Option<&Path>)> {
+        self.dirs.iter().map(PathBuf::as_path).zip(
+            self.output
+                .iter()
+                .map(|output| output.as_ref().map(PathBuf::as_path)),
+        )
+    }
+}
+
+/// `name` with `replacement` for every character that is illegal in file names on this
+/// platform, as well as, on Windows or if `windows`, for the characters Windows refuses, for
+/// trailing dots and spaces and before device names such as "CON".
+fn sanitized<'a>(name: &'a OsStr, replacement: &str, windows: bool) -> Cow<'a, OsStr> {
+    let windows = windows || cfg!(windows);
+    let bytes = name.as_encoded_bytes();
+    let is_illegal = |byte: u8| {
+        ILLEGAL_CHARS.contains(&(byte as char))
+            || windows
+                && (byte.is_ascii_control() || WINDOWS_ILLEGAL_CHARS.contains(&(byte as char)))
+    };
+    let stem = bytes.split(|byte| *byte == b'.').next().unwrap_or_default();
+    let is_reserved = windows
+        && RESERVED_NAMES
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved.as_bytes()));
+    let trailing = if windows {
+        bytes.len() - bytes.trim_ascii_end().len()
+            + bytes
+                .trim_ascii_end()
+                .iter()
+                .rev()
+                .take_while(|byte| **byte == b'.')
+                .count()
+    } else {
+        0
+    };
+    if !is_reserved && trailing == 0 && !bytes.iter().any(|byte| is_illegal(*byte)) {
+        return Cow::Borrowed(name);
+    }
+    let mut sanitized = Vec::with_capacity(bytes.len());
+    if is_reserved {
+        sanitized.extend_from_slice(replacement.as_bytes());
+    }
+    // Only ASCII bytes are replaced, which never are part of a longer character.
+    for byte in &bytes[..bytes.len() - trailing] {
+        if is_illegal(*byte) {
+            sanitized.extend_from_slice(replacement.as_bytes());
+        } else {
+            sanitized.push(*byte);
+        }
+    }
+    for _ in 0..trailing {
+        sanitized.extend_from_slice(replacement.as_bytes());
+    }
+    // SAFETY: Only ASCII characters of `name` were replaced, with valid UTF-8.
+    Cow::Owned(unsafe { OsString::from_encoded_bytes_unchecked(sanitized) })
+}
+
+/// Whether the encoded `bytes` of an `OsStr` may be split at `index`, i.e. at their ends or
+/// right before or after a valid UTF-8 character, as `OsStr::from_encoded_bytes_unchecked`
+/// requires, which keeps any non-UTF-8 sequence whole.
+fn is_boundary(bytes: &[u8], index: usize) -> bool {
+    index == 0
+        || index == bytes.len()
+        || bytes[index..]
+            .utf8_chunks()
+            .next()
+            .is_some_and(|chunk| !chunk.valid().is_empty())
+        || bytes[..index]
+            .utf8_chunks()
+            .last()
+            .is_some_and(|chunk| chunk.invalid().is_empty() && !chunk.valid().is_empty())
+}
+
+/// The largest boundary of `bytes` not above `index`.
+fn floor_boundary(bytes: &[u8], index: usize) -> usize {
+    (0..=index.min(bytes.len()))
+        .rev()
+        .find(|i| is_boundary(bytes, *i))
+        .unwrap_or(0)
+}
+
+/// The smallest boundary of `bytes` not below `index`.
+fn ceil_boundary(bytes: &[u8], index: usize) -> usize {
+    (index..=bytes.len())
+        .find(|i| is_boundary(bytes, *i))
+        .unwrap_or(bytes.len())
+}
+
+/// Moves `src` to `dst`, falling back to copying when they are on different filesystems, in
+/// which case the modification times are kept if `preserve_times`.
+pub(crate) fn move_portrait(src: &Path, dst: &Path, preserve_times: bool) -> std::io::Result<()> {
+    let (src, dst) = (&*long_path(src), &*long_path(dst));
+    match std::fs::rename(src, dst) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            move_across_devices(src, dst, preserve_times)
+        }
+        result => result,
+    }
+}
+
+/// Moves `src` to `dst` on another filesystem, which `rename` cannot, by copying it and then
+/// removing it. A partial copy is removed, leaving `src` untouched.
+fn move_across_devices(src: &Path, dst: &Path, preserve_times: bool) -> std::io::Result<()> {
+    std::fs::create_dir(dst)?;
+    let copied = copy_dir_contents(src, dst, preserve_times).and_then(|()| {
+        if preserve_times {
+            copy_mtime(src, dst)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(err) = copied {
+        if std::fs::remove_dir_all(dst).is_err() {
+            log::error!("Failed to clean up partial copy {}", dst.display());
+        }
+        return Err(err);
+    }
+    std::fs::remove_dir_all(src)
+}
+
+/// Moves the required files of `src` into the new directory `dst`, and its other files as
+/// `extras` says, removing `src` once it is empty. Its subdirectories are left in place. On
+/// failure, the files already moved are moved back and `dst` is removed.
+pub(crate) fn clean_move_portrait(
+    src: &Path,
+    dst: &Path,
+    portrait_dir: &PortraitDir,
+    extras: CleanMove,
+    preserve_times: bool,
+) -> std::io::Result<()> {
+    let files = portrait_dir
+        .files(src)
+        .ok_or(std::io::ErrorKind::NotFound)?;
+    let others = portrait_dir.extras(src);
+    let dst = &*long_path(dst);
+    std::fs::create_dir(dst)?;
+    let mut moved = Vec::with_capacity(files.len() + others.len());
+    let result = clean_move_files(
+        src,
+        dst,
+        &files,
+        &others,
+        extras,
+        preserve_times,
+        &mut moved,
+    );
+    if let Err(err) = result {
+        for (file, target) in moved.iter().rev() {
+            if let Err(err) = move_file(target, file, preserve_times) {
+                log::error!("Failed to move {} back: {}", target.display(), err);
+            }
+        }
+        // Only emptied directories are removed, so nothing that could not be moved back.
+        let _ = std::fs::remove_dir(dst.join(EXTRAS_DIR_NAME));
+        if std::fs::remove_dir(dst).is_err() {
+            log::error!("Failed to clean up partial move {}", dst.display());
+        }
+        return Err(err);
+    }
+    // Whatever is left, e.g. the extras or a subdirectory, keeps the original directory.
+    if std::fs::remove_dir(src).is_err() {
+        log::debug!("Kept {} for what is left in it", src.display());
+    }
+    Ok(())
+}
+
+/// The moves of `clean_move_portrait`, pushing every moved file along with its target to
+/// `moved`.
+fn clean_move_files(
+    src: &Path,
+    dst: &Path,
+    files: &[PathBuf],
+    others: &[PathBuf],
+    extras: CleanMove,
+    preserve_times: bool,
+    moved: &mut Vec<(PathBuf, PathBuf)>,
+) -> std::io::Result<()> {
+    for file in files {
+        let target = dst.join(file.file_name().unwrap_or_default());
+        move_file(file, &target, preserve_times)?;
+        moved.push((file.clone(), target));
+    }
+    match extras {
+        CleanMove::Subfolder if !others.is_empty() => {
+            let extras_dir = dst.join(EXTRAS_DIR_NAME);
+            std::fs::create_dir(&extras_dir)?;
+            for file in others {
+                let target = extras_dir.join(file.file_name().unwrap_or_default());
+                move_file(file, &target, preserve_times)?;
+                log::info!("Moved extra {} to {}", file.display(), target.display());
+                moved.push((file.clone(), target));
+            }
+        }
+        CleanMove::Leave => {
+            for file in others {
+                log::info!("Left extra {} in place", file.display());
+            }
+        }
+        CleanMove::Subfolder => {}
+    }
+    if preserve_times {
+        copy_mtime(src, dst)?;
+    }
+    Ok(())
+}
+
+/// Undoes `clean_move_portrait`: moves the files of `dst`, including those of its "extras"
+/// subdirectory, back into `src`, which may still hold what was left there, and removes `dst`.
+/// Fails without moving anything if one of the files is in `src` again.
+pub(crate) fn restore_clean_move(dst: &Path, src: &Path) -> std::io::Result<()> {
+    let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(dst)?);
+    let extras_dir = dst.join(EXTRAS_DIR_NAME);
+    let mut files = Vec::new();
+    for dir in [dst, &extras_dir] {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            if dir == dst && entry.file_name() == EXTRAS_DIR_NAME && entry.file_type()?.is_dir() {
+                continue;
+            }
+            files.push((entry.path(), src.join(entry.file_name())));
+        }
+    }
+    if files.iter().any(|(_, target)| target.exists()) {
+        return Err(std::io::ErrorKind::AlreadyExists.into());
+    }
+    std::fs::create_dir_all(src)?;
+    for (file, target) in &files {
+        move_file(file, target, true)?;
+    }
+    if extras_dir.is_dir() {
+        std::fs::remove_dir(&extras_dir)?;
+    }
+    std::fs::remove_dir(dst)?;
+    filetime::set_file_mtime(src, mtime)
+}
+
+/// `path` as Windows accepts it when it is too long: absolute and with the "\\?\" prefix,
+/// which the canonical path of its parent already has. E.g. a deep destination with
+/// `keep_original_path`.
+#[cfg(windows)]
+fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::os::windows::ffi::OsStrExt;
+    if path.as_os_str().encode_wide().count() < MAX_DIR_PATH
+        || path.as_os_str().as_encoded_bytes().starts_with(br"\\?\")
+    {
+        return Cow::Borrowed(path);
+    }
+    match (path.parent().map(std::fs::canonicalize), path.file_name()) {
+        (Some(Ok(parent)), Some(file_name)) => Cow::Owned(parent.join(file_name)),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Moves the file `src` to `dst`, falling back to copying when they are on different
+/// filesystems.
+fn move_file(src: &Path, dst: &Path, preserve_times: bool) -> std::io::Result<()> {
+    match std::fs::rename(src, dst) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(src, dst)?;
+            if preserve_times {
+                copy_mtime(src, dst)?;
+            }
+            std::fs::remove_file(src)
+        }
+        result => result,
+    }
+}
+
+/// Removes the parent of `src` and its ancestors as long as they are empty and below `root`,
+/// returning how many are removed.
+pub(crate) fn prune_empty_parents(src: &Path, root: &Path) -> usize {
+    let mut pruned = 0;
+    for dir in src.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) {
+            break;
+        }
+        // Only empty directories can be removed, so a directory that is not is left alone.
+        match std::fs::remove_dir(dir) {
+            Ok(()) => {
+                log::debug!("Removed empty directory {}", dir.display());
+                pruned += 1;
+            }
+            Err(_) => break,
+        }
+    }
+    pruned
+}
+
+/// Calls `operation` on `src` until it succeeds, fails with an error that does not look
+/// transient, or fails `retries` more times, waiting `delay` before the first retry and twice
+/// as long before every further one.
+pub(crate) fn retrying(
+    retries: u32,
+    delay: std::time::Duration,
+    src: &Path,
+    mut operation: impl FnMut() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut delay = delay;
+    for retry in 1..=retries {
+        match operation() {
+            Err(err) if is_transient(&err) => {
+                log::debug!(
+                    "Retrying {} ({}/{}) in {} ms: {}",
+                    src.display(),
+                    retry,
+                    retries,
+                    delay.as_millis(),
+                    err
+                );
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+    operation()
+}
+
+/// Whether `err` may go away by itself, e.g. on a busy network share.
+fn is_transient(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        err.kind(),
+        ErrorKind::ResourceBusy
+            | ErrorKind::TimedOut
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::StaleNetworkFileHandle
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+    )
+}
+
+fn copy_dir_contents(src: &Path, dst: &Path, preserve_times: bool) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&target)?;
+            copy_dir_contents(&entry.path(), &target, preserve_times)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+        // A directory's time is set last, as filling it updates it.
+        if preserve_times {
+            copy_mtime(&entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_mtime(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(src)?);
+    filetime::set_file_mtime(dst, mtime)
+}
+
+/// Whether any of the required files in `dir` is not a PNG, e.g. "Small.jpg".
+pub(crate) fn needs_conversion(dir: &Path, portrait_dir: &PortraitDir) -> bool {
+    portrait_dir
+        .files(dir)
+        .unwrap_or_default()
+        .iter()
+        .any(|file| {
+            !file
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("png"))
+        })
+}
+
+/// Writes the required files of `src` into the new directory `dst` as PNGs, erasing `src`
+/// afterwards unless `keep_src`. Every file is decoded before anything is written, so that
+/// an undecodable one leaves no partial conversion behind.
+pub(crate) fn convert_portrait(
+    src: &Path,
+    dst: &Path,
+    portrait_dir: &PortraitDir,
+    keep_src: bool,
+) -> std::io::Result<()> {
+    let files = portrait_dir
+        .files(src)
+        .ok_or(std::io::ErrorKind::NotFound)?;
+    let images = files
+        .iter()
+        .map(|file| {
+            image::open(file).map_err(|err| {
+                let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("failed to decode {}: {}", file_name, err),
+                )
+            })
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    std::fs::create_dir_all(dst)?;
+    let written = files.iter().zip(images).try_for_each(|(file, image)| {
+        let target = dst
+            .join(file.file_stem().unwrap_or_default())
+            .with_extension("png");
+        image
+            .save_with_format(&target, image::ImageFormat::Png)
+            .map_err(std::io::Error::other)
+    });
+    // As a move would, `dst` keeps the other files of `src`, which is then erased.
+    let written = written.and_then(|()| {
+        if keep_src {
+            Ok(())
+        } else {
+            copy_unconverted(src, dst, &files)
+        }
+    });
+    if let Err(err) = written {
+        if std::fs::remove_dir_all(dst).is_err() {
+            log::error!("Failed to clean up partial conversion {}", dst.display());
+        }
+        return Err(err);
+    }
+    if keep_src {
+        Ok(())
+    } else {
+        std::fs::remove_dir_all(src)
+    }
+}
+
+/// Copies everything in `src` but the `converted` files into `dst`, leaving alone what is
+/// already there, e.g. "Small.png" next to the "Small.jpg" it was converted from.
+fn copy_unconverted(src: &Path, dst: &Path, converted: &[PathBuf]) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if converted.contains(&entry.path()) || target.exists() {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir(&target)?;
+            copy_dir_contents(&entry.path(), &target, false)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn copy_portrait(
+    src: &Path,
+    dst: &Path,
+    portrait_dir: &PortraitDir,
+    preserve_times: bool,
+) -> std::io::Result<()> {
+    let files = portrait_dir
+        .files(src)
+        .ok_or(std::io::ErrorKind::NotFound)?;
+    std::fs::create_dir_all(dst)?;
+    let copied = files.iter().try_for_each(|file| {
+        let target = dst.join(file.file_name().unwrap_or_default());
+        std::fs::copy(file, &target)?;
+        if preserve_times {
+            copy_mtime(file, &target)?;
+        }
+        Ok(())
+    });
+    let copied = copied.and_then(|()| {
+        if preserve_times {
+            copy_mtime(src, dst)
+        } else {
+            Ok(())
+        }
+    });
+    if copied.is_err() && std::fs::remove_dir_all(dst).is_err() {
+        log::error!("Failed to clean up partial copy {}", dst.display());
+    }
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::LazyLock;
+
+    static NO_SLOTS: LazyLock<HashMap<String, String>> = LazyLock::new(HashMap::new);
+
+    /// The options of a run without arguments.
+    fn options() -> MoveOptions<'static> {
+        MoveOptions {
+            prefix: "pf_portrait_",
+            keep_original_path: false,
+            name_template: None,
+            separator: "_",
+            attempt_padding: 3,
+            max_attempts: 1_000_000,
+            preserve_tree: false,
+            strip_prefix_repeat: false,
+            bucket: Bucket::None,
+            collapse_redundant: false,
+            sanitize_replacement: "_",
+            windows_names: false,
+            max_name_length: 255,
+            force_collisions: 0,
+            use_descriptor: false,
+            name_from_metadata: false,
+            prefix_from_source: false,
+            slot_map: &NO_SLOTS,
+            target_pending: false,
+        }
+    }
+
+    /// A new empty directory for the test `name`.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("portraits-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The names `Move` gives to the `dirs` below "downloads" in an empty target.
+    fn destinations(test: &str, dirs: &[&str], options: &MoveOptions) -> Vec<String> {
+        let target = scratch(test);
+        let root = Path::new("downloads");
+        let dirs: Vec<PathBuf> = dirs.iter().map(|dir| root.join(dir)).collect();
+        let destinations = Move::with_dirs(root, &dirs, &target, options)
+            .unwrap()
+            .iter()
+            .map(|(_, dst)| {
+                dst.unwrap()
+                    .strip_prefix(&target)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        std::fs::remove_dir_all(&target).unwrap();
+        destinations
+    }
+
+    #[test]
+    fn numbered_names() {
+        let dirs = ["packA/gimli", "packA/legolas"];
+        assert_eq!(
+            destinations("numbered", &dirs, &options()),
+            ["pf_portrait_000000", "pf_portrait_000001"]
+        );
+        let options = MoveOptions {
+            prefix: "",
+            ..options()
+        };
+        assert_eq!(
+            destinations("numbered-empty", &dirs, &options),
+            ["000000", "000001"]
+        );
+        // What `--prefix x --prefix-separator _` makes of the prefix.
+        let options = MoveOptions {
+            prefix: "x_",
+            ..options
+        };
+        assert_eq!(
+            destinations("numbered-separated", &dirs, &options),
+            ["x_000000", "x_000001"]
+        );
+    }
+
+    #[test]
+    fn original_path_names() {
+        let dirs = ["packA/gimli", "packB/gimli"];
+        let options = MoveOptions {
+            keep_original_path: true,
+            prefix: "",
+            ..options()
+        };
+        assert_eq!(
+            destinations("original-empty", &dirs, &options),
+            ["packA_gimli", "packB_gimli"]
+        );
+        let options = MoveOptions {
+            prefix: "x_",
+            ..options
+        };
+        assert_eq!(
+            destinations("original-separated", &dirs, &options),
+            ["x_packA_gimli", "x_packB_gimli"]
+        );
+    }
+
+    #[test]
+    fn sanitized_illegal_chars() {
+        let name = OsStr::new("a:b?c");
+        let expected = if cfg!(windows) {
+            "a_b_c"
+        } else if cfg!(target_os = "macos") {
+            "a_b?c"
+        } else {
+            "a:b?c"
+        };
+        assert_eq!(sanitized(name, "_", false), OsStr::new(expected));
+        assert_eq!(sanitized(name, "_", true), OsStr::new("a_b_c"));
+    }
+
+    #[test]
+    fn sanitized_reserved_names() {
+        let expected = if cfg!(windows) { "_CON" } else { "CON" };
+        assert_eq!(
+            sanitized(OsStr::new("CON"), "_", false),
+            OsStr::new(expected)
+        );
+        assert_eq!(
+            sanitized(OsStr::new("con.png"), "_", true),
+            OsStr::new("_con.png")
+        );
+        assert_eq!(
+            sanitized(OsStr::new("CONAN"), "_", true),
+            OsStr::new("CONAN")
+        );
+        assert_eq!(
+            sanitized(OsStr::new("gimli. "), "_", true),
+            OsStr::new("gimli__")
+        );
+    }
+
+    #[test]
+    fn stripped_once_or_repeatedly() {
+        let name = OsStr::new("pf_pf_gimli");
+        assert_eq!(OriginalFileName::stripped(name, "pf_", false), "pf_gimli");
+        assert_eq!(OriginalFileName::stripped(name, "pf_", true), "gimli");
+        assert_eq!(OriginalFileName::stripped(name, "", true), "pf_pf_gimli");
+        let options = MoveOptions {
+            prefix: "pf_",
+            keep_original_path: true,
+            ..options()
+        };
+        assert_eq!(
+            destinations("strip-once", &["pf_pf_gimli"], &options),
+            ["pf_pf_gimli"]
+        );
+        let options = MoveOptions {
+            strip_prefix_repeat: true,
+            ..options
+        };
+        assert_eq!(
+            destinations("strip-repeat", &["pf_pf_gimli"], &options),
+            ["pf_gimli"]
+        );
+    }
+
+    #[test]
+    fn stripped_ignoring_ascii_case() {
+        let name = OsStr::new("PF_gimli");
+        assert_eq!(OriginalFileName::stripped(name, "pf_", false), "gimli");
+        assert_eq!(OriginalFileName::stripped(name, "Pf_", false), "gimli");
+        let name = OsStr::new("PF_pf_Pf_gimli");
+        assert_eq!(OriginalFileName::stripped(name, "pf_", true), "gimli");
+    }
+
+    #[test]
+    fn stripped_non_ascii() {
+        let stripped =
+            |name: &str, prefix: &str| OriginalFileName::stripped(OsStr::new(name), prefix, true);
+        assert_eq!(stripped("élfe_gimli", "élfe_"), "gimli");
+        assert_eq!(stripped("élfe_élfe_gimli", "élfe_"), "gimli");
+        // Only ASCII case is ignored.
+        assert_eq!(stripped("ÉLFE_gimli", "élfe_"), "ÉLFE_gimli");
+        assert_eq!(stripped("pf_ñandú", "PF_"), "ñandú");
+        assert_eq!(stripped("ギムリ", "ギ"), "ムリ");
+        // A prefix that is the start of a longer character.
+        assert_eq!(stripped("é", "e"), "é");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stripped_non_utf8() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let name = OsString::from_vec(vec![b'p', b'f', b'_', 0xff, b'g']);
+        let stripped = OriginalFileName::stripped(&name, "pf_", false);
+        assert_eq!(stripped.as_bytes(), [0xff, b'g']);
+        let name = OsString::from_vec(vec![0xc3, b'g']);
+        assert_eq!(OriginalFileName::stripped(&name, "é", false), name);
+    }
+
+    #[test]
+    fn collapse_redundant_components() {
+        let root = Path::new("downloads");
+        let original = |dir: &str, collapse_redundant| {
+            let options = MoveOptions {
+                collapse_redundant,
+                ..options()
+            };
+            let dir = root.join(dir);
+            let name = OriginalFileName::new(&options, 1, &dir).unwrap();
+            name.as_ref().joined("/").to_string_lossy().into_owned()
+        };
+        assert_eq!(original("Pack/Pack/gimli", false), "Pack/Pack/gimli");
+        assert_eq!(original("Pack/Pack/gimli", true), "Pack/gimli");
+        assert_eq!(original("Pack/pack/PACK/gimli", true), "Pack/gimli");
+        assert_eq!(original("Pack/gimli/Gimli", true), "Pack/Gimli");
+        assert_eq!(
+            original("Pack/Elves/Pack/gimli", true),
+            "Pack/Elves/Pack/gimli"
+        );
+        let options = MoveOptions {
+            keep_original_path: true,
+            collapse_redundant: true,
+            ..options()
+        };
+        assert_eq!(
+            destinations("collapse", &["Pack/Pack/gimli"], &options),
+            ["pf_portrait_Pack_gimli"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let name = OsString::from_vec(vec![0x66, 0xff]);
+        let dir = scratch("non-utf8");
+        let (root, target) = (dir.join("downloads"), dir.join("portraits"));
+        let src = root.join("packA").join(&name);
+        std::fs::create_dir_all(&src).unwrap();
+        std::fs::create_dir(&target).unwrap();
+        let options = MoveOptions {
+            keep_original_path: true,
+            ..options()
+        };
+        let dirs = [src.clone()];
+        let moves = Move::with_dirs(&root, &dirs, &target, &options).unwrap();
+        let dst = moves.iter().next().unwrap().1.unwrap().to_path_buf();
+        assert_eq!(
+            dst.file_name().unwrap().as_bytes(),
+            b"pf_portrait_packA_f\xff"
+        );
+
+        let log = dir.join("undo.jsonl");
+        let mut undo_log = crate::undo::UndoLog::open(&log).unwrap();
+        undo_log.record(crate::Operation::Move, &src, Some(&dst));
+        move_portrait(&src, &dst, true).unwrap();
+        assert!(dst.is_dir() && !src.exists());
+        drop(undo_log);
+        // Written as the bytes of the paths, which are no strings.
+        assert!(std::fs::read_to_string(&log).unwrap().contains(",102,255]"));
+        let undo = crate::undo::undo(&log, false).unwrap();
+        assert!(undo.irreversible.is_empty());
+        assert!(src.is_dir() && !dst.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn move_across_devices_keeps_mtimes() {
+        let dir = scratch("across-devices");
+        let (src, dst) = (dir.join("gimli"), dir.join("pf_portrait_000000"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("Small.png"), b"small").unwrap();
+        std::fs::write(src.join("sub/notes.txt"), b"notes").unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        for path in ["Small.png", "sub/notes.txt", "sub", ""] {
+            filetime::set_file_mtime(src.join(path), mtime).unwrap();
+        }
+        move_across_devices(&src, &dst, true).unwrap();
+        assert!(!src.exists());
+        for path in ["Small.png", "sub/notes.txt", "sub", ""] {
+            let metadata = std::fs::metadata(dst.join(path)).unwrap();
+            assert_eq!(
+                filetime::FileTime::from_last_modification_time(&metadata),
+                mtime,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(std::fs::read(dst.join("sub/notes.txt")).unwrap(), b"notes");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths() {
+        use std::os::windows::ffi::OsStrExt;
+        let dir = scratch("long-path");
+        let short = dir.join("gimli");
+        assert_eq!(&*long_path(&short), short.as_path());
+        // Too long for a directory, though not for a file.
+        let length = dir.as_os_str().encode_wide().count() + 1;
+        let long = dir.join("a".repeat(MAX_DIR_PATH + 2 - length));
+        assert!(long.as_os_str().encode_wide().count() < 260);
+        let prefixed = long_path(&long);
+        assert!(prefixed
+            .as_os_str()
+            .as_encoded_bytes()
+            .starts_with(br"\\?\"));
+        std::fs::create_dir(&prefixed).unwrap();
+        std::fs::write(prefixed.join("Small.png"), b"small").unwrap();
+        assert!(prefixed.join("Small.png").is_file());
+        std::fs::remove_dir_all(long_path(&dir)).unwrap();
+    }
+
+    fn portrait_dir(required_files: &[&str]) -> PortraitDir {
+        PortraitDir {
+            required_files: required_files.iter().map(|file| file.to_string()).collect(),
+            case_sensitive: false,
+            extensions: vec!["png".to_owned()],
+            min_image_size: None,
+        }
+    }
+
+    #[test]
+    fn clean_move_and_restore() {
+        let dir = scratch("clean-move");
+        let (src, dst) = (dir.join("gimli"), dir.join("pf_portrait_000000"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        for file in ["Small.png", "Medium.png", "readme.txt"] {
+            std::fs::write(src.join(file), file).unwrap();
+        }
+        let portrait_dir = portrait_dir(&["Small.png", "Medium.png"]);
+        clean_move_portrait(&src, &dst, &portrait_dir, CleanMove::Subfolder, true).unwrap();
+        assert!(dst.join("Small.png").is_file() && dst.join("extras/readme.txt").is_file());
+        // The subdirectory keeps the original directory.
+        assert!(src.join("sub").is_dir() && !src.join("Small.png").exists());
+
+        restore_clean_move(&dst, &src).unwrap();
+        assert!(!dst.exists());
+        for file in ["Small.png", "Medium.png", "readme.txt"] {
+            assert_eq!(std::fs::read_to_string(src.join(file)).unwrap(), file);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clean_move_rolls_back() {
+        let dir = scratch("clean-move-rollback");
+        let (src, dst) = (dir.join("gimli"), dir.join("pf_portrait_000000"));
+        std::fs::create_dir(&src).unwrap();
+        // A required file named like the extras subdirectory, which then cannot be created.
+        for file in ["Small.png", "extras", "readme.txt"] {
+            std::fs::write(src.join(file), file).unwrap();
+        }
+        let portrait_dir = portrait_dir(&["Small.png", "extras"]);
+        let err =
+            clean_move_portrait(&src, &dst, &portrait_dir, CleanMove::Subfolder, true).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(!dst.exists());
+        for file in ["Small.png", "extras", "readme.txt"] {
+            assert_eq!(std::fs::read_to_string(src.join(file)).unwrap(), file);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fit_long_names() {
+        let options = MoveOptions {
+            max_name_length: 32,
+            ..options()
+        };
+        let short = OsString::from("pf_portrait_gimli");
+        assert_eq!(Move::fit(short.clone(), &options), short);
+        let long = |name: &str| OsString::from(format!("pf_portrait_{}_gimli", name.repeat(10)));
+        let fitted = Move::fit(long("ギムリ"), &options);
+        let fitted = fitted.to_str().unwrap();
+        assert!(fitted.len() <= 32, "{}", fitted);
+        assert!(fitted.starts_with("pf_portrait_") && fitted.contains('~'));
+        assert_ne!(fitted, Move::fit(long("ギリム"), &options));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fit_non_utf8_names() {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        let options = MoveOptions {
+            max_name_length: 32,
+            ..options()
+        };
+        let mut bytes = b"pf_portrait_".to_vec();
+        bytes.extend([0xff, 0xfe].repeat(20));
+        let long = OsString::from_vec(bytes.clone());
+        let fitted = Move::fit(long, &options);
+        assert!(fitted.len() <= 32);
+        // Invalid sequences are not split, so a run of them is cut out whole.
+        assert!(fitted.as_bytes().starts_with(b"pf_portrait_~_"));
+        // The hash is of the bytes, not of their lossy replacement.
+        bytes[20] = 0xfd;
+        assert_ne!(fitted, Move::fit(OsString::from_vec(bytes), &options));
+    }
+
+    #[test]
+    fn pending_target() {
+        let target = scratch("pending").join("new");
+        let root = Path::new("downloads");
+        let dirs = [root.join("packA/gimli")];
+        assert!(Move::with_dirs(root, &dirs, &target, &options()).is_err());
+        let options = MoveOptions {
+            target_pending: true,
+            ..options()
+        };
+        let moves = Move::with_dirs(root, &dirs, &target, &options).unwrap();
+        assert_eq!(
+            moves.iter().next().unwrap().1,
+            Some(target.join("pf_portrait_000000").as_path())
+        );
+        std::fs::remove_dir_all(target.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn slots() {
+        let target = scratch("slots");
+        std::fs::create_dir(target.join("taken")).unwrap();
+        let slot_map = HashMap::from([
+            ("gimli".to_owned(), "dwarf".to_owned()),
+            ("legolas".to_owned(), "taken".to_owned()),
+        ]);
+        let options = MoveOptions {
+            slot_map: &slot_map,
+            ..options()
+        };
+        let root = Path::new("downloads");
+        let dirs = ["packA/Gimli", "packA/legolas", "packA/aragorn"].map(|dir| root.join(dir));
+        let moves = Move::with_dirs(root, &dirs, &target, &options).unwrap();
+        let destinations: Vec<Option<&Path>> = moves.iter().map(|(_, dst)| dst).collect();
+        // The existing slot only skips its dir, which does not take the first number either.
+        assert_eq!(
+            destinations,
+            [
+                Some(target.join("dwarf").as_path()),
+                None,
+                Some(target.join("pf_portrait_000000").as_path())
+            ]
+        );
+        let dirs = ["packA/gimli", "packB/gimli"].map(|dir| root.join(dir));
+        assert!(Move::with_dirs(root, &dirs, &target, &options).is_err());
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn convert_keeps_other_files() {
+        let dir = scratch("convert");
+        let (src, dst) = (dir.join("gimli"), dir.join("pf_portrait_000000"));
+        std::fs::create_dir_all(src.join("art")).unwrap();
+        image::RgbImage::new(2, 2)
+            .save(src.join("Small.jpg"))
+            .unwrap();
+        image::RgbImage::new(2, 2)
+            .save(src.join("Medium.png"))
+            .unwrap();
+        std::fs::write(src.join("readme.txt"), b"readme").unwrap();
+        std::fs::write(src.join("art/sketch.txt"), b"sketch").unwrap();
+        let portrait_dir = PortraitDir {
+            extensions: vec!["png".to_owned(), "jpg".to_owned()],
+            ..portrait_dir(&["Small.png", "Medium.png"])
+        };
+        convert_portrait(&src, &dst, &portrait_dir, false).unwrap();
+        assert!(!src.exists() && !dst.join("Small.jpg").exists());
+        let small = std::fs::read(dst.join("Small.png")).unwrap();
+        assert_eq!(
+            image::guess_format(&small).unwrap(),
+            image::ImageFormat::Png
+        );
+        assert_eq!(std::fs::read(dst.join("readme.txt")).unwrap(), b"readme");
+        assert_eq!(
+            std::fs::read(dst.join("art/sketch.txt")).unwrap(),
+            b"sketch"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_cleans_up() {
+        let dir = scratch("copy-partial");
+        let (src, dst) = (dir.join("gimli"), dir.join("pf_portrait_000000"));
+        // A directory in place of a required file, which cannot be copied.
+        std::fs::create_dir_all(src.join("Medium.png")).unwrap();
+        std::fs::write(src.join("Small.png"), b"small").unwrap();
+        let portrait_dir = portrait_dir(&["Small.png", "Medium.png"]);
+        assert!(copy_portrait(&src, &dst, &portrait_dir, true).is_err());
+        assert!(!dst.exists() && src.join("Small.png").is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
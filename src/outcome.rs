@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+
+/// What was done, or attempted, to a directory.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Move,
+    /// Moving only the files of a directory into a new one, see `CleanMove`.
+    CleanMove,
+    Copy,
+    /// Extracting a portrait directory from an archive.
+    Extract,
+    /// Writing the files of a directory as PNGs, erasing it unless copying.
+    Convert,
+    Erase,
+    EraseDuplicate,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Move => "move",
+            Self::CleanMove => "clean_move",
+            Self::Copy => "copy",
+            Self::Extract => "extract",
+            Self::Convert => "convert",
+            Self::Erase => "erase",
+            Self::EraseDuplicate => "erase_duplicate",
+        })
+    }
+}
+
+/// The result of one operation on one directory.
+#[derive(Debug)]
+pub enum OperationOutcome {
+    /// The operation succeeded.
+    Done {
+        operation: Operation,
+        src: PathBuf,
+        dst: Option<PathBuf>,
+    },
+    /// The operation would have been performed, if this were not a dry run.
+    Planned {
+        operation: Operation,
+        src: PathBuf,
+        dst: Option<PathBuf>,
+    },
+    /// No destination could be found for `src`, or its erasure was not confirmed, so it was
+    /// left alone.
+    Skipped { operation: Operation, src: PathBuf },
+    /// The operation was attempted, but failed.
+    Failed {
+        operation: Operation,
+        src: PathBuf,
+        dst: Option<PathBuf>,
+        error: std::io::Error,
+    },
+}
+
+impl OperationOutcome {
+    pub(crate) fn new(
+        operation: Operation,
+        src: PathBuf,
+        dst: Option<PathBuf>,
+        result: std::io::Result<()>,
+    ) -> Self {
+        match result {
+            Ok(()) => Self::Done {
+                operation,
+                src,
+                dst,
+            },
+            Err(error) => Self::Failed {
+                operation,
+                src,
+                dst,
+                error,
+            },
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        match self {
+            Self::Done { operation, .. }
+            | Self::Planned { operation, .. }
+            | Self::Skipped { operation, .. }
+            | Self::Failed { operation, .. } => *operation,
+        }
+    }
+
+    /// Whether the operation succeeded, or would have been attempted in a dry run.
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Done { .. } | Self::Planned { .. })
+    }
+
+    /// Whether the operation failed for lack of permissions.
+    pub fn is_permission_denied(&self) -> bool {
+        matches!(self, Self::Failed { error, .. } if is_permission_denied(error))
+    }
+}
+
+pub(crate) fn is_permission_denied(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::PermissionDenied
+}
+
+/// Why an operation failed, telling the permission errors from the others since they are
+/// fixed differently.
+struct Reason<'a>(&'a std::io::Error);
+
+impl fmt::Display for Reason<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_permission_denied(self.0) {
+            f.write_str("permission denied, check the owner and permissions of the files")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl fmt::Display for OperationOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Done {
+                operation,
+                src,
+                dst,
+            } => match (operation, dst) {
+                (Operation::Move, Some(dst)) => {
+                    write!(f, "Renamed {} to {}", src.display(), dst.display())
+                }
+                (Operation::CleanMove, Some(dst)) => {
+                    write!(
+                        f,
+                        "Moved the files of {} to {}",
+                        src.display(),
+                        dst.display()
+                    )
+                }
+                (Operation::Copy, Some(dst)) => {
+                    write!(f, "Copied {} to {}", src.display(), dst.display())
+                }
+                (Operation::Extract, Some(dst)) => {
+                    write!(f, "Extracted {} to {}", src.display(), dst.display())
+                }
+                (Operation::Convert, Some(dst)) => {
+                    write!(f, "Converted {} to {}", src.display(), dst.display())
+                }
+                (_, Some(dst)) => write!(f, "Trashed {} to {}", src.display(), dst.display()),
+                (Operation::EraseDuplicate, _) => write!(f, "Erased duplicate {}", src.display()),
+                _ => write!(f, "Erased {}", src.display()),
+            },
+            Self::Planned {
+                operation,
+                src,
+                dst,
+            } => match (operation, dst) {
+                (Operation::Move, Some(dst)) => {
+                    write!(f, "Would rename {} to {}", src.display(), dst.display())
+                }
+                (Operation::CleanMove, Some(dst)) => write!(
+                    f,
+                    "Would move the files of {} to {}",
+                    src.display(),
+                    dst.display()
+                ),
+                (Operation::Copy, Some(dst)) => {
+                    write!(f, "Would copy {} to {}", src.display(), dst.display())
+                }
+                (Operation::Extract, Some(dst)) => {
+                    write!(f, "Would extract {} to {}", src.display(), dst.display())
+                }
+                (Operation::Convert, Some(dst)) => {
+                    write!(f, "Would convert {} to {}", src.display(), dst.display())
+                }
+                (_, Some(dst)) => {
+                    write!(f, "Would trash {} to {}", src.display(), dst.display())
+                }
+                (Operation::EraseDuplicate, _) => {
+                    write!(f, "Would erase duplicate {}", src.display())
+                }
+                _ => write!(f, "Would erase {}", src.display()),
+            },
+            Self::Skipped { operation, src } => match operation {
+                Operation::EraseDuplicate => {
+                    write!(f, "Left duplicate {} in place", src.display())
+                }
+                Operation::Erase => write!(f, "Left {} in place", src.display()),
+                _ => write!(f, "Unable to rename {}", src.display()),
+            },
+            Self::Failed {
+                operation,
+                src,
+                dst,
+                error,
+            } => match (operation, dst) {
+                (Operation::Move, Some(dst)) => write!(
+                    f,
+                    "Unable to rename {} to {}: {}",
+                    src.display(),
+                    dst.display(),
+                    Reason(error)
+                ),
+                (Operation::CleanMove, Some(dst)) => write!(
+                    f,
+                    "Unable to move the files of {} to {}: {}",
+                    src.display(),
+                    dst.display(),
+                    Reason(error)
+                ),
+                (Operation::Copy, Some(dst)) => write!(
+                    f,
+                    "Unable to copy {} to {}: {}",
+                    src.display(),
+                    dst.display(),
+                    Reason(error)
+                ),
+                (Operation::Extract, Some(dst)) => write!(
+                    f,
+                    "Unable to extract {} to {}: {}",
+                    src.display(),
+                    dst.display(),
+                    Reason(error)
+                ),
+                (Operation::Convert, Some(dst)) => write!(
+                    f,
+                    "Unable to convert {} to {}: {}",
+                    src.display(),
+                    dst.display(),
+                    Reason(error)
+                ),
+                (_, Some(dst)) => write!(
+                    f,
+                    "Failed to trash {} to {}: {}",
+                    src.display(),
+                    dst.display(),
+                    Reason(error)
+                ),
+                (Operation::EraseDuplicate, _) => {
+                    write!(
+                        f,
+                        "Failed to erase duplicate {}: {}",
+                        src.display(),
+                        Reason(error)
+                    )
+                }
+                _ => write!(f, "Failed to erase {}: {}", src.display(), Reason(error)),
+            },
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::undo::UndoLog;
+use crate::{moving, CleanMove, Operation, OperationOutcome, PortraitDir};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// One move of a plan written by `--plan`, to be performed by `--apply`.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct PlanEntry {
+    pub operation: Operation,
+    #[serde(with = "crate::encoded_path")]
+    pub src: PathBuf,
+    #[serde(with = "crate::encoded_path")]
+    pub dst: PathBuf,
+}
+
+/// Writes the planned moves of `outcomes` to `path` as a JSON array, to be reviewed, edited
+/// and applied.
+pub(crate) fn write_plan(path: &Path, outcomes: &[OperationOutcome]) -> anyhow::Result<usize> {
+    let entries: Vec<PlanEntry> = outcomes
+        .iter()
+        .filter_map(|outcome| match outcome {
+            OperationOutcome::Planned {
+                operation,
+                src,
+                dst: Some(dst),
+            } => Some(PlanEntry {
+                operation: *operation,
+                src: src.clone(),
+                dst: dst.clone(),
+            }),
+            _ => None,
+        })
+        .collect();
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(&mut file, &entries)?;
+    writeln!(file)?;
+    file.flush()?;
+    Ok(entries.len())
+}
+
+/// Performs the moves of the plan at `path`, refusing those whose source is gone or whose
+/// destination is taken, including by an earlier entry of the plan. The clean moves put the
+/// other files as `extras` says.
+pub(crate) fn apply_plan(
+    path: &Path,
+    portrait_dir: &PortraitDir,
+    extras: CleanMove,
+    copy: bool,
+    dry_run: bool,
+    preserve_times: bool,
+    undo_log: &mut UndoLog,
+) -> anyhow::Result<Vec<OperationOutcome>> {
+    let entries: Vec<PlanEntry> =
+        serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    let mut taken = HashSet::new();
+    let mut outcomes = Vec::with_capacity(entries.len());
+    for PlanEntry {
+        operation,
+        src,
+        dst,
+    } in entries
+    {
+        let refused = match operation {
+            Operation::Move | Operation::CleanMove | Operation::Copy | Operation::Convert => {
+                if !src.is_dir() {
+                    Some(std::io::Error::from(std::io::ErrorKind::NotFound))
+                } else if dst.exists() || !taken.insert(dst.clone()) {
+                    Some(std::io::Error::from(std::io::ErrorKind::AlreadyExists))
+                } else {
+                    None
+                }
+            }
+            _ => Some(std::io::Error::other(format!(
+                "{} cannot be applied from a plan",
+                operation
+            ))),
+        };
+        if let Some(error) = refused {
+            outcomes.push(OperationOutcome::Failed {
+                operation,
+                src,
+                dst: Some(dst),
+                error,
+            });
+            continue;
+        }
+        if dry_run {
+            outcomes.push(OperationOutcome::Planned {
+                operation,
+                src,
+                dst: Some(dst),
+            });
+            continue;
+        }
+        undo_log.record(operation, &src, Some(&dst));
+        let result = dst
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| match operation {
+                Operation::Convert => moving::convert_portrait(&src, &dst, portrait_dir, copy),
+                Operation::Copy => moving::copy_portrait(&src, &dst, portrait_dir, preserve_times),
+                Operation::CleanMove => {
+                    moving::clean_move_portrait(&src, &dst, portrait_dir, extras, preserve_times)
+                }
+                _ => moving::move_portrait(&src, &dst, preserve_times),
+            });
+        outcomes.push(OperationOutcome::new(operation, src, Some(dst), result));
+    }
+    Ok(outcomes)
+}
@@ -0,0 +1,73 @@
+use std::io::Read;
+use std::path::Path;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// The text of the first of `keys` found in the uncompressed tEXt and iTXt chunks of
+/// `file`.
+pub(crate) fn png_text(file: &Path, keys: &[&str]) -> Option<String> {
+    let bytes = std::fs::read(file).ok()?;
+    if bytes.get(..8)? != PNG_SIGNATURE {
+        return None;
+    }
+    let mut texts: Vec<(String, String)> = Vec::new();
+    let mut chunks = &bytes[8..];
+    while chunks.len() >= 12 {
+        let length = u32::from_be_bytes(chunks[..4].try_into().ok()?) as usize;
+        let kind = &chunks[4..8];
+        let data = chunks.get(8..8usize.checked_add(length)?)?;
+        match kind {
+            b"tEXt" => {
+                if let Some((key, text)) = split_at_nul(data) {
+                    // tEXt is Latin-1, whose code points are those of its bytes.
+                    let latin1 = |bytes: &[u8]| bytes.iter().map(|&b| b as char).collect();
+                    texts.push((latin1(key), latin1(text)));
+                }
+            }
+            b"iTXt" => {
+                // The keyword, the compression flag and method, the language tag and the
+                // translated keyword come before the UTF-8 text.
+                let text = split_at_nul(data).and_then(|(key, rest)| match rest {
+                    [0, _, rest @ ..] => {
+                        let (_, rest) = split_at_nul(rest)?;
+                        let (_, text) = split_at_nul(rest)?;
+                        Some((key, std::str::from_utf8(text).ok()?))
+                    }
+                    _ => None,
+                });
+                if let Some((key, text)) = text {
+                    texts.push((String::from_utf8_lossy(key).into_owned(), text.to_owned()));
+                }
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        chunks = &chunks[(12 + length).min(chunks.len())..];
+    }
+    keys.iter().find_map(|key| {
+        texts
+            .iter()
+            .find(|(found, _)| found == key)
+            .map(|(_, text)| text.clone())
+    })
+}
+
+fn split_at_nul(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let nul = bytes.iter().position(|&b| b == 0)?;
+    Some((&bytes[..nul], &bytes[nul + 1..]))
+}
+
+pub(crate) fn png_dimensions(file: &Path) -> Option<(u32, u32)> {
+    let mut file = std::fs::File::open(file).ok()?;
+    let mut header = [0u8; 24];
+    file.read_exact(&mut header).ok()?;
+    if header[..8] != PNG_SIGNATURE || &header[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(header[16..20].try_into().ok()?);
+    let height = u32::from_be_bytes(header[20..24].try_into().ok()?);
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some((width, height))
+}
@@ -0,0 +1,199 @@
+use crate::{Operation, OperationOutcome};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// The final counts of a run.
+#[derive(Debug, Default, Serialize)]
+pub struct Counts {
+    pub renamed: usize,
+    pub failed_to_rename: usize,
+    pub erased_useless: usize,
+    pub erased_duplicates: usize,
+    pub erased_portrait_duplicates: usize,
+    pub trashed: usize,
+    pub skipped_invalid: usize,
+    pub wrong_dimensions: usize,
+    pub generated: usize,
+    pub skipped_hidden: usize,
+    pub skipped_installed: usize,
+    pub skipped_over_limit: usize,
+    pub stripped_extras: usize,
+    pub skipped_placeholders: usize,
+    pub reclaimed_bytes: u64,
+    pub inconsistent: usize,
+    pub blocklisted: usize,
+    pub not_included: usize,
+    pub incomplete: usize,
+    pub recent: usize,
+    pub wrong_aspect: usize,
+    pub casing_conflicts: usize,
+    pub fixed_casing: usize,
+    pub deferred: usize,
+    pub permission_denied: usize,
+}
+
+impl Counts {
+    /// The number of directories that were left out of the run for any reason but being
+    /// hidden or missing from the include list, which were asked for.
+    pub fn skipped_total(&self) -> usize {
+        self.skipped_invalid
+            + self.wrong_dimensions
+            + self.skipped_installed
+            + self.skipped_over_limit
+            + self.skipped_placeholders
+            + self.inconsistent
+            + self.blocklisted
+            + self.incomplete
+            + self.recent
+            + self.wrong_aspect
+            + self.deferred
+            + self.permission_denied
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Moved {
+    #[serde(serialize_with = "crate::encoded_path::serialize")]
+    pub src: PathBuf,
+    #[serde(serialize_with = "crate::encoded_path::serialize")]
+    pub dst: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Failed {
+    pub operation: String,
+    #[serde(serialize_with = "crate::encoded_path::serialize")]
+    pub src: PathBuf,
+    #[serde(serialize_with = "crate::encoded_path::option::serialize")]
+    pub dst: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+/// Everything that was done (or would have been done, in a dry run) during a run.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub dry_run: bool,
+    pub moved: Vec<Moved>,
+    #[serde(serialize_with = "crate::encoded_path::vec::serialize")]
+    pub erased_duplicates: Vec<PathBuf>,
+    #[serde(serialize_with = "crate::encoded_path::vec::serialize")]
+    pub erased_useless: Vec<PathBuf>,
+    pub trashed: Vec<Moved>,
+    pub failed: Vec<Failed>,
+    pub counts: Counts,
+}
+
+impl Report {
+    pub fn new(dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            ..Self::default()
+        }
+    }
+
+    /// Records the outcomes of `run`, `cleanup` or the duplicates of `prepare`.
+    pub fn add(&mut self, outcomes: &[OperationOutcome]) {
+        for outcome in outcomes {
+            match outcome {
+                OperationOutcome::Done {
+                    operation,
+                    src,
+                    dst,
+                }
+                | OperationOutcome::Planned {
+                    operation,
+                    src,
+                    dst,
+                } => match (operation, dst) {
+                    (
+                        Operation::Move
+                        | Operation::CleanMove
+                        | Operation::Copy
+                        | Operation::Extract
+                        | Operation::Convert,
+                        Some(dst),
+                    ) => {
+                        self.counts.renamed += 1;
+                        self.moved.push(Moved {
+                            src: src.clone(),
+                            dst: dst.clone(),
+                        });
+                    }
+                    (Operation::Erase | Operation::EraseDuplicate, Some(dst)) => {
+                        self.counts.trashed += 1;
+                        self.trashed.push(Moved {
+                            src: src.clone(),
+                            dst: dst.clone(),
+                        });
+                    }
+                    (Operation::EraseDuplicate, _) => {
+                        self.counts.erased_duplicates += 1;
+                        self.erased_duplicates.push(src.clone());
+                    }
+                    _ => {
+                        self.counts.erased_useless += 1;
+                        self.erased_useless.push(src.clone());
+                    }
+                },
+                OperationOutcome::Skipped { operation, src } => {
+                    self.add_failure(*operation, src, None, None)
+                }
+                OperationOutcome::Failed {
+                    operation,
+                    src,
+                    dst,
+                    error,
+                } => self.add_failure(*operation, src, dst.as_deref(), Some(error)),
+            }
+        }
+    }
+
+    /// Records the outcomes of `dedupe_portraits`, counting them apart from the duplicates
+    /// in the downloads directory.
+    pub fn add_portrait_duplicates(&mut self, outcomes: &[OperationOutcome]) {
+        let erased_duplicates = self.counts.erased_duplicates;
+        self.add(outcomes);
+        self.counts.erased_portrait_duplicates = self.counts.erased_duplicates - erased_duplicates;
+        self.counts.erased_duplicates = erased_duplicates;
+    }
+
+    fn add_failure(
+        &mut self,
+        operation: Operation,
+        src: &Path,
+        dst: Option<&Path>,
+        error: Option<&std::io::Error>,
+    ) {
+        if matches!(
+            operation,
+            Operation::Move
+                | Operation::CleanMove
+                | Operation::Copy
+                | Operation::Extract
+                | Operation::Convert
+        ) {
+            self.counts.failed_to_rename += 1;
+        }
+        if error.is_some_and(crate::outcome::is_permission_denied) {
+            self.counts.permission_denied += 1;
+        }
+        self.failed.push(Failed {
+            operation: format!("{:?}", operation),
+            src: src.to_path_buf(),
+            dst: dst.map(Path::to_path_buf),
+            error: error.map(std::io::Error::to_string),
+        });
+    }
+
+    /// Writes the report as JSON to `path`, or to stdout if `path` is "-".
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        if path == Path::new("-") {
+            serde_json::to_writer_pretty(std::io::stdout().lock(), self)?;
+            println!();
+        } else {
+            let file = std::fs::File::create(path)?;
+            serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)?;
+        }
+        Ok(())
+    }
+}
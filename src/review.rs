@@ -0,0 +1,97 @@
+use crate::PortraitDir;
+use anyhow::anyhow;
+use std::fmt::Write as _;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+/// The size in pixels to which the previews are shrunk, each character showing two of them
+/// one above the other.
+const PREVIEW_WIDTH: u32 = 38;
+const PREVIEW_HEIGHT: u32 = 44;
+
+/// Shows every directory of `moves` with a destination, along with a preview of its
+/// "Medium.png" in any of the accepted formats, and asks whether to move it. Everything is
+/// written to stderr, which keeps stdout for the output, e.g. with `--progress-format ndjson`.
+/// Returns whether each is approved, the directories without a destination being approved to
+/// report them.
+pub(crate) fn review(
+    moves: &[(&Path, Option<&Path>)],
+    portrait_dir: &PortraitDir,
+) -> anyhow::Result<Vec<bool>> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!("Cannot review, stdin is not a terminal"));
+    }
+    let to_review = moves.iter().filter(|(_, dst)| dst.is_some()).count();
+    let mut approved = Vec::with_capacity(moves.len());
+    let mut answer_all = None;
+    let mut reviewed = 0;
+    for (src, dst) in moves {
+        let Some(dst) = dst else {
+            approved.push(true);
+            continue;
+        };
+        if let Some(answer) = answer_all {
+            approved.push(answer);
+            continue;
+        }
+        reviewed += 1;
+        eprintln!();
+        if std::io::stderr().is_terminal() {
+            match portrait_dir
+                .find(src, "Medium.png")
+                .and_then(|medium| preview(&medium))
+            {
+                Some(preview) => eprint!("{}", preview),
+                None => eprintln!("(no preview)"),
+            }
+        }
+        eprintln!("{}/{}: {}", reviewed, to_review, src.display());
+        eprintln!("  to {}", dst.display());
+        let answer = loop {
+            eprint!("Move it? [y]es, [n]o, [a]ll remaining, [q]uit: ");
+            let mut answer = String::new();
+            std::io::stderr().flush()?;
+            // The end of the input rejects the remaining directories, like quitting.
+            if std::io::stdin().read_line(&mut answer)? == 0 {
+                break 'q';
+            }
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "y" => break 'y',
+                "n" => break 'n',
+                "a" => break 'a',
+                "q" => break 'q',
+                _ => {}
+            }
+        };
+        approved.push(matches!(answer, 'y' | 'a'));
+        match answer {
+            'a' => answer_all = Some(true),
+            'q' => answer_all = Some(false),
+            _ => {}
+        }
+    }
+    Ok(approved)
+}
+
+/// `image` shrunk and drawn with upper half blocks in 24-bit color, the foreground being the
+/// upper pixel and the background the lower one.
+fn preview(image: &Path) -> Option<String> {
+    let image = image::open(image)
+        .ok()?
+        .thumbnail(PREVIEW_WIDTH, PREVIEW_HEIGHT)
+        .into_rgb8();
+    let mut preview = String::new();
+    for y in (0..image.height()).step_by(2) {
+        for x in 0..image.width() {
+            let [r, g, b] = image.get_pixel(x, y).0;
+            write!(preview, "\x1b[38;2;{};{};{}m", r, g, b).ok()?;
+            if y + 1 < image.height() {
+                let [r, g, b] = image.get_pixel(x, y + 1).0;
+                write!(preview, "\x1b[48;2;{};{};{}m", r, g, b).ok()?;
+            }
+            preview.push('▀');
+        }
+        preview.push_str("\x1b[0m\n");
+    }
+    Some(preview)
+}
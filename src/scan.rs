@@ -0,0 +1,1805 @@
+use crate::checksum::{Checksum, ChecksumCache, ColorHistogram, PerceptualHash, PerceptualIndex};
+use crate::png::png_dimensions;
+use crate::undo::UndoLog;
+use crate::{archive, moving, progress};
+use crate::{
+    Bucket, DedupOn, EventStream, HashAlgorithm, Keep, Operation, OperationOutcome, SortOrder,
+    EXPECTED_DIMENSIONS, PORTRAIT_FILES,
+};
+use globset::GlobSet;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const DIMENSIONS_TOLERANCE_PERCENT: u32 = 10;
+/// Below this `ColorHistogram::similarity`, two images of a directory are taken for different
+/// artwork.
+const MIN_CONSISTENCY: f32 = 0.5;
+
+/// Decides which of the visited directories a `Scan` collects.
+pub trait ScanDir {
+    fn include(&self, path: &Path) -> bool;
+
+    /// What decides whether `path` is included, logged with -vv while scanning.
+    fn describe(&self, _path: &Path) -> Option<String> {
+        None
+    }
+}
+
+/// Whether `scan_dir` includes `path`, logging the decision at the trace level.
+fn included<T: ScanDir>(scan_dir: &T, path: &Path) -> bool {
+    let included = scan_dir.include(path);
+    if log::log_enabled!(log::Level::Trace) {
+        let verdict = if included { "Included" } else { "Not included" };
+        match scan_dir.describe(path) {
+            Some(description) => log::trace!("{} {}: {}", verdict, path.display(), description),
+            None => log::trace!("{} {}", verdict, path.display()),
+        }
+    }
+    included
+}
+
+/// Finds the files named `file_names` in `dir`, returning their actual paths. Unless
+/// `case_sensitive`, e.g. "small.png" is found for "Small.png", though an exact match wins.
+pub(crate) fn find_files<S: AsRef<str>>(
+    dir: &Path,
+    file_names: &[S],
+    case_sensitive: bool,
+) -> Option<Vec<PathBuf>> {
+    let alternatives: Vec<[&str; 1]> = file_names
+        .iter()
+        .map(|file_name| [file_name.as_ref()])
+        .collect();
+    find_any_files(dir, &alternatives, case_sensitive)
+}
+
+/// Like `find_files`, but finding for each entry of `alternatives` the first of its names
+/// that is in `dir`.
+pub(crate) fn find_any_files<A: AsRef<[S]>, S: AsRef<str>>(
+    dir: &Path,
+    alternatives: &[A],
+    case_sensitive: bool,
+) -> Option<Vec<PathBuf>> {
+    if case_sensitive {
+        return alternatives
+            .iter()
+            .map(|file_names| {
+                file_names
+                    .as_ref()
+                    .iter()
+                    .map(|file_name| dir.join(file_name.as_ref()))
+                    .find(|file| file.exists())
+            })
+            .collect();
+    }
+    let entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    alternatives
+        .iter()
+        .map(|file_names| {
+            file_names.as_ref().iter().find_map(|file_name| {
+                let file_name = file_name.as_ref();
+                let exact = dir.join(file_name);
+                if entries.contains(&exact) {
+                    return Some(exact);
+                }
+                entries
+                    .iter()
+                    .find(|entry| {
+                        entry
+                            .file_name()
+                            .and_then(OsStr::to_str)
+                            .is_some_and(|name| name.eq_ignore_ascii_case(file_name))
+                    })
+                    .cloned()
+            })
+        })
+        .collect()
+}
+
+/// A directory containing all of the required files, by default "Small.png", "Medium.png"
+/// and "Fulllength.png".
+pub struct PortraitDir {
+    pub required_files: Vec<String>,
+    /// Only accept the exact file names, rather than e.g. "small.png" too.
+    pub case_sensitive: bool,
+    /// The extensions with which the required ".png" files are accepted, in order of
+    /// preference, e.g. "jpg" for "Small.jpg".
+    pub extensions: Vec<String>,
+    /// Reject the directories whose required files are all smaller than this many bytes, such
+    /// as the 1x1 placeholders of some packs.
+    pub min_image_size: Option<u64>,
+}
+
+impl PortraitDir {
+    /// The actual paths of the required files in `dir`, in the order in which they are
+    /// required.
+    pub fn files(&self, dir: &Path) -> Option<Vec<PathBuf>> {
+        let alternatives: Vec<Vec<String>> = self
+            .required_files
+            .iter()
+            .map(|file_name| self.alternatives(file_name))
+            .collect();
+        find_any_files(dir, &alternatives, self.case_sensitive)
+    }
+
+    /// The actual path of `file_name` in `dir`, which need not be required, e.g. "Medium.jpg"
+    /// for "Medium.png".
+    pub fn find(&self, dir: &Path, file_name: &str) -> Option<PathBuf> {
+        find_any_files(dir, &[self.alternatives(file_name)], self.case_sensitive)?.pop()
+    }
+
+    /// The names accepted for the required file `file_name`: a ".png" one with each of the
+    /// extensions instead, and any other as is.
+    pub fn alternatives(&self, file_name: &str) -> Vec<String> {
+        match file_name.rsplit_once('.') {
+            Some((stem, extension)) if extension.eq_ignore_ascii_case("png") => self
+                .extensions
+                .iter()
+                .map(|extension| format!("{}.{}", stem, extension))
+                .collect(),
+            _ => vec![file_name.to_owned()],
+        }
+    }
+
+    /// The required files missing from `dir`.
+    pub fn missing(&self, dir: &Path) -> Vec<&str> {
+        self.required_files
+            .iter()
+            .filter(|file_name| self.find(dir, file_name).is_none())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The files in `dir` other than the required ones, e.g. a "readme.txt" or "Thumbs.db".
+    pub fn extras(&self, dir: &Path) -> Vec<PathBuf> {
+        let required = self.files(dir).unwrap_or_default();
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut extras: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| !file_type.is_dir()))
+            .map(|entry| entry.path())
+            .filter(|path| !required.contains(path))
+            .collect();
+        extras.sort();
+        extras
+    }
+}
+
+/// The files of a portrait directory that are not required.
+#[derive(Debug)]
+pub struct Extras {
+    pub dir: PathBuf,
+    pub files: Vec<PathBuf>,
+}
+
+impl ScanDir for PortraitDir {
+    fn include(&self, path: &Path) -> bool {
+        match (self.files(path), self.min_image_size) {
+            (Some(files), Some(min_image_size)) => !is_placeholder(&files, min_image_size),
+            (files, None) => files.is_some(),
+            (None, _) => false,
+        }
+    }
+
+    fn describe(&self, path: &Path) -> Option<String> {
+        let (mut found, mut missing) = (Vec::new(), Vec::new());
+        for file_name in &self.required_files {
+            match self.find(path, file_name) {
+                Some(file) => found.push(
+                    file.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+                None => missing.push(file_name.clone()),
+            }
+        }
+        let mut description = match (found.is_empty(), missing.is_empty()) {
+            (true, _) => format!("missing {}", missing.join(", ")),
+            (false, true) => format!("found {}", found.join(", ")),
+            (false, false) => format!("found {}, missing {}", found.join(", "), missing.join(", ")),
+        };
+        if let (Some(files), Some(min_image_size)) = (self.files(path), self.min_image_size) {
+            if is_placeholder(&files, min_image_size) {
+                description.push_str(", all placeholders");
+            }
+        }
+        Some(description)
+    }
+}
+
+/// Whether `files` are all smaller than `min_image_size` bytes.
+fn is_placeholder(files: &[PathBuf], min_image_size: u64) -> bool {
+    files
+        .iter()
+        .all(|file| std::fs::metadata(file).is_ok_and(|metadata| metadata.len() < min_image_size))
+}
+
+/// Any directory that is not a `PortraitDir`.
+pub struct NonPortraitDir(pub PortraitDir);
+
+impl ScanDir for NonPortraitDir {
+    fn include(&self, path: &Path) -> bool {
+        !self.0.include(path)
+    }
+
+    fn describe(&self, path: &Path) -> Option<String> {
+        self.0.describe(path)
+    }
+}
+
+/// A directory holding some but not all of the required files of a `PortraitDir`, e.g. a
+/// "Small.png" and a "Medium.png" without their "Fulllength.png".
+pub struct PartialPortraitDir(pub PortraitDir);
+
+impl ScanDir for PartialPortraitDir {
+    fn include(&self, path: &Path) -> bool {
+        let missing = self.0.missing(path).len();
+        missing > 0 && missing < self.0.required_files.len()
+    }
+
+    fn describe(&self, path: &Path) -> Option<String> {
+        self.0.describe(path)
+    }
+}
+
+impl Scan<'_, PartialPortraitDir> {
+    /// Warns about every scanned directory, along with the files it lacks.
+    pub fn report(&self) {
+        for dir in &self.dirs {
+            log::warn!(
+                "{} is incomplete, it lacks {}",
+                dir.display(),
+                self.scan_dir.0.missing(dir).join(", ")
+            );
+        }
+    }
+}
+
+/// A directory of which the `PortraitDir` only lacks files that can be generated from its
+/// "Fulllength.png", in any of the accepted formats.
+pub struct GeneratableDir(pub PortraitDir);
+
+impl ScanDir for GeneratableDir {
+    fn include(&self, path: &Path) -> bool {
+        let missing = self.0.missing(path);
+        !missing.is_empty()
+            && missing
+                .iter()
+                .all(|file_name| generated_dimensions(file_name).is_some())
+            && self.0.find(path, PORTRAIT_FILES[2]).is_some()
+    }
+}
+
+/// The dimensions of the required file `file_name` if it can be generated, as "Small.png" and
+/// "Medium.png" can.
+fn generated_dimensions(file_name: &str) -> Option<(u32, u32)> {
+    PORTRAIT_FILES[..2]
+        .iter()
+        .zip(EXPECTED_DIMENSIONS)
+        .find(|(generatable, _)| **generatable == file_name)
+        .map(|(_, dimensions)| dimensions)
+}
+
+/// Lists `dirs` and asks the user to type "yes" to erase them, refusing when stdin is not a
+/// terminal which could answer.
+fn confirmed(dirs: &[PathBuf]) -> bool {
+    eprintln!("The following directories will be erased:");
+    for dir in dirs {
+        eprintln!("  {}", dir.display());
+    }
+    if !std::io::stdin().is_terminal() {
+        log::error!("Not erasing anything: cannot ask for confirmation, stdin is not a terminal");
+        return false;
+    }
+    eprint!(r#"Type "yes" to erase these {} directories: "#, dirs.len());
+    let mut answer = String::new();
+    if std::io::stderr().flush().is_err() || std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    if answer.trim() != "yes" {
+        eprintln!("Not erasing anything.");
+        return false;
+    }
+    true
+}
+
+/// The total size of the files in `dir` and its subdirectories, not following symlinks.
+pub(crate) fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// The latest modification time of `dir` and everything in it, not following symlinks.
+fn newest_mtime(dir: &Path) -> Option<SystemTime> {
+    let mut newest = std::fs::symlink_metadata(dir).ok()?.modified().ok();
+    for entry in std::fs::read_dir(dir).ok()?.filter_map(|entry| entry.ok()) {
+        let modified = match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => newest_mtime(&entry.path()),
+            Ok(metadata) => metadata.modified().ok(),
+            Err(_) => None,
+        };
+        newest = newest.max(modified);
+    }
+    newest
+}
+
+/// Renames `name` in `dir` to `file_name`, which only differs in case, returning whether it
+/// is (or would be) renamed.
+fn fixed_casing(dir: &Path, name: &str, file_name: &str, dry_run: bool) -> bool {
+    let (src, dst) = (dir.join(name), dir.join(file_name));
+    if dry_run {
+        log::info!("Would rename {} to {}", src.display(), file_name);
+        return true;
+    }
+    // Case-insensitive filesystems may take the direct rename for a no-op.
+    let tmp = dir.join(format!("{}.casing", file_name));
+    match std::fs::rename(&src, &tmp).and_then(|()| std::fs::rename(&tmp, &dst)) {
+        Ok(()) => {
+            log::info!("Renamed {} to {}", src.display(), file_name);
+            true
+        }
+        Err(err) => {
+            log::error!(
+                "Failed to rename {} to {}: {}",
+                src.display(),
+                file_name,
+                err
+            );
+            false
+        }
+    }
+}
+
+/// Controls how directories are erased.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EraseOptions<'a> {
+    /// Only report what would be erased.
+    pub dry_run: bool,
+    /// List the directories and ask before erasing them.
+    pub confirm: bool,
+    /// Move the directories in here rather than deleting them.
+    pub trash_dir: Option<&'a Path>,
+    /// Where the outcome of every directory goes as soon as it is erased.
+    pub events: Option<&'a EventStream>,
+}
+
+/// Erases `dirs` as duplicates, along with the bytes this frees.
+pub(crate) fn erase_measured(
+    dirs: &[PathBuf],
+    options: &EraseOptions,
+    undo_log: &mut UndoLog,
+) -> (Vec<OperationOutcome>, u64) {
+    let sizes: HashMap<&Path, u64> = dirs
+        .iter()
+        .map(|dir| (dir.as_path(), dir_size(dir)))
+        .collect();
+    let outcomes = erase_dirs(dirs, Operation::EraseDuplicate, options, undo_log);
+    // Trashed directories still take up their space.
+    let reclaimed = outcomes
+        .iter()
+        .filter_map(|outcome| match outcome {
+            OperationOutcome::Done { src, dst: None, .. }
+            | OperationOutcome::Planned { src, dst: None, .. } => sizes.get(src.as_path()),
+            _ => None,
+        })
+        .sum();
+    (outcomes, reclaimed)
+}
+
+/// Erases `dirs`, after asking for confirmation if configured and this is not a dry run.
+/// Without it, every directory is skipped.
+fn erase_dirs(
+    dirs: &[PathBuf],
+    operation: Operation,
+    options: &EraseOptions,
+    undo_log: &mut UndoLog,
+) -> Vec<OperationOutcome> {
+    if options.confirm && !options.dry_run && !dirs.is_empty() && !confirmed(dirs) {
+        let outcomes: Vec<OperationOutcome> = dirs
+            .iter()
+            .map(|dir| OperationOutcome::Skipped {
+                operation,
+                src: dir.clone(),
+            })
+            .collect();
+        if let Some(events) = options.events {
+            outcomes.iter().for_each(|outcome| events.outcome(outcome));
+        }
+        return outcomes;
+    }
+    let undo_log = Mutex::new(undo_log);
+    // Trashing picks the first free name in the trash directory, which is racy.
+    if options.dry_run || options.trash_dir.is_some() {
+        return dirs
+            .iter()
+            .map(|dir| erase_dir(dir, operation, options, &undo_log))
+            .collect();
+    }
+    // The deepest directories go first, so that no directory is deleted while one below it is
+    // being deleted too.
+    let mut by_depth: BTreeMap<usize, Vec<(usize, &PathBuf)>> = BTreeMap::new();
+    for (index, dir) in dirs.iter().enumerate() {
+        by_depth
+            .entry(dir.components().count())
+            .or_default()
+            .push((index, dir));
+    }
+    let mut outcomes: Vec<(usize, OperationOutcome)> = Vec::with_capacity(dirs.len());
+    for level in by_depth.into_values().rev() {
+        outcomes.par_extend(
+            level
+                .into_par_iter()
+                .map(|(index, dir)| (index, erase_dir(dir, operation, options, &undo_log))),
+        );
+    }
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+fn erase_dir(
+    dir: &Path,
+    operation: Operation,
+    options: &EraseOptions,
+    undo_log: &Mutex<&mut UndoLog>,
+) -> OperationOutcome {
+    let src = dir.to_path_buf();
+    let dst = options
+        .trash_dir
+        .map(|trash_dir| trash_destination(trash_dir, dir));
+    let outcome = if options.dry_run {
+        OperationOutcome::Planned {
+            operation,
+            src,
+            dst,
+        }
+    } else {
+        undo_log
+            .lock()
+            .unwrap()
+            .record(operation, dir, dst.as_deref());
+        let result = match (options.trash_dir, &dst) {
+            (Some(trash_dir), Some(dst)) => std::fs::create_dir_all(trash_dir)
+                .and_then(|()| moving::move_portrait(dir, dst, true)),
+            _ => std::fs::remove_dir_all(dir),
+        };
+        OperationOutcome::new(operation, src, dst, result)
+    };
+    if let Some(events) = options.events {
+        events.outcome(&outcome);
+    }
+    outcome
+}
+
+/// A path in `trash_dir` named after `dir` which does not exist yet, e.g. "junk_2" if "junk"
+/// and "junk_1" were trashed before.
+fn trash_destination(trash_dir: &Path, dir: &Path) -> PathBuf {
+    let file_name = dir.file_name().unwrap_or_default();
+    let mut dst = trash_dir.join(file_name);
+    let mut attempt = 0;
+    while dst.exists() {
+        attempt += 1;
+        let mut numbered = file_name.to_os_string();
+        numbered.push(format!("_{}", attempt));
+        dst = trash_dir.join(numbered);
+    }
+    dst
+}
+
+fn roughly_equals(actual: u32, expected: u32) -> bool {
+    actual.abs_diff(expected) * 100 <= expected * DIMENSIONS_TOLERANCE_PERCENT
+}
+
+/// A directory waiting to be scanned, with its depth below the root.
+type Queued = (PathBuf, usize);
+
+/// Controls how a `Scan` traverses the directory structure.
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    /// Show a spinner while scanning.
+    pub progress: bool,
+    /// Do not descend below this depth, 0 being the immediate children of the root.
+    pub max_depth: Option<usize>,
+    /// Read directories on this many threads; the collected directories are then sorted.
+    pub jobs: Option<usize>,
+    /// Descend into symbolically linked directories, skipping those already scanned.
+    pub follow_symlinks: bool,
+    /// Descend into directories whose name starts with a ".", e.g. ".git".
+    pub include_hidden: bool,
+    /// Neither collect nor descend into the directories whose path relative to the root
+    /// matches one of these patterns.
+    pub exclude: GlobSet,
+    /// Also collect the zip archives, see `Scan::archives`.
+    pub scan_archives: bool,
+    /// Only log the directories that cannot be read for lack of permissions at the debug
+    /// level, see `Scan::permission_denied`.
+    pub ignore_permission_errors: bool,
+}
+
+/// The entries of one directory that a `Scan` may descend into.
+#[derive(Default)]
+struct Subdirs {
+    dirs: Vec<PathBuf>,
+    symlinks: Vec<PathBuf>,
+    hidden: usize,
+    excluded: Vec<PathBuf>,
+    archives: Vec<PathBuf>,
+    permission_denied: usize,
+}
+
+/// The directories below `root` (recursively) which are included by `scan_dir`.
+#[derive(Debug)]
+pub struct Scan<'a, T>
+where
+    T: ScanDir,
+{
+    pub(crate) root: &'a Path,
+    pub(crate) dirs: Vec<PathBuf>,
+    pub(crate) skipped_hidden: usize,
+    permission_denied: usize,
+    pub(crate) excluded: Vec<PathBuf>,
+    pub(crate) archives: Vec<PathBuf>,
+    scan_dir: T,
+}
+
+impl<'a, T> Scan<'a, T>
+where
+    T: ScanDir + Sync,
+{
+    pub fn new(root: &'a Path, scan_dir: T, options: &ScanOptions) -> Self {
+        let dirs = Vec::new();
+        let mut scan = Self {
+            root,
+            dirs,
+            skipped_hidden: 0,
+            permission_denied: 0,
+            excluded: Vec::new(),
+            archives: Vec::new(),
+            scan_dir,
+        };
+        let spinner = progress::spinner(options.progress, "Scanning");
+        let visited = options.follow_symlinks.then(|| {
+            let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+            Mutex::new(HashSet::from([root]))
+        });
+        let visited = visited.as_ref();
+        match options.jobs {
+            Some(jobs) if jobs > 1 => scan.scan_parallel(jobs, options, visited, &spinner),
+            _ => scan.scan(options, visited, &spinner),
+        }
+        spinner.finish_and_clear();
+        scan
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root
+    }
+
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Skips the directories in `dirs`, e.g. those that were already handled.
+    pub fn skip(&mut self, dirs: &HashSet<PathBuf>) {
+        self.dirs.retain(|dir| !dirs.contains(dir));
+    }
+
+    /// Adds `dirs` to the scan in order, e.g. those in which a dry run would have generated
+    /// the missing files.
+    pub fn add(&mut self, dirs: &[PathBuf]) {
+        self.dirs.extend_from_slice(dirs);
+        self.dirs.sort();
+        self.dirs.dedup();
+    }
+
+    pub fn scan_dir(&self) -> &T {
+        &self.scan_dir
+    }
+
+    /// The number of hidden directories that were not descended into.
+    pub fn skipped_hidden(&self) -> usize {
+        self.skipped_hidden
+    }
+
+    /// The number of directories that could not be read for lack of permissions.
+    pub fn permission_denied(&self) -> usize {
+        self.permission_denied
+    }
+
+    /// The directories that were not descended into because they matched an exclude pattern.
+    pub fn excluded(&self) -> &[PathBuf] {
+        &self.excluded
+    }
+
+    /// The zip archives that were found, if `ScanOptions::scan_archives`.
+    pub fn archives(&self) -> &[PathBuf] {
+        &self.archives
+    }
+
+    /// The subdirectories of `dir` and, when following symlinks, the symlinked directories.
+    fn subdirs(
+        root: &Path,
+        dir: &Path,
+        options: &ScanOptions,
+        visited: Option<&Mutex<HashSet<PathBuf>>>,
+        spinner: &ProgressBar,
+    ) -> Subdirs {
+        spinner.inc(1);
+        log::trace!("Scanning {}", dir.display());
+        let mut subdirs = Subdirs::default();
+        let contents = match std::fs::read_dir(dir) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                subdirs.permission_denied += 1;
+                if options.ignore_permission_errors {
+                    log::debug!("Skipping {}: permission denied", dir.display());
+                } else {
+                    log::warn!("Skipping {}: permission denied", dir.display());
+                }
+                return subdirs;
+            }
+            Err(err) => {
+                log::warn!("Failed to scan the contents of {}: {}", dir.display(), err);
+                return subdirs;
+            }
+        };
+        for entry in contents.filter_map(Result::ok) {
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let path = entry.path();
+            let is_dir = file_type.is_dir() || file_type.is_symlink() && path.is_dir();
+            let is_archive = options.scan_archives && !is_dir && archive::is_archive(&path);
+            if !(is_dir || is_archive) {
+                continue;
+            }
+            if !options.include_hidden && entry.file_name().as_encoded_bytes().starts_with(b".") {
+                subdirs.hidden += usize::from(is_dir);
+            } else if path
+                .strip_prefix(root)
+                .is_ok_and(|relative| options.exclude.is_match(relative))
+            {
+                subdirs.excluded.push(path);
+            } else if is_archive {
+                subdirs.archives.push(path);
+            } else if file_type.is_dir() {
+                subdirs.dirs.push(path);
+            } else if visited.is_some() {
+                subdirs.symlinks.push(path);
+            }
+        }
+        subdirs.dirs.sort();
+        subdirs.symlinks.sort();
+        if let Some(visited) = visited {
+            subdirs.dirs.retain(|path| Self::first_visit(path, visited));
+        }
+        subdirs
+    }
+
+    fn first_visit(path: &Path, visited: &Mutex<HashSet<PathBuf>>) -> bool {
+        let Ok(canonical) = path.canonicalize() else {
+            return false;
+        };
+        let mut visited = visited.lock().unwrap();
+        if visited.contains(&canonical) {
+            log::warn!(
+                "Skipping {}: {} was already scanned (symlink loop?)",
+                path.display(),
+                canonical.display()
+            );
+            return false;
+        }
+        visited.insert(canonical);
+        true
+    }
+
+    /// Symlinks are only followed once everything else was scanned, so that a directory is
+    /// found through its real path rather than through a link to it.
+    fn follow_symlinks(
+        symlinks: &mut Vec<Queued>,
+        visited: Option<&Mutex<HashSet<PathBuf>>>,
+    ) -> Vec<Queued> {
+        let Some(visited) = visited else {
+            return Vec::new();
+        };
+        std::mem::take(symlinks)
+            .into_iter()
+            .filter(|(path, _)| Self::first_visit(path, visited))
+            .collect()
+    }
+
+    fn include_all(&mut self, dirs: &[Queued]) {
+        for (path, _) in dirs {
+            if included(&self.scan_dir, path) {
+                self.dirs.push(path.clone());
+            }
+        }
+    }
+
+    fn scan(
+        &mut self,
+        options: &ScanOptions,
+        visited: Option<&Mutex<HashSet<PathBuf>>>,
+        spinner: &ProgressBar,
+    ) {
+        let can_descend = |depth: usize| options.max_depth.is_none_or(|max| depth < max);
+        let mut worklist: Vec<Queued> = vec![(self.root.to_path_buf(), 0)];
+        let mut symlinks: Vec<Queued> = Vec::new();
+        loop {
+            if worklist.is_empty() {
+                let followed = Self::follow_symlinks(&mut symlinks, visited);
+                self.include_all(&followed);
+                // Reversed, so that the subdirectories are popped in order.
+                worklist.extend(
+                    followed
+                        .into_iter()
+                        .rev()
+                        .filter(|(_, d)| can_descend(*d))
+                        .map(|(dir, d)| (dir, d + 1)),
+                );
+            }
+            let Some((dir, depth)) = worklist.pop() else {
+                break;
+            };
+            let Subdirs {
+                dirs: subdirs,
+                symlinks: links,
+                hidden,
+                excluded,
+                archives,
+                permission_denied,
+            } = Self::subdirs(self.root, &dir, options, visited, spinner);
+            let subdirs: Vec<Queued> = subdirs.into_iter().map(|dir| (dir, depth)).collect();
+            symlinks.extend(links.into_iter().map(|link| (link, depth)));
+            self.skipped_hidden += hidden;
+            self.permission_denied += permission_denied;
+            self.excluded.extend(excluded);
+            self.archives.extend(archives);
+            self.include_all(&subdirs);
+            if can_descend(depth) {
+                worklist.extend(subdirs.into_iter().rev().map(|(dir, d)| (dir, d + 1)));
+            }
+        }
+        if visited.is_some() {
+            // Put the directories found through symlinks in place, as `scan_parallel` does.
+            self.dirs.sort();
+        }
+    }
+
+    fn scan_parallel(
+        &mut self,
+        jobs: usize,
+        options: &ScanOptions,
+        visited: Option<&Mutex<HashSet<PathBuf>>>,
+        spinner: &ProgressBar,
+    ) {
+        let pool = match rayon::ThreadPoolBuilder::new().num_threads(jobs).build() {
+            Ok(pool) => pool,
+            Err(_) => {
+                log::warn!("Failed to start {} scanning threads", jobs);
+                return self.scan(options, visited, spinner);
+            }
+        };
+        let can_descend = |depth: usize| options.max_depth.is_none_or(|max| depth < max);
+        let mut level: Vec<Queued> = vec![(self.root.to_path_buf(), 0)];
+        let mut symlinks: Vec<Queued> = Vec::new();
+        loop {
+            if level.is_empty() {
+                let followed = Self::follow_symlinks(&mut symlinks, visited);
+                self.include_all(&followed);
+                level.extend(
+                    followed
+                        .into_iter()
+                        .filter(|(_, d)| can_descend(*d))
+                        .map(|(dir, d)| (dir, d + 1)),
+                );
+                if level.is_empty() {
+                    break;
+                }
+            }
+            let (root, scan_dir) = (self.root, &self.scan_dir);
+            let found: Vec<(Vec<PathBuf>, Subdirs, usize)> = pool.install(|| {
+                level
+                    .par_iter()
+                    .map(|(dir, depth)| {
+                        let subdirs = Self::subdirs(root, dir, options, visited, spinner);
+                        let included = subdirs
+                            .dirs
+                            .iter()
+                            .filter(|path| included(scan_dir, path))
+                            .cloned()
+                            .collect();
+                        (included, subdirs, *depth)
+                    })
+                    .collect()
+            });
+            level = Vec::new();
+            for (included, subdirs, depth) in found {
+                self.dirs.extend(included);
+                self.skipped_hidden += subdirs.hidden;
+                self.permission_denied += subdirs.permission_denied;
+                self.excluded.extend(subdirs.excluded);
+                self.archives.extend(subdirs.archives);
+                if can_descend(depth) {
+                    level.extend(subdirs.dirs.into_iter().map(|dir| (dir, depth + 1)));
+                }
+                symlinks.extend(subdirs.symlinks.into_iter().map(|link| (link, depth)));
+            }
+        }
+        self.dirs.sort();
+        self.excluded.sort();
+        self.archives.sort();
+    }
+}
+
+impl Scan<'_, NonPortraitDir> {
+    pub fn erase(self, options: &EraseOptions, undo_log: &mut UndoLog) -> Vec<OperationOutcome> {
+        erase_dirs(&self.dirs, Operation::Erase, options, undo_log)
+    }
+
+    /// Stops the directories containing one of `portrait_dirs` from being erased, such as
+    /// those recreated by `--preserve-tree`.
+    pub fn skip_ancestors_of(&mut self, portrait_dirs: &[PathBuf]) {
+        self.dirs.retain(|dir| {
+            !portrait_dirs
+                .iter()
+                .any(|portrait_dir| portrait_dir.starts_with(dir))
+        });
+    }
+}
+
+impl Scan<'_, GeneratableDir> {
+    /// Generates the missing files of every directory, returning those in which they were
+    /// (or would be, in a dry run) generated.
+    pub fn generate_missing(self, dry_run: bool) -> Vec<PathBuf> {
+        let mut generated = Vec::new();
+        for dir in self.dirs {
+            if dry_run {
+                log::info!("Would generate missing files in {}", dir.display());
+                generated.push(dir);
+            } else if let Err(err) = generate_dir(&dir, &self.scan_dir.0) {
+                log::error!(
+                    "Failed to generate missing files in {}: {}",
+                    dir.display(),
+                    err
+                );
+            } else {
+                generated.push(dir);
+            }
+        }
+        generated
+    }
+}
+
+/// Writes the missing files of `dir` as PNGs, resized from its "Fulllength.png".
+fn generate_dir(dir: &Path, portrait_dir: &PortraitDir) -> image::ImageResult<()> {
+    let Some(full) = portrait_dir.find(dir, PORTRAIT_FILES[2]) else {
+        return Ok(());
+    };
+    let full = image::open(full)?;
+    for file_name in portrait_dir.missing(dir) {
+        let Some((width, height)) = generated_dimensions(file_name) else {
+            continue;
+        };
+        full.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+            .save_with_format(dir.join(file_name), image::ImageFormat::Png)?;
+    }
+    Ok(())
+}
+
+impl Scan<'_, PortraitDir> {
+    /// Sorts the directories, ties being broken by their path so that the order is the same
+    /// on every run.
+    pub fn sort(&mut self, order: SortOrder) {
+        if let SortOrder::None = order {
+            return;
+        }
+        self.dirs.sort();
+        match order {
+            SortOrder::Mtime => self.dirs.sort_by_cached_key(|dir| {
+                std::fs::metadata(dir)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            }),
+            SortOrder::Size => self.dirs.sort_by_cached_key(|dir| {
+                self.scan_dir.files(dir).map(|files| {
+                    files
+                        .iter()
+                        .filter_map(|file| std::fs::metadata(file).ok())
+                        .map(|metadata| metadata.len())
+                        .sum::<u64>()
+                })
+            }),
+            SortOrder::None | SortOrder::Name => {}
+        }
+    }
+
+    /// Erases the `duplicates` found by `duplicates`, returning the outcomes along with the
+    /// bytes freed (or that would be freed) by deleting them.
+    pub fn erase_duplicates(
+        &mut self,
+        duplicates: &[PathBuf],
+        options: &EraseOptions,
+        undo_log: &mut UndoLog,
+    ) -> (Vec<OperationOutcome>, u64) {
+        self.skip(&duplicates.iter().cloned().collect());
+        erase_measured(duplicates, options, undo_log)
+    }
+
+    /// Removes from the scan the directories whose checksum is in `blocklist`, returning
+    /// them.
+    pub fn skip_blocklisted(
+        &mut self,
+        blocklist: &HashSet<String>,
+        hash_algorithm: HashAlgorithm,
+        dedup_on: DedupOn,
+        progress: bool,
+        cache: &mut ChecksumCache,
+    ) -> Vec<PathBuf> {
+        let bar = progress::bar(progress, self.dirs.len(), "Checking the blocklist");
+        let mut blocklisted = Vec::new();
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            bar.inc(1);
+            let checksum =
+                Checksum::from_dir_cached(dir, scan_dir, hash_algorithm, dedup_on, cache);
+            match checksum {
+                Some(checksum) if blocklist.contains(&checksum.to_string()) => {
+                    log::info!("Skipping {}, it is blocklisted", dir.display());
+                    blocklisted.push(dir.clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+        bar.finish_and_clear();
+        blocklisted
+    }
+
+    /// The directories whose files are the same as those of another directory, the original
+    /// chosen by `keep`, which is logged along with each. They are in the order of the scan.
+    pub fn duplicates(
+        &self,
+        hash_algorithm: HashAlgorithm,
+        dedup_on: DedupOn,
+        keep: Keep,
+        prefer_richer: Option<&str>,
+        progress: bool,
+        cache: &mut ChecksumCache,
+    ) -> Vec<PathBuf> {
+        let sets = self.checksum_sets(hash_algorithm, dedup_on, progress, cache);
+        let mut originals: HashMap<&Path, &Path> = HashMap::new();
+        for set in sets.values().filter(|set| set.len() > 1) {
+            let original = self.kept(set, keep, prefer_richer);
+            for dir in set {
+                if *dir != original {
+                    originals.insert(dir, original);
+                }
+            }
+        }
+        self.dirs
+            .iter()
+            .filter(|dir| match originals.get(dir.as_path()) {
+                Some(original) => {
+                    log::debug!("{} is a duplicate of {}", dir.display(), original.display());
+                    true
+                }
+                None => false,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The sets of scanned directories sharing a checksum, each in the order of the scan, the
+    /// sets being sorted by their first directory.
+    pub fn duplicate_groups(
+        &self,
+        hash_algorithm: HashAlgorithm,
+        dedup_on: DedupOn,
+        progress: bool,
+        cache: &mut ChecksumCache,
+    ) -> Vec<Vec<PathBuf>> {
+        let sets = self.checksum_sets(hash_algorithm, dedup_on, progress, cache);
+        let mut groups: Vec<Vec<PathBuf>> = sets
+            .into_values()
+            .filter(|set| set.len() > 1)
+            .map(|set| set.into_iter().map(Path::to_path_buf).collect())
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    /// The scanned directories by their checksum, in the order of the scan.
+    fn checksum_sets(
+        &self,
+        hash_algorithm: HashAlgorithm,
+        dedup_on: DedupOn,
+        progress: bool,
+        cache: &mut ChecksumCache,
+    ) -> HashMap<Checksum, Vec<&Path>> {
+        let mut sets: HashMap<Checksum, Vec<&Path>> = HashMap::new();
+        let bar = progress::bar(progress, self.dirs.len(), "Checking for duplicates");
+        for dir in &self.dirs {
+            bar.inc(1);
+            match Checksum::from_dir_cached(dir, &self.scan_dir, hash_algorithm, dedup_on, cache) {
+                Some(checksum) => sets.entry(checksum).or_default().push(dir),
+                None => log::warn!("Failed to get checksum for {}", dir.display()),
+            }
+        }
+        bar.finish_and_clear();
+        sets
+    }
+
+    /// The directory of the duplicates `set`, in the order of the scan, that `keep` chooses.
+    /// With `prefer_richer`, it chooses only among those holding the named extra file, if any,
+    /// and then among those holding the most extra files.
+    fn kept<'b>(&self, set: &[&'b Path], keep: Keep, prefer_richer: Option<&str>) -> &'b Path {
+        let richest: Vec<&'b Path>;
+        let set = match prefer_richer {
+            Some(file) => {
+                let richness = |dir: &Path| {
+                    let extras = self.scan_dir.extras(dir);
+                    let holds_file = !file.is_empty()
+                        && extras.iter().any(|extra| {
+                            extra
+                                .file_name()
+                                .is_some_and(|name| name.eq_ignore_ascii_case(file))
+                        });
+                    (holds_file, extras.len())
+                };
+                let richness: Vec<_> = set.iter().map(|dir| richness(dir)).collect();
+                let max = richness.iter().max().copied();
+                richest = set
+                    .iter()
+                    .zip(&richness)
+                    .filter(|(_, richness)| Some(**richness) == max)
+                    .map(|(dir, _)| *dir)
+                    .collect();
+                &richest[..]
+            }
+            None => set,
+        };
+        let relative = |dir: &'b Path| dir.strip_prefix(self.root).unwrap_or(dir);
+        let newest = |dir: &Path| {
+            self.scan_dir
+                .files(dir)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+                .max()
+        };
+        let by_path = |a: &&'b Path, b: &&'b Path| relative(a).cmp(relative(b));
+        let chosen = match keep {
+            Keep::First => None,
+            Keep::Newest => set
+                .iter()
+                .min_by(|a, b| newest(b).cmp(&newest(a)).then_with(|| by_path(a, b))),
+            Keep::ShortestPath => set.iter().min_by(|a, b| {
+                let (a_len, b_len) = (relative(a).as_os_str().len(), relative(b).as_os_str().len());
+                a_len.cmp(&b_len).then_with(|| by_path(a, b))
+            }),
+            Keep::LongestName => set.iter().min_by(|a, b| {
+                let name_len = |dir: &Path| dir.file_name().unwrap_or_default().len();
+                name_len(b).cmp(&name_len(a)).then_with(|| by_path(a, b))
+            }),
+        };
+        chosen.unwrap_or(&set[0])
+    }
+
+    /// Skips the directories whose files are already in one of the directories of
+    /// `installed`, returning them.
+    pub fn skip_installed(
+        &mut self,
+        installed: &Scan<'_, PortraitDir>,
+        hash_algorithm: HashAlgorithm,
+        dedup_on: DedupOn,
+        progress: bool,
+        cache: &mut ChecksumCache,
+    ) -> Vec<PathBuf> {
+        let bar = progress::bar(
+            progress,
+            installed.dirs.len() + self.dirs.len(),
+            "Checking installed portraits",
+        );
+        let mut checksums: HashMap<Checksum, &Path> = HashMap::new();
+        for dir in &installed.dirs {
+            bar.inc(1);
+            if let Some(checksum) =
+                Checksum::from_dir_cached(dir, &installed.scan_dir, hash_algorithm, dedup_on, cache)
+            {
+                checksums.entry(checksum).or_insert(dir);
+            }
+        }
+        let mut skipped = Vec::new();
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            bar.inc(1);
+            let installed =
+                Checksum::from_dir_cached(dir, scan_dir, hash_algorithm, dedup_on, cache)
+                    .and_then(|checksum| checksums.get(&checksum));
+            match installed {
+                Some(installed) => {
+                    log::info!(
+                        "Skipping {}, already installed as {}",
+                        dir.display(),
+                        installed.display()
+                    );
+                    skipped.push(dir.clone());
+                    false
+                }
+                None => true,
+            }
+        });
+        bar.finish_and_clear();
+        skipped
+    }
+
+    /// Skips the directories after the first `max`, returning how many were skipped.
+    pub fn limit(&mut self, max: usize) -> usize {
+        let skipped = self.dirs.len().saturating_sub(max);
+        for dir in self.dirs.drain(self.dirs.len() - skipped..) {
+            log::info!(
+                "Skipping {}, the Portraits directory is full",
+                dir.display()
+            );
+        }
+        skipped
+    }
+
+    /// Keeps the directories, in order, until their required files would add up to more than
+    /// `max` bytes, removing the others from the scan. Returns how many were removed and the
+    /// bytes of those kept.
+    pub fn limit_bytes(&mut self, max: u64) -> (usize, u64) {
+        let mut total = 0;
+        let sizes: Vec<u64> = self
+            .dirs
+            .iter()
+            .map(|dir| {
+                self.scan_dir
+                    .files(dir)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|file| std::fs::metadata(file).ok())
+                    .map(|metadata| metadata.len())
+                    .sum()
+            })
+            .collect();
+        let kept = sizes
+            .iter()
+            .take_while(|size| {
+                total += **size;
+                total <= max
+            })
+            .count();
+        let kept_bytes = sizes[..kept].iter().sum();
+        for (dir, size) in self.dirs.drain(kept..).zip(&sizes[kept..]) {
+            log::info!(
+                "Deferring {} ({} bytes), over the byte limit",
+                dir.display(),
+                size
+            );
+        }
+        (sizes.len() - kept, kept_bytes)
+    }
+
+    /// The scanned directories that hold more than the required files.
+    pub fn extras(&self) -> Vec<Extras> {
+        self.dirs
+            .iter()
+            .map(|dir| Extras {
+                dir: dir.clone(),
+                files: self.scan_dir.extras(dir),
+            })
+            .filter(|extras| !extras.files.is_empty())
+            .collect()
+    }
+
+    /// Removes the files that are not required from the scanned directories, returning how
+    /// many were (or would be) removed.
+    pub fn strip_extras(&self, dry_run: bool) -> usize {
+        let mut stripped = 0;
+        for Extras { files, .. } in self.extras() {
+            for file in files {
+                if dry_run {
+                    log::info!("Would remove extra file {}", file.display());
+                    stripped += 1;
+                    continue;
+                }
+                match std::fs::remove_file(&file) {
+                    Ok(()) => {
+                        log::debug!("Removed extra file {}", file.display());
+                        stripped += 1;
+                    }
+                    Err(err) => log::error!("Failed to remove {}: {}", file.display(), err),
+                }
+            }
+        }
+        stripped
+    }
+
+    /// Skips the directories that are already installed below `target`, for when `target` is
+    /// inside the scanned directory, returning how many were skipped. When `target` is the
+    /// scanned directory itself, only the directories where `bucket` and `preserve_tree` would
+    /// put them are installed, e.g. "G/gimli" but not "packA/gimli" with `Bucket::ByLetter`.
+    pub fn skip_in_place(&mut self, target: &Path, bucket: Bucket, preserve_tree: bool) -> usize {
+        let Ok(target) = target.canonicalize() else {
+            return 0;
+        };
+        let is_root = self.root.canonicalize().is_ok_and(|root| root == target);
+        let installed = |relative: &Path| {
+            let components: Vec<&OsStr> = relative.iter().collect();
+            match (&components[..], bucket) {
+                _ if !is_root || preserve_tree => true,
+                ([_], Bucket::None) => true,
+                ([letter, _], Bucket::ByLetter) => letter.to_str().is_some_and(|letter| {
+                    letter == "#" || letter.chars().count() == 1 && letter.to_uppercase() == letter
+                }),
+                ([_, _], Bucket::BySourceTop) => true,
+                _ => false,
+            }
+        };
+        let mut skipped = 0;
+        self.dirs.retain(|dir| {
+            // The dir itself is not resolved, in case it is a link to elsewhere.
+            let in_place = dir
+                .parent()
+                .and_then(|parent| parent.canonicalize().ok())
+                .zip(dir.file_name())
+                .is_some_and(|(parent, name)| {
+                    parent
+                        .join(name)
+                        .strip_prefix(&target)
+                        .is_ok_and(&installed)
+                });
+            if in_place {
+                log::debug!("Leaving {} in place", dir.display());
+                skipped += 1;
+            }
+            !in_place
+        });
+        skipped
+    }
+
+    /// Skips the directories but those named, or found at the relative path, by an entry of
+    /// `list`, returning how many were skipped.
+    pub fn keep_listed(&mut self, list: &[String]) -> usize {
+        let entries: Vec<String> = list
+            .iter()
+            .map(|entry| entry.replace('\\', "/").trim_matches('/').to_owned())
+            .collect();
+        let mut used = vec![false; entries.len()];
+        let case_sensitive = self.scan_dir.case_sensitive;
+        let root = self.root;
+        let mut skipped = 0;
+        self.dirs.retain(|dir| {
+            let relative = dir.strip_prefix(root).unwrap_or(dir);
+            let relative = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let name = dir.file_name().unwrap_or_default().to_string_lossy();
+            let matching = entries.iter().position(|entry| {
+                let candidate = if entry.contains('/') {
+                    &relative
+                } else {
+                    &*name
+                };
+                if case_sensitive {
+                    candidate == entry
+                } else {
+                    candidate.eq_ignore_ascii_case(entry)
+                }
+            });
+            match matching {
+                Some(index) => {
+                    used[index] = true;
+                    true
+                }
+                None => {
+                    log::debug!("Skipping {}, it is not in the include list", dir.display());
+                    skipped += 1;
+                    false
+                }
+            }
+        });
+        for (entry, used) in list.iter().zip(used) {
+            if !used {
+                log::warn!("No portrait dir matches {} of the include list", entry);
+            }
+        }
+        skipped
+    }
+
+    /// Warns about the directories holding several files named like the same required file
+    /// but for their case, e.g. "small.png" and "Small.png", which only case-sensitive
+    /// filesystems allow. With `fix`, renames the files named like a required file but for
+    /// their case to its exact name, e.g. "small.png" to "Small.png", unless they conflict.
+    /// Returns how many directories conflict and how many files are renamed.
+    pub fn check_casing(&self, fix: bool, dry_run: bool) -> (usize, usize) {
+        let mut conflicting = 0;
+        let mut fixed = 0;
+        for dir in &self.dirs {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            let mut names: Vec<String> = entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect();
+            names.sort();
+            let mut conflicts = false;
+            for required_file in &self.scan_dir.required_files {
+                for file_name in self.scan_dir.alternatives(required_file) {
+                    let matching: Vec<&String> = names
+                        .iter()
+                        .filter(|name| name.eq_ignore_ascii_case(&file_name))
+                        .collect();
+                    match matching[..] {
+                        [name] if fix && *name != file_name => {
+                            fixed += usize::from(fixed_casing(dir, name, &file_name, dry_run));
+                        }
+                        [_, _, ..] => {
+                            let matching: Vec<&str> =
+                                matching.iter().map(|name| name.as_str()).collect();
+                            log::warn!(
+                                "{}: {} differ only in case",
+                                dir.display(),
+                                matching.join(", ")
+                            );
+                            conflicts = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if conflicts {
+                conflicting += 1;
+            }
+        }
+        (conflicting, fixed)
+    }
+
+    /// Skips the directories in which something was modified less than `min_age` ago, as
+    /// they may still be being extracted, returning them.
+    pub fn skip_recent(&mut self, min_age: Duration) -> Vec<PathBuf> {
+        let now = SystemTime::now();
+        let mut recent = Vec::new();
+        self.dirs.retain(|dir| {
+            // Modification times in the future are as suspicious as recent ones.
+            let age =
+                newest_mtime(dir).map(|newest| now.duration_since(newest).unwrap_or_default());
+            match age {
+                Some(age) if age < min_age => {
+                    log::warn!(
+                        "Skipping {}: modified {} s ago, it may still be being written",
+                        dir.display(),
+                        age.as_secs()
+                    );
+                    recent.push(dir.clone());
+                    false
+                }
+                _ => true,
+            }
+        });
+        recent
+    }
+
+    /// Skips the directories whose required files are all smaller than `min_image_size`
+    /// bytes, returning how many were skipped.
+    pub fn skip_placeholders(&mut self, min_image_size: u64) -> usize {
+        let mut skipped = 0;
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            let files = scan_dir.files(dir).unwrap_or_default();
+            if is_placeholder(&files, min_image_size) {
+                log::warn!("Skipping {}: only holds placeholder images", dir.display());
+                skipped += 1;
+                return false;
+            }
+            true
+        });
+        skipped
+    }
+
+    pub fn skip_invalid_png(&mut self) -> usize {
+        let mut skipped = 0;
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            for file in scan_dir.files(dir).unwrap_or_default() {
+                let is_png = file
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+                if is_png && png_dimensions(&file).is_none() {
+                    log::warn!(
+                        "Skipping {}: {} is not a valid PNG",
+                        dir.display(),
+                        file.file_name().unwrap_or_default().to_string_lossy()
+                    );
+                    skipped += 1;
+                    return false;
+                }
+            }
+            true
+        });
+        skipped
+    }
+
+    pub fn check_dimensions(&mut self, strict: bool) -> usize {
+        let mut mismatched = 0;
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            let mut matches = true;
+            // Only the standard files have known dimensions, whichever files are required.
+            let files = PORTRAIT_FILES.iter().zip(EXPECTED_DIMENSIONS).filter_map(
+                |(file_name, expected)| Some((scan_dir.find(dir, file_name)?, expected)),
+            );
+            for (file, (expected_width, expected_height)) in files {
+                let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+                match image::image_dimensions(&file).ok() {
+                    Some((width, height))
+                        if roughly_equals(width, expected_width)
+                            && roughly_equals(height, expected_height) => {}
+                    Some((width, height)) => {
+                        log::warn!(
+                            "{}: {} is {}x{}, expected about {}x{}",
+                            dir.display(),
+                            file_name,
+                            width,
+                            height,
+                            expected_width,
+                            expected_height
+                        );
+                        matches = false;
+                    }
+                    None => {
+                        log::warn!(
+                            "{}: failed to read the dimensions of {}",
+                            dir.display(),
+                            file_name
+                        );
+                        matches = false;
+                    }
+                }
+            }
+            if !matches {
+                mismatched += 1;
+            }
+            matches || !strict
+        });
+        mismatched
+    }
+
+    /// Skips the directories whose "Fulllength.png", or e.g. "Fulllength.jpg", has a height to
+    /// width ratio outside of `min` to `max`, returning how many were skipped.
+    pub fn skip_wrong_aspect(&mut self, min: Option<f64>, max: Option<f64>) -> usize {
+        let mut skipped = 0;
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            let Some(file) = scan_dir.find(dir, "Fulllength.png") else {
+                log::warn!("{}: found no Fulllength.png", dir.display());
+                return true;
+            };
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            let dimensions = image::image_dimensions(&file).ok();
+            let Some((width, height)) = dimensions.filter(|(width, _)| *width > 0) else {
+                log::warn!(
+                    "{}: failed to read the dimensions of {}",
+                    dir.display(),
+                    file_name
+                );
+                return true;
+            };
+            let ratio = f64::from(height) / f64::from(width);
+            if min.is_some_and(|min| ratio < min) || max.is_some_and(|max| ratio > max) {
+                log::warn!(
+                    "Skipping {}: {} is {}x{}, a height to width ratio of {:.2}",
+                    dir.display(),
+                    file_name,
+                    width,
+                    height,
+                    ratio
+                );
+                skipped += 1;
+                return false;
+            }
+            true
+        });
+        skipped
+    }
+
+    /// Warns about the directories whose "Small.png", "Medium.png" and "Fulllength.png" seem to
+    /// come from different artwork, removing them from the scan if `strict`, and returns how
+    /// many there are.
+    pub fn check_consistency(&mut self, strict: bool) -> usize {
+        let mut inconsistent = 0;
+        let scan_dir = &self.scan_dir;
+        self.dirs.retain(|dir| {
+            let histograms: Option<Vec<(PathBuf, ColorHistogram)>> = PORTRAIT_FILES
+                .iter()
+                .filter_map(|file_name| scan_dir.find(dir, file_name))
+                .map(|file| ColorHistogram::from_file(&file).map(|histogram| (file, histogram)))
+                .collect();
+            let Some(histograms) = histograms else {
+                log::warn!("{}: failed to decode an image", dir.display());
+                return true;
+            };
+            let mismatch = histograms
+                .iter()
+                .enumerate()
+                .find_map(|(i, (a, a_histogram))| {
+                    histograms[i + 1..].iter().find_map(|(b, b_histogram)| {
+                        let similarity = a_histogram.similarity(b_histogram);
+                        (similarity < MIN_CONSISTENCY).then_some((a, b, similarity))
+                    })
+                });
+            let Some((a, b, similarity)) = mismatch else {
+                return true;
+            };
+            log::warn!(
+                "{}: {} and {} look like different artwork ({:.0}% alike)",
+                dir.display(),
+                a.file_name().unwrap_or_default().to_string_lossy(),
+                b.file_name().unwrap_or_default().to_string_lossy(),
+                similarity * 100.0
+            );
+            inconsistent += 1;
+            !strict
+        });
+        inconsistent
+    }
+
+    pub fn erase_perceptual_duplicates(
+        &mut self,
+        options: &EraseOptions,
+        threshold: u32,
+        undo_log: &mut UndoLog,
+    ) -> Vec<OperationOutcome> {
+        let scan_dir = &self.scan_dir;
+        let mut hashes = self
+            .dirs
+            .par_iter()
+            .map(|dir| PerceptualHash::from_dir(dir, scan_dir))
+            .collect::<Vec<_>>()
+            .into_iter();
+        // The first of the similar directories in the order of the scan is kept.
+        let mut index = PerceptualIndex::default();
+        let mut duplicates = Vec::new();
+        self.dirs.retain(|dir| {
+            let Some(hash) = hashes.next().flatten() else {
+                log::warn!("Failed to get perceptual hash for {}", dir.display());
+                return true;
+            };
+            if index.contains_near(hash, threshold) {
+                duplicates.push(dir.clone());
+                false
+            } else {
+                index.insert(hash);
+                true
+            }
+        });
+        erase_dirs(&duplicates, Operation::EraseDuplicate, options, undo_log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new directory for the test `name`, holding an empty directory at every path of `dirs`.
+    fn scratch(name: &str, dirs: &[&str]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("portraits-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in dirs {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        root
+    }
+
+    /// A scan of `root` that found the `dirs` below it, accepting the portrait files with
+    /// the `extensions`.
+    fn scan<'a>(root: &'a Path, dirs: &[&str], extensions: &[&str]) -> Scan<'a, PortraitDir> {
+        Scan {
+            root,
+            dirs: dirs.iter().map(|dir| root.join(dir)).collect(),
+            skipped_hidden: 0,
+            permission_denied: 0,
+            excluded: Vec::new(),
+            archives: Vec::new(),
+            scan_dir: PortraitDir {
+                required_files: PORTRAIT_FILES.map(str::to_owned).to_vec(),
+                case_sensitive: false,
+                extensions: extensions
+                    .iter()
+                    .map(|extension| extension.to_string())
+                    .collect(),
+                min_image_size: None,
+            },
+        }
+    }
+
+    /// The `dirs` below `root` which `skip_in_place` keeps.
+    fn not_in_place(
+        root: &Path,
+        target: &Path,
+        dirs: &[&str],
+        bucket: Bucket,
+        preserve_tree: bool,
+    ) -> Vec<PathBuf> {
+        let mut scan = scan(root, dirs, &[]);
+        scan.skip_in_place(target, bucket, preserve_tree);
+        scan.dirs
+    }
+
+    #[test]
+    fn skip_in_place_same_dir() {
+        let dirs = ["pf_portrait_000000", "gimli", "packA/legolas", "G/gimli"];
+        let root = &scratch("in-place-same", &dirs);
+        assert_eq!(
+            not_in_place(root, root, &dirs, Bucket::None, false),
+            [root.join("packA/legolas"), root.join("G/gimli")]
+        );
+        assert_eq!(
+            not_in_place(root, root, &dirs, Bucket::ByLetter, false),
+            [
+                root.join("pf_portrait_000000"),
+                root.join("gimli"),
+                root.join("packA/legolas")
+            ]
+        );
+        assert!(not_in_place(root, root, &dirs, Bucket::None, true).is_empty());
+        // The same dir, spelled differently.
+        let dotted = &root.join("packA/..");
+        assert_eq!(
+            not_in_place(root, dotted, &dirs, Bucket::None, false),
+            [root.join("packA/legolas"), root.join("G/gimli")]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn skip_in_place_inner_target() {
+        let dirs = ["packA/gimli", "Portraits/myportrait", "Portraits/G/gimli"];
+        let root = &scratch("in-place-inner", &dirs);
+        assert_eq!(
+            not_in_place(root, &root.join("Portraits"), &dirs, Bucket::None, false),
+            [root.join("packA/gimli")]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn dimensions_of_any_format() {
+        let dirs = ["wide", "tall"];
+        let root = &scratch("dimensions", &dirs);
+        for (dir, (width, height)) in dirs.iter().zip([(40, 20), (20, 40)]) {
+            let image = image::RgbImage::new(width, height);
+            image.save(root.join(dir).join("Fulllength.jpg")).unwrap();
+        }
+        let mut aspect = scan(root, &dirs, &["png", "jpg"]);
+        assert_eq!(aspect.skip_wrong_aspect(Some(1.0), None), 1);
+        assert_eq!(aspect.dirs, [root.join("tall")]);
+
+        let (width, height) = EXPECTED_DIMENSIONS[2];
+        image::RgbImage::new(width, height)
+            .save(root.join("tall/Fulllength.jpg"))
+            .unwrap();
+        let mut dimensions = scan(root, &dirs, &["png", "jpg"]);
+        assert_eq!(dimensions.check_dimensions(true), 1);
+        assert_eq!(dimensions.dirs, [root.join("tall")]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn generate_missing() {
+        let dirs = ["gimli", "complete", "legolas"];
+        let root = &scratch("generate", &dirs);
+        for dir in dirs {
+            let file = root.join(dir).join("fulllength.jpg");
+            image::RgbImage::new(20, 30).save(file).unwrap();
+        }
+        for file in ["Small.png", "Medium.png"] {
+            std::fs::copy(
+                root.join("complete/fulllength.jpg"),
+                root.join("complete").join(file),
+            )
+            .unwrap();
+        }
+        let generatable = |required_files: &[&str]| {
+            let scan_dir = scan(root, &[], &["png", "jpg"]).scan_dir;
+            GeneratableDir(PortraitDir {
+                required_files: required_files.iter().map(|file| file.to_string()).collect(),
+                ..scan_dir
+            })
+        };
+        let options = ScanOptions::default();
+        // Neither a complete dir nor one lacking a file that cannot be generated.
+        let scan = Scan::new(root, generatable(&PORTRAIT_FILES), &options);
+        assert_eq!(scan.dirs, [root.join("gimli"), root.join("legolas")]);
+        let scan = Scan::new(root, generatable(&["Small.png", "Preview.png"]), &options);
+        assert!(scan.dirs.is_empty());
+
+        let scan = Scan::new(root, generatable(&PORTRAIT_FILES[1..]), &options);
+        assert_eq!(
+            scan.generate_missing(true),
+            [root.join("gimli"), root.join("legolas")]
+        );
+        assert!(!root.join("gimli/Medium.png").exists());
+        let scan = Scan::new(root, generatable(&PORTRAIT_FILES[1..]), &options);
+        assert_eq!(scan.generate_missing(false).len(), 2);
+        let medium = std::fs::read(root.join("gimli/Medium.png")).unwrap();
+        assert_eq!(
+            image::guess_format(&medium).unwrap(),
+            image::ImageFormat::Png
+        );
+        assert_eq!(
+            image::image_dimensions(root.join("gimli/Medium.png")).unwrap(),
+            EXPECTED_DIMENSIONS[1]
+        );
+        // Only the required files are generated.
+        assert!(!root.join("gimli/Small.png").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn perceptual_duplicates() {
+        let dirs = ["a", "b", "c"];
+        let root = &scratch("perceptual", &dirs);
+        let (width, height) = EXPECTED_DIMENSIONS[1];
+        let gradient = |reversed: bool| {
+            image::GrayImage::from_fn(width, height, |x, y| {
+                let x = if reversed { width - 1 - x } else { x };
+                image::Luma([(x * 255 / width) as u8 ^ (y as u8 & 0x10)])
+            })
+        };
+        // The same image re-encoded, after the original, and a distinct one.
+        gradient(false).save(root.join("a/Medium.png")).unwrap();
+        gradient(false).save(root.join("b/Medium.jpg")).unwrap();
+        gradient(true).save(root.join("c/Medium.png")).unwrap();
+        let options = EraseOptions {
+            dry_run: true,
+            ..EraseOptions::default()
+        };
+        let erase = |threshold| {
+            let mut scan = scan(root, &dirs, &["png", "jpg"]);
+            let outcomes =
+                scan.erase_perceptual_duplicates(&options, threshold, &mut UndoLog::disabled());
+            let erased: Vec<PathBuf> = outcomes
+                .into_iter()
+                .map(|outcome| match outcome {
+                    OperationOutcome::Planned { src, .. } => src,
+                    outcome => panic!("{}", outcome),
+                })
+                .collect();
+            (scan.dirs, erased)
+        };
+        assert_eq!(
+            erase(10),
+            (vec![root.join("a"), root.join("c")], vec![root.join("b")])
+        );
+        // Every hash is within 64 bits of the first.
+        assert_eq!(
+            erase(64),
+            (vec![root.join("a")], vec![root.join("b"), root.join("c")])
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
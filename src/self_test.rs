@@ -0,0 +1,91 @@
+use crate::args;
+use std::path::Path;
+use std::process::ExitCode;
+use wrathoftherighteousportraits::{
+    cleanup, prepare, run, EventStream, OperationOutcome, Preparation,
+};
+
+const PORTRAIT_FILES: [(&str, u32, u32); 3] = [
+    ("Small.png", 64, 76),
+    ("Medium.png", 152, 175),
+    ("Fulllength.png", 692, 1024),
+];
+
+/// What the self-test expects of a run with --remove-duplicate-dirs and
+/// --remove-useless-dirs on its fixture.
+const EXPECTED_MOVED: usize = 2;
+const EXPECTED_DEDUPED: usize = 1;
+const EXPECTED_USELESS: usize = 1;
+
+/// Runs the whole pipeline on a fixture in a temporary directory, which is removed afterwards,
+/// and prints PASS or FAIL depending on whether the counts are the expected ones.
+pub fn run_self_test() -> anyhow::Result<ExitCode> {
+    let dir = std::env::temp_dir().join(format!("portraits-self-test-{}", std::process::id()));
+    let result = create_fixture(&dir).and_then(|()| failures(&dir));
+    if let Err(err) = std::fs::remove_dir_all(&dir) {
+        log::warn!("Failed to remove {}: {}", dir.display(), err);
+    }
+    let failures = result?;
+    for failure in &failures {
+        println!("{}", failure);
+    }
+    if failures.is_empty() {
+        println!("PASS");
+        Ok(ExitCode::SUCCESS)
+    } else {
+        println!("FAIL");
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// Two portraits and a duplicate of one of them in "Downloads", and a directory that is not a
+/// portrait in "Portraits".
+fn create_fixture(dir: &Path) -> anyhow::Result<()> {
+    let downloads = dir.join("Downloads");
+    write_portrait(&downloads.join("pack").join("gimli"), [150, 90, 40])?;
+    write_portrait(&downloads.join("pack").join("legolas"), [40, 150, 60])?;
+    write_portrait(&downloads.join("copy").join("gimli"), [150, 90, 40])?;
+    let useless = dir.join("Portraits").join("notes");
+    std::fs::create_dir_all(&useless)?;
+    std::fs::write(useless.join("readme.txt"), "Not a portrait")?;
+    Ok(())
+}
+
+fn write_portrait(dir: &Path, color: [u8; 3]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (file, width, height) in PORTRAIT_FILES {
+        image::RgbImage::from_pixel(width, height, image::Rgb(color)).save(dir.join(file))?;
+    }
+    Ok(())
+}
+
+/// Runs on the fixture in `dir`, returning what differs from the expectations.
+fn failures(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let config = args::self_test_config(&dir.join("Downloads"), &dir.join("Portraits"))?;
+    let Preparation {
+        scan, duplicates, ..
+    } = prepare(&config, &EventStream::disabled());
+    let moves = run(&config, scan, &EventStream::disabled())?;
+    let useless = cleanup(&config, &EventStream::disabled());
+    let mut failures = Vec::new();
+    for (what, outcomes, expected) in [
+        ("moved", &moves, EXPECTED_MOVED),
+        ("deduped", &duplicates, EXPECTED_DEDUPED),
+        ("useless-erased", &useless, EXPECTED_USELESS),
+    ] {
+        for outcome in outcomes {
+            if let OperationOutcome::Failed { .. } | OperationOutcome::Skipped { .. } = outcome {
+                failures.push(outcome.to_string());
+            }
+        }
+        let done = outcomes
+            .iter()
+            .filter(|outcome| matches!(outcome, OperationOutcome::Done { .. }))
+            .count();
+        println!("{:<14} = {} (expected {})", what, done, expected);
+        if done != expected {
+            failures.push(format!("Expected {} {} dirs, got {}", expected, what, done));
+        }
+    }
+    Ok(failures)
+}
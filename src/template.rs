@@ -0,0 +1,116 @@
+use std::ffi::{OsStr, OsString};
+
+/// A parsed `--name-template`, e.g. "{prefix}{index:04}", from which the names of the
+/// directories moved into the Portraits directory are rendered.
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Literal(String),
+    Prefix,
+    /// The position of the directory in the scan, zero-padded to the given width.
+    Index(usize),
+    /// The original path of the directory below the downloads directory.
+    Path,
+    /// The number of collisions so far, zero-padded to the given width.
+    Attempt(usize),
+}
+
+impl NameTemplate {
+    /// Parses the placeholders `{prefix}`, `{index}`, `{path}` and `{attempt}`, where
+    /// `{index}` and `{attempt}` may be given a width, e.g. `{index:04}`. A literal brace is
+    /// written as `{{` or `}}`.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed placeholder in {:?}", template))?;
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(Self::placeholder(&rest[..end])?);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("unmatched '}}' in {:?}", template)),
+                '/' | '\\' => return Err(format!("{:?} contains a path separator", template)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        if pieces.is_empty() {
+            return Err("the name template is empty".to_string());
+        }
+        Ok(Self { pieces })
+    }
+
+    fn placeholder(placeholder: &str) -> Result<Piece, String> {
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => {
+                let width = width
+                    .parse()
+                    .map_err(|_| format!("invalid width in {{{}}}", placeholder))?;
+                (name, Some(width))
+            }
+            None => (placeholder, None),
+        };
+        match (name, width) {
+            ("prefix", None) => Ok(Piece::Prefix),
+            ("path", None) => Ok(Piece::Path),
+            ("index", width) => Ok(Piece::Index(width.unwrap_or(0))),
+            ("attempt", width) => Ok(Piece::Attempt(width.unwrap_or(0))),
+            ("prefix" | "path", Some(_)) => {
+                Err(format!("{{{}}} does not take a width", placeholder))
+            }
+            _ => Err(format!("unknown placeholder {{{}}}", placeholder)),
+        }
+    }
+
+    pub(crate) fn has_path(&self) -> bool {
+        self.pieces.iter().any(|piece| matches!(piece, Piece::Path))
+    }
+
+    pub(crate) fn has_attempt(&self) -> bool {
+        self.pieces
+            .iter()
+            .any(|piece| matches!(piece, Piece::Attempt(_)))
+    }
+
+    pub(crate) fn render(
+        &self,
+        prefix: &str,
+        index: usize,
+        path: &OsStr,
+        attempt: u32,
+    ) -> OsString {
+        let mut rendered = OsString::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(literal) => rendered.push(literal),
+                Piece::Prefix => rendered.push(prefix),
+                Piece::Index(width) => rendered.push(format!("{:0width$}", index)),
+                Piece::Path => rendered.push(path),
+                Piece::Attempt(width) => rendered.push(format!("{:0width$}", attempt)),
+            }
+        }
+        rendered
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{moving, Operation, OperationOutcome};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// One line of the undo log: an operation recorded right before it was performed.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UndoEntry {
+    pub operation: Operation,
+    #[serde(with = "crate::encoded_path")]
+    pub src: PathBuf,
+    #[serde(with = "crate::encoded_path::option")]
+    pub dst: Option<PathBuf>,
+    pub reversible: bool,
+}
+
+/// An append-only log of the operations performed on the filesystem, one JSON object per line.
+pub struct UndoLog {
+    file: Option<std::fs::File>,
+}
+
+impl UndoLog {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self { file: Some(file) })
+    }
+
+    /// A log that records nothing, e.g. for dry runs.
+    pub fn disabled() -> Self {
+        Self { file: None }
+    }
+
+    pub(crate) fn from_config(path: &Path, dry_run: bool) -> Self {
+        if dry_run {
+            return Self::disabled();
+        }
+        Self::open(path).unwrap_or_else(|err| {
+            log::error!("Failed to open undo log {}: {}", path.display(), err);
+            Self::disabled()
+        })
+    }
+
+    pub fn record(&mut self, operation: Operation, src: &Path, dst: Option<&Path>) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let entry = UndoEntry {
+            operation,
+            src: src.to_path_buf(),
+            dst: dst.map(Path::to_path_buf),
+            // Erasing into a trash directory is a move too.
+            reversible: matches!(
+                operation,
+                Operation::Move
+                    | Operation::CleanMove
+                    | Operation::Copy
+                    | Operation::Extract
+                    | Operation::Convert
+            ) || dst.is_some(),
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(file, "{}", line));
+        if let Err(err) = written {
+            log::error!(
+                "Failed to write undo log entry for {}: {}",
+                src.display(),
+                err
+            );
+        }
+    }
+}
+
+/// The outcome of replaying an undo log.
+pub struct Undo {
+    pub outcomes: Vec<OperationOutcome>,
+    pub irreversible: Vec<UndoEntry>,
+}
+
+/// Replays the undo log at `path` in reverse: moved directories are moved back and copies
+/// are erased, while deletions are reported as irreversible.
+pub fn undo(path: &Path, dry_run: bool) -> anyhow::Result<Undo> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str::<UndoEntry>(&line)?);
+    }
+    let mut outcomes = Vec::new();
+    let mut irreversible = Vec::new();
+    for entry in entries.into_iter().rev() {
+        let dst = match (&entry.dst, entry.reversible) {
+            (Some(dst), true) => dst.clone(),
+            _ => {
+                irreversible.push(entry);
+                continue;
+            }
+        };
+        // The entry is written before the operation, which may have failed.
+        if !dst.exists() {
+            continue;
+        }
+        // Erasing the converted files would lose the portrait when the originals are gone.
+        if entry.operation == Operation::Convert && !entry.src.exists() {
+            irreversible.push(entry);
+            continue;
+        }
+        let outcome = match entry.operation {
+            Operation::Copy | Operation::Extract | Operation::Convert if !dry_run => {
+                OperationOutcome::new(
+                    Operation::Erase,
+                    dst.clone(),
+                    None,
+                    std::fs::remove_dir_all(&dst),
+                )
+            }
+            Operation::Copy | Operation::Extract | Operation::Convert => {
+                OperationOutcome::Planned {
+                    operation: Operation::Erase,
+                    src: dst,
+                    dst: None,
+                }
+            }
+            // What the clean move left behind may still be in the original directory.
+            Operation::CleanMove if !dry_run => {
+                let result = moving::restore_clean_move(&dst, &entry.src);
+                OperationOutcome::new(Operation::CleanMove, dst, Some(entry.src), result)
+            }
+            Operation::CleanMove => OperationOutcome::Planned {
+                operation: Operation::CleanMove,
+                src: dst,
+                dst: Some(entry.src),
+            },
+            _ if entry.src.exists() => OperationOutcome::Failed {
+                operation: Operation::Move,
+                src: dst,
+                dst: Some(entry.src),
+                error: std::io::Error::from(std::io::ErrorKind::AlreadyExists),
+            },
+            _ if !dry_run => {
+                let result = entry
+                    .src
+                    .parent()
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|()| moving::move_portrait(&dst, &entry.src, true));
+                OperationOutcome::new(Operation::Move, dst, Some(entry.src), result)
+            }
+            _ => OperationOutcome::Planned {
+                operation: Operation::Move,
+                src: dst,
+                dst: Some(entry.src),
+            },
+        };
+        outcomes.push(outcome);
+    }
+    Ok(Undo {
+        outcomes,
+        irreversible,
+    })
+}